@@ -0,0 +1,164 @@
+use crate::chip8::{MEMORY_SIZE, PROGRAM_START};
+use crate::disasm;
+use crate::symbols::Symbols;
+
+// Instructions a backtrace shows from before the error
+const HISTORY: usize = 16;
+
+// An instruction that was run: its cycle, address and opcode
+#[derive(Clone, Copy, Default)]
+pub struct Run {
+    pub cycle: u64,
+    pub pc: u16,
+    pub opcode: u16,
+}
+
+// The last HISTORY instructions run
+pub struct History {
+    runs: [Run; HISTORY],
+    // Where the next run goes
+    head: usize,
+    len: usize,
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self {
+            runs: [Run::default(); HISTORY],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn push(&mut self, run: Run) {
+        self.runs[self.head] = run;
+        self.head = (self.head + 1) % HISTORY;
+        self.len = (self.len + 1).min(HISTORY);
+    }
+
+    // Oldest first
+    pub fn runs(&self) -> Vec<Run> {
+        let oldest = (self.head + HISTORY - self.len) % HISTORY;
+        (0..self.len)
+            .map(|i| self.runs[(oldest + i) % HISTORY])
+            .collect()
+    }
+}
+
+// A place in the call stack: the instruction running there and the routine
+// it's in, if that's known
+struct Frame {
+    pc: u16,
+    opcode: u16,
+    routine: Option<u16>,
+}
+
+// Where the program was when it stopped: the instruction that was running,
+// the calls that led to it and the instructions run before it
+pub struct Backtrace {
+    // Innermost first
+    frames: Vec<Frame>,
+    history: Vec<Run>,
+}
+
+impl Backtrace {
+    // `returns` are the return addresses on the stack, oldest first
+    pub fn capture(memory: &[u8], pc: u16, returns: &[u16], history: &History) -> Self {
+        let opcode_at = |address: u16| {
+            let address = address as usize % MEMORY_SIZE;
+            ((memory[address] as u16) << 8) | memory[(address + 1) % MEMORY_SIZE] as u16
+        };
+        // The routine a return address came back from is the one its call
+        // went to, and the outermost frame is the program itself
+        let called = |address: u16| {
+            let opcode = opcode_at(address.wrapping_sub(2));
+            (disasm::pattern(opcode) == Some("2NNN")).then_some(opcode & 0x0FFF)
+        };
+
+        let mut places = vec![pc];
+        places.extend(returns.iter().rev().map(|&address| address.wrapping_sub(2)));
+        let mut routines: Vec<Option<u16>> = returns.iter().rev().map(|&a| called(a)).collect();
+        routines.push(Some(PROGRAM_START as u16));
+
+        let frames = places
+            .into_iter()
+            .zip(routines)
+            .map(|(pc, routine)| Frame {
+                pc,
+                opcode: opcode_at(pc),
+                routine,
+            })
+            .collect();
+        Self {
+            frames,
+            history: history.runs(),
+        }
+    }
+
+    // The instruction that was running
+    pub fn pc(&self) -> u16 {
+        self.frames[0].pc
+    }
+
+    pub fn text(&self, bxnn: bool, symbols: &Symbols) -> String {
+        let describe = |pc: u16, opcode: u16| {
+            let mnemonic = symbols.mnemonic(opcode, bxnn).unwrap_or_default();
+            format!("{:03X}  {:04X}  {:<18}", pc, opcode, mnemonic)
+        };
+
+        let mut text = "Backtrace, innermost call first:\n".to_string();
+        for (number, frame) in self.frames.iter().enumerate() {
+            let routine = frame
+                .routine
+                .map_or("?".to_string(), |r| symbols.routine_name(r));
+            text += &format!(
+                "  #{:<3} {} in {}\n",
+                number,
+                describe(frame.pc, frame.opcode),
+                routine
+            );
+        }
+
+        text += "Last instructions:\n";
+        for run in &self.history {
+            let line = format!("  {:>10}  {}", run.cycle, describe(run.pc, run.opcode));
+            text += line.trim_end();
+            text.push('\n');
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cycles(history: &History) -> Vec<u64> {
+        history.runs().iter().map(|run| run.cycle).collect()
+    }
+
+    fn push(history: &mut History, cycles: std::ops::Range<u64>) {
+        for cycle in cycles {
+            history.push(Run {
+                cycle,
+                pc: 0x200,
+                opcode: 0x1200,
+            });
+        }
+    }
+
+    // After a reset the history starts again while cycles keep counting
+    #[test]
+    fn history_from_a_later_cycle() {
+        let mut history = History::new();
+        push(&mut history, 1005..1008);
+        assert_eq!(cycles(&history), [1005, 1006, 1007]);
+    }
+
+    #[test]
+    fn history_keeps_the_latest_runs() {
+        let mut history = History::new();
+        push(&mut history, 3..40);
+        assert_eq!(cycles(&history), (24..40).collect::<Vec<_>>());
+    }
+}
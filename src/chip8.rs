@@ -1,23 +1,49 @@
-use rand::Rng;
-use rand::rngs::ThreadRng;
+use std::path::Path;
 
+use crate::backtrace::{Backtrace, History, Run};
 use crate::config::Config;
-use crate::hardware::Hardware;
+use crate::coverage::Coverage;
+use crate::database::sha1_hex;
+use crate::display::Display;
+use crate::overlap::Overlaps;
+use crate::profile::Profiler;
+use crate::random::Random;
 use crate::registers::Registers;
+use crate::rom::Rom;
+use crate::screenshot;
 use crate::stack::Stack;
 use crate::timers::Timers;
+use crate::trace::{self, Entry, Tracer};
 
-const MEMORY_SIZE: usize = 4096;
+pub const MEMORY_SIZE: usize = 4096;
+pub const PROGRAM_START: usize = 0x200;
 
 pub struct Chip8 {
     memory: [u8; MEMORY_SIZE],
+    // Memory as it was after loading, for reset
+    loaded: [u8; MEMORY_SIZE],
     register: Registers,
     stack: Stack,
     pc: u16,
-    rng: ThreadRng,
+    random: Box<dyn Random>,
     cfg: Config,
-    hardware: Hardware,
+    display: Display,
     timers: Timers,
+    // CHIP-8 keys that are down this frame, one bit per key
+    keys: u16,
+    // Keys that were down when FX0A started waiting, minus those released since
+    key_wait: Option<u16>,
+    // Frames run so far
+    frame: u64,
+    // Instructions run so far
+    cycles: u64,
+    tracer: Option<Tracer>,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    overlaps: Option<Overlaps>,
+    history: History,
+    // Where the program was when an instruction failed
+    backtrace: Option<Backtrace>,
 }
 
 fn opcode_error(opcode: u16, pc: u16) -> String {
@@ -38,7 +64,9 @@ fn create_nn(c: u16, d: u16) -> u16 {
 }
 
 impl Chip8 {
-    pub fn new(rom: &[u8], cfg: Config) -> Self {
+    // CXNN draws from `random`, so runs with the same generator, seed and
+    // input are identical
+    pub fn new(rom: &Rom, cfg: Config, random: Box<dyn Random>) -> Self {
         let mut memory: [u8; MEMORY_SIZE] = [0; MEMORY_SIZE];
 
         // Load fontset here
@@ -63,34 +91,317 @@ impl Chip8 {
 
         memory[..FONTSET.len()].copy_from_slice(&FONTSET);
 
-        if rom.len() % 2 != 0 {
-            panic!("Bad ROM size: must be even number of bytes");
-        }
-        if 0x200 + rom.len() > MEMORY_SIZE {
-            panic!("ROM size exceeds available memory");
-        }
+        // The ROM has already been checked against cfg.rom_capacity
+        let rom = rom.bytes();
+        memory[PROGRAM_START..(PROGRAM_START + rom.len())].copy_from_slice(rom);
 
-        memory[0x200..(0x200 + rom.len())].copy_from_slice(rom);
-
-        let mute = cfg.mute;
+        let timers = Timers::new(cfg.mute, cfg.volume, cfg.tone_hz);
 
         Self {
             memory,
+            loaded: memory,
             register: Registers::new(),
-            stack: Stack::new(),
-            pc: 0x200,
-            rng: rand::rng(),
+            stack: Stack::new(cfg.stack_depth, cfg.stack_in_memory),
+            pc: PROGRAM_START as u16,
+            random,
             cfg,
-            hardware: Hardware::new(),
-            timers: Timers::new(mute),
+            display: Display::new(),
+            timers,
+            keys: 0,
+            key_wait: None,
+            frame: 0,
+            cycles: 0,
+            tracer: None,
+            profiler: None,
+            coverage: None,
+            overlaps: None,
+            history: History::new(),
+            backtrace: None,
         }
     }
 
-    pub fn clean_up(&mut self) -> Result<(), &'static str> {
-        self.hardware.clean_up()
+    // Logs instructions from now on, see trace::Tracer
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    pub fn take_tracer(&mut self) -> Option<Tracer> {
+        self.tracer.take()
+    }
+
+    // Counts instructions from now on, see profile::Profiler
+    pub fn set_profiler(&mut self, profiler: Profiler) {
+        self.profiler = Some(profiler);
+    }
+
+    pub fn take_profiler(&mut self) -> Option<Profiler> {
+        self.profiler.take()
+    }
+
+    // Records coverage from now on, see coverage::Coverage
+    pub fn set_coverage(&mut self, coverage: Coverage) {
+        self.coverage = Some(coverage);
+    }
+
+    pub fn take_coverage(&mut self) -> Option<Coverage> {
+        self.coverage.take()
+    }
+
+    // Checks for code and data overlaps from now on, see overlap::Overlaps
+    pub fn set_overlaps(&mut self, overlaps: Overlaps) {
+        self.overlaps = Some(overlaps);
+    }
+
+    pub fn take_overlaps(&mut self) -> Option<Overlaps> {
+        self.overlaps.take()
+    }
+
+    pub fn display(&self) -> &Display {
+        &self.display
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn set_v(&mut self, register: u8, value: u8) {
+        self.register.set_v(register, value);
     }
 
+    pub fn set_i(&mut self, value: u16) {
+        self.register.set_index_register(value);
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
+    pub fn set_delay(&mut self, value: u8) {
+        self.timers.set_delay(value).unwrap_or_default();
+    }
+
+    pub fn set_sound(&mut self, value: u8) {
+        self.timers.set_sound(value).unwrap_or_default();
+    }
+
+    pub fn set_memory(&mut self, address: u16, value: u8) {
+        self.memory[address as usize % MEMORY_SIZE] = value;
+    }
+
+    // Return addresses on the stack, oldest first
+    pub fn stack(&self) -> Vec<u16> {
+        self.stack.entries(&self.memory)
+    }
+
+    // Instructions run so far
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // Where the program is now and how it got there
+    pub fn backtrace(&self) -> Backtrace {
+        self.backtrace_at(self.pc)
+    }
+
+    fn backtrace_at(&self, pc: u16) -> Backtrace {
+        let returns = self.stack.entries(&self.memory);
+        Backtrace::capture(&self.memory, pc, &returns, &self.history)
+    }
+
+    // Starts the program over from the loaded ROM. Tracing, profiling and
+    // the random numbers carry on.
+    pub fn reset(&mut self) {
+        self.memory = self.loaded;
+        self.register = Registers::new();
+        self.stack = Stack::new(self.cfg.stack_depth, self.cfg.stack_in_memory);
+        self.pc = PROGRAM_START as u16;
+        self.display = Display::new();
+        self.set_delay(0);
+        self.set_sound(0);
+        self.key_wait = None;
+        self.history = History::new();
+        self.backtrace = None;
+    }
+
+    pub fn cpu_hz(&self) -> u32 {
+        self.cfg.cpu_hz
+    }
+
+    pub fn set_cpu_hz(&mut self, hz: u32) {
+        self.cfg.cpu_hz = hz;
+    }
+
+    pub fn muted(&self) -> bool {
+        self.cfg.mute
+    }
+
+    pub fn set_mute(&mut self, mute: bool) -> Result<(), &'static str> {
+        self.timers.set_mute(mute)?;
+        self.cfg.mute = mute;
+        Ok(())
+    }
+
+    // Runs one 60Hz frame with `keys` down: cpu_hz / 60 instructions, then
+    // a timer tick
+    pub fn run_frame(&mut self, keys: u16) -> Result<(), String> {
+        let instructions = self.begin_frame(keys);
+        for _ in 0..instructions {
+            self.run_instruction()?;
+        }
+        self.end_frame();
+        Ok(())
+    }
+
+    // Starts a frame with `keys` down and returns how many instructions to
+    // run in it. For callers that need to look at every instruction; most
+    // should use run_frame.
+    pub fn begin_frame(&mut self, keys: u16) -> u64 {
+        self.keys = keys;
+
+        // Spread the remainder of cpu_hz / 60 evenly over the frames
+        let hz = self.cfg.cpu_hz as u64;
+        let instructions = (self.frame + 1) * hz / 60 - self.frame * hz / 60;
+        self.frame += 1;
+        instructions
+    }
+
+    // Like step, but skips bad opcodes if the config says to
+    pub fn run_instruction(&mut self) -> Result<(), String> {
+        match self.step() {
+            Err(err) if self.cfg.skip_bad_opcodes && err.starts_with("bad opcode") => Ok(()),
+            Err(err) => {
+                let pc = self.history.runs().last().map_or(self.pc, |run| run.pc);
+                self.backtrace = Some(self.backtrace_at(pc));
+                Err(err)
+            }
+            result => result,
+        }
+    }
+
+    // The backtrace of the instruction that stopped the program
+    pub fn take_backtrace(&mut self) -> Option<Backtrace> {
+        self.backtrace.take()
+    }
+
+    pub fn end_frame(&mut self) {
+        self.timers.tick();
+    }
+
+    // Saves the display as an image, see screenshot::save
+    pub fn screenshot(&self, path: &Path) -> Result<(), String> {
+        screenshot::save(
+            path,
+            &self.display.image(&self.cfg.palette),
+            self.cfg.palette.off(),
+            self.cfg.screenshot_scale,
+        )
+    }
+
+    // SHA-1 of everything that affects what the program does next
+    pub fn state_hash(&self) -> String {
+        let mut state = Vec::new();
+        state.extend_from_slice(&self.memory);
+        for register in 0..16 {
+            state.push(self.register.get_v(register));
+        }
+        state.extend(self.register.get_index().to_be_bytes());
+        state.extend(self.pc.to_be_bytes());
+        for address in self.stack.entries(&self.memory) {
+            state.extend(address.to_be_bytes());
+        }
+        state.push(self.timers.get_delay().unwrap_or_default());
+        state.push(self.timers.get_sound());
+        for row in self.display.bytes() {
+            state.extend_from_slice(row);
+        }
+
+        sha1_hex(&state)
+    }
+
+    fn check_key(&self, key: u8) -> Result<bool, &'static str> {
+        Ok(self.keys & (1 << (key & 0xF)) != 0)
+    }
+
+    // A key pressed since waiting started, or None to keep waiting
+    fn get_key(&mut self) -> Result<Option<u8>, &'static str> {
+        let waiting = self.key_wait.get_or_insert(self.keys);
+        // Keys released while waiting can be pressed again
+        *waiting &= self.keys;
+
+        let pressed = self.keys & !*waiting;
+        if pressed == 0 {
+            return Ok(None);
+        }
+        self.key_wait = None;
+        Ok(Some(pressed.trailing_zeros() as u8))
+    }
+
+    // Runs one instruction, and traces, profiles or checks it if asked to
     pub fn step(&mut self) -> Result<(), String> {
+        let cycle = self.cycles;
+        self.cycles += 1;
+
+        let pc = self.pc;
+        let opcode =
+            ((self.memory[pc as usize] as u16) << 8) | (self.memory[(pc + 1) as usize] as u16);
+        self.history.push(Run { cycle, pc, opcode });
+        let traced = self
+            .tracer
+            .as_ref()
+            .is_some_and(|tracer| tracer.wants(cycle, pc, opcode));
+        if !traced && self.profiler.is_none() && self.coverage.is_none() && self.overlaps.is_none()
+        {
+            return self.execute();
+        }
+
+        let i = self.register.get_index();
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record(pc, opcode, i);
+        }
+        if let Some(overlaps) = &mut self.overlaps {
+            overlaps.record(cycle, pc, opcode, i);
+        }
+
+        let before = traced.then(|| self.trace_state());
+        let result = self.execute();
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(pc, opcode, self.pc, self.stack.sp());
+        }
+        if let Some(before) = before {
+            let entry = Entry {
+                cycle,
+                pc,
+                opcode,
+                changes: trace::changes(&before, &self.trace_state()),
+            };
+            if let Some(tracer) = &mut self.tracer {
+                let logged = tracer.log(&entry);
+                // An instruction that failed is reported ahead of the trace
+                return result.and(logged);
+            }
+        }
+        result
+    }
+
+    pub fn trace_state(&self) -> trace::State {
+        let mut v = [0; 16];
+        for (register, value) in v.iter_mut().enumerate() {
+            *value = self.register.get_v(register as u8);
+        }
+        trace::State {
+            v,
+            i: self.register.get_index(),
+            sp: self.stack.sp(),
+            delay: self.timers.get_delay().unwrap_or_default(),
+            sound: self.timers.get_sound(),
+        }
+    }
+
+    fn execute(&mut self) -> Result<(), String> {
         let opcode = ((self.memory[self.pc as usize] as u16) << 8)
             | (self.memory[(self.pc + 1) as usize] as u16);
         let pc = self.pc; // Address of current instruction
@@ -108,14 +419,12 @@ impl Chip8 {
                     if d == 0 {
                         // 00E0
                         // Clear screen
-                        match self.hardware.clear() {
-                            Ok(()) => return Ok(()),
-                            Err(err) => return Err(sub_error(opcode, pc, err)),
-                        }
+                        self.display.clear();
+                        return Ok(());
                     } else if d == 0xE {
                         // 00EE
                         // Return subroutine
-                        self.pc = match self.stack.return_subroutine() {
+                        self.pc = match self.stack.return_subroutine(&self.memory) {
                             Ok(value) => value,
                             Err(err) => {
                                 // Skip instruction on stack underflow if allowed
@@ -150,7 +459,7 @@ impl Chip8 {
                 // Jump to address as subroutine (add to stack)
                 let nnn = create_nnn(b, c, d);
                 if (nnn as usize) < MEMORY_SIZE {
-                    match self.stack.subroutine(self.pc) {
+                    match self.stack.subroutine(self.pc, &mut self.memory) {
                         Ok(()) => {
                             self.pc = nnn;
                         }
@@ -385,7 +694,7 @@ impl Chip8 {
             0xC => {
                 // CXNN
                 // Vx = NN | Rand()
-                let rand_val: u8 = self.rng.random();
+                let rand_val = self.random.next_byte();
 
                 let nn = create_nn(c, d);
                 let val = rand_val & (nn as u8);
@@ -408,7 +717,7 @@ impl Chip8 {
 
                 for i in 0..(d as usize) {
                     let byte = self.memory[index + i];
-                    match self.hardware.display_row(byte, vx, vy + i as u8) {
+                    match self.display.draw_row(byte, vx, vy + i as u8) {
                         Ok(flag) => {
                             if flag {
                                 self.register.set_v(0xF, 1);
@@ -417,17 +726,14 @@ impl Chip8 {
                         Err(err) => return Err(sub_error(opcode, pc, err)),
                     }
                 }
-                match self.hardware.draw() {
-                    Ok(()) => Ok(()),
-                    Err(err) => Err(sub_error(opcode, pc, err)),
-                }
+                Ok(())
             }
             0xE => {
                 if c == 9 && d == 0xE {
                     // EX9E
                     // Skip if pressed
                     let vx = self.register.get_v(b as u8);
-                    match Hardware::check_key(vx) {
+                    match self.check_key(vx) {
                         Ok(is_pressed) => {
                             if is_pressed {
                                 self.pc += 2;
@@ -441,7 +747,7 @@ impl Chip8 {
                     // EXA1
                     // Skipped if not pressed
                     let vx = self.register.get_v(b as u8);
-                    match Hardware::check_key(vx) {
+                    match self.check_key(vx) {
                         Ok(is_pressed) => {
                             if !is_pressed {
                                 self.pc += 2;
@@ -469,8 +775,13 @@ impl Chip8 {
                     } else if d == 0xA {
                         // FX0A
                         // Wait until key
-                        let key = match Hardware::get_key() {
-                            Ok(v) => v,
+                        // Runs again until a key is pressed, so frames keep going meanwhile
+                        let key = match self.get_key() {
+                            Ok(Some(v)) => v,
+                            Ok(None) => {
+                                self.pc = pc;
+                                return Ok(());
+                            }
                             Err(err) => return Err(sub_error(opcode, pc, err)),
                         };
                         self.register.set_v(b as u8, key);
@@ -1,9 +1,29 @@
-// TODO: Configurable colors
+use crate::filter::FilterMode;
+use crate::keymap::{Hotkeys, Keymap};
+use crate::palette::Palette;
+use crate::render::RenderMode;
+
 pub struct Config {
     pub skip_stack_underflow: bool,
+    // None for no limit
+    pub stack_depth: Option<usize>,
+    pub stack_in_memory: bool,
+    // Bytes free for the program above 0x200
+    pub rom_capacity: usize,
     pub bxnn: bool,
     pub fx1e_overflow: bool,
     pub shift_in_place_8xy: bool,
     pub increment_i_on_mem: bool,
+    pub skip_bad_opcodes: bool,
+    pub cpu_hz: u32,
     pub mute: bool,
+    pub volume: f32,
+    pub tone_hz: f32,
+    pub palette: Palette,
+    pub keymap: Keymap,
+    pub hotkeys: Hotkeys,
+    pub render_mode: RenderMode,
+    pub filter: FilterMode,
+    pub filter_frames: usize,
+    pub screenshot_scale: usize,
 }
@@ -0,0 +1,303 @@
+use std::{fs, path::Path};
+
+use crate::chip8::{MEMORY_SIZE, PROGRAM_START};
+use crate::disasm::{self, Access};
+use crate::symbols::Symbols;
+
+// Ways memory was used as data
+const READ: u8 = 1;
+const WRITTEN: u8 = 2;
+
+// Records which addresses ran as instructions and which were read or
+// written as data, for an annotated listing and an LCOV file
+pub struct Coverage {
+    // Memory as it was loaded, which the listing disassembles
+    memory: Vec<u8>,
+    rom_len: usize,
+    // Times an instruction started at each address
+    executed: Vec<u64>,
+    access: Vec<u8>,
+}
+
+// One line of the listing
+struct Line {
+    address: usize,
+    len: usize,
+    // Set for lines shown as instructions
+    hits: Option<u64>,
+}
+
+impl Coverage {
+    pub fn new(memory: &[u8], rom_len: usize) -> Self {
+        Self {
+            memory: memory.to_vec(),
+            rom_len,
+            executed: vec![0; MEMORY_SIZE],
+            access: vec![0; MEMORY_SIZE],
+        }
+    }
+
+    // Called before each instruction runs, with I as it is then
+    pub fn record(&mut self, pc: u16, opcode: u16, i: u16) {
+        self.executed[pc as usize % MEMORY_SIZE] += 1;
+
+        let (len, flag) = match disasm::data_access(opcode) {
+            Some((len, Access::Read)) => (len, READ),
+            Some((len, Access::Write)) => (len, WRITTEN),
+            None => return,
+        };
+        for offset in 0..len {
+            self.access[(i as usize + offset) % MEMORY_SIZE] |= flag;
+        }
+    }
+
+    // Walks the program, showing what ran as instructions, data as single
+    // bytes and the rest of the ROM as instructions that never ran. Past the
+    // end of the ROM only addresses that were used are shown.
+    fn lines(&self) -> Vec<Line> {
+        let rom_end = PROGRAM_START + self.rom_len;
+        let used = |address: usize| self.executed[address] > 0 || self.access[address] != 0;
+
+        let mut lines = Vec::new();
+        let mut address = PROGRAM_START;
+        while address < MEMORY_SIZE {
+            let line = if self.executed[address] > 0 {
+                Line {
+                    address,
+                    len: 2,
+                    hits: Some(self.executed[address]),
+                }
+            } else if address >= rom_end && !used(address) {
+                address += 1;
+                continue;
+            } else if self.access[address] != 0 || address + 1 >= rom_end || used(address + 1) {
+                Line {
+                    address,
+                    len: 1,
+                    hits: None,
+                }
+            } else {
+                Line {
+                    address,
+                    len: 2,
+                    hits: Some(0),
+                }
+            };
+            address += line.len;
+            lines.push(line);
+        }
+        lines
+    }
+
+    // "     1234  x--  200  6005  LD V0, 05" for instructions, with "rw"
+    // marking data reads and writes and "-" for what never happened. Labels
+    // get a line of their own.
+    pub fn save_listing(
+        &self,
+        path: &Path,
+        name: &str,
+        bxnn: bool,
+        symbols: &Symbols,
+    ) -> Result<(), String> {
+        let lines = self.lines();
+        let found = lines.iter().filter(|line| line.hits.is_some()).count();
+        let hit = lines.iter().filter(|line| line.hits > Some(0)).count();
+
+        let mut text = format!(
+            "; Coverage of {}: {} of {} instructions run ({:.1}%)\n",
+            name,
+            hit,
+            found,
+            hit as f64 * 100.0 / found.max(1) as f64
+        );
+        for line in &lines {
+            if let Some(label) = symbols.name(line.address as u16) {
+                text += &format!("{}:\n", label);
+            }
+            let mark = |set: bool, c: char| if set { c } else { '-' };
+            let flags: String = [
+                mark(line.hits > Some(0), 'x'),
+                mark(self.access[line.address] & READ != 0, 'r'),
+                mark(self.access[line.address] & WRITTEN != 0, 'w'),
+            ]
+            .iter()
+            .collect();
+            let listed = match line.hits {
+                Some(hits) => {
+                    let opcode = ((self.memory[line.address] as u16) << 8)
+                        | self.memory[line.address + 1] as u16;
+                    let mnemonic = symbols.mnemonic(opcode, bxnn).unwrap_or_default();
+                    format!(
+                        "{:>10}  {}  {:03X}  {:04X}  {}",
+                        hits, flags, line.address, opcode, mnemonic
+                    )
+                }
+                None => format!(
+                    "{:>10}  {}  {:03X}  {:02X}",
+                    "", flags, line.address, self.memory[line.address]
+                ),
+            };
+            text += listed.trim_end();
+            text.push('\n');
+        }
+
+        fs::write(path, text).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    }
+
+    // An LCOV tracefile for the listing at `listing`, with a line for each
+    // instruction in it
+    pub fn save_lcov(&self, path: &Path, listing: &Path, symbols: &Symbols) -> Result<(), String> {
+        let source = fs::canonicalize(listing).unwrap_or_else(|_| listing.to_path_buf());
+        let mut text = format!("TN:\nSF:{}\n", source.display());
+
+        let (mut found, mut hit) = (0, 0);
+        // The listing starts with a summary line
+        let mut number = 1;
+        for line in self.lines() {
+            number += 1;
+            if symbols.name(line.address as u16).is_some() {
+                number += 1;
+            }
+            if let Some(hits) = line.hits {
+                text += &format!("DA:{},{}\n", number, hits);
+                found += 1;
+                if hits > 0 {
+                    hit += 1;
+                }
+            }
+        }
+        text += &format!("LF:{}\nLH:{}\nend_of_record\n", found, hit);
+
+        fs::write(path, text).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::Chip8;
+    use crate::random;
+    use crate::rom::Rom;
+    use crate::settings::Settings;
+
+    // A ROM that fills memory up to 0xFFF. It writes 300, reads 302 and
+    // then spins at FFD, leaving FFF as a lone byte at the end.
+    fn coverage(instructions: usize) -> Coverage {
+        let mut rom = vec![0; MEMORY_SIZE - PROGRAM_START];
+        let program = [0xA3, 0x00, 0xF0, 0x55, 0xA3, 0x02, 0xF0, 0x65, 0x1F, 0xFD];
+        rom[..program.len()].copy_from_slice(&program);
+        rom[0xFFD - PROGRAM_START..][..2].copy_from_slice(&[0x1F, 0xFD]);
+
+        let rom = Rom::from_bytes(rom, MEMORY_SIZE - PROGRAM_START).unwrap();
+        let mut config = Settings::defaults().to_config().unwrap();
+        config.mute = true;
+        let mut cpu = Chip8::new(&rom, config, random::new(0));
+        cpu.set_coverage(Coverage::new(cpu.memory(), rom.bytes().len()));
+        for _ in 0..instructions {
+            cpu.run_instruction().unwrap();
+        }
+        cpu.take_coverage().unwrap()
+    }
+
+    fn save(coverage: &Coverage, symbols: &Symbols) -> (String, String) {
+        let dir = std::env::temp_dir();
+        let listing = dir.join(format!("chip8-coverage-{}.txt", std::process::id()));
+        let lcov = dir.join(format!("chip8-coverage-{}.info", std::process::id()));
+        coverage
+            .save_listing(&listing, "full.ch8", false, symbols)
+            .unwrap();
+        coverage.save_lcov(&lcov, &listing, symbols).unwrap();
+
+        let texts = (
+            fs::read_to_string(&listing).unwrap(),
+            fs::read_to_string(&lcov).unwrap(),
+        );
+        fs::remove_file(&listing).unwrap();
+        fs::remove_file(&lcov).unwrap();
+        texts
+    }
+
+    #[test]
+    fn lines_split_code_data_and_unused_bytes() {
+        let lines = coverage(8).lines();
+        let summary: Vec<(usize, usize, Option<u64>)> = lines
+            .iter()
+            .filter(|line| !(0x20A..0x300).contains(&line.address) || line.address == 0x20A)
+            .filter(|line| !(0x305..0xFFD).contains(&line.address))
+            .map(|line| (line.address, line.len, line.hits))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (0x200, 2, Some(1)),
+                (0x202, 2, Some(1)),
+                (0x204, 2, Some(1)),
+                (0x206, 2, Some(1)),
+                (0x208, 2, Some(1)),
+                (0x20A, 2, Some(0)),
+                // Written, then a byte on its own since the next one is read
+                (0x300, 1, None),
+                (0x301, 1, None),
+                (0x302, 1, None),
+                (0x303, 2, Some(0)),
+                (0xFFD, 2, Some(3)),
+                // Too close to the end of the ROM for an instruction
+                (0xFFF, 1, None),
+            ]
+        );
+        assert_eq!(lines.len(), 5 + 123 + 3 + 1661 + 2);
+    }
+
+    #[test]
+    fn listing_marks_runs_reads_and_writes() {
+        let (listing, _) = save(&coverage(8), &Symbols::default());
+        let lines: Vec<&str> = listing.lines().collect();
+
+        assert_eq!(
+            lines[0],
+            "; Coverage of full.ch8: 6 of 1790 instructions run (0.3%)"
+        );
+        assert_eq!(lines[1], "         1  x--  200  A300  LD I, 300");
+        assert_eq!(lines[6], "         0  ---  20A  0000");
+        assert_eq!(lines[129], "            --w  300  00");
+        assert_eq!(lines[130], "            ---  301  00");
+        assert_eq!(lines[131], "            -r-  302  00");
+        assert_eq!(lines[lines.len() - 2], "         3  x--  FFD  1FFD  JP FFD");
+        assert_eq!(lines[lines.len() - 1], "            ---  FFF  00");
+    }
+
+    #[test]
+    fn lcov_lines_match_the_listing() {
+        let symbols = Symbols::from_text("300 sprite\n").unwrap();
+        let (listing, lcov) = save(&coverage(8), &symbols);
+        let listing: Vec<&str> = listing.lines().collect();
+        assert_eq!(listing[129], "sprite:");
+        assert_eq!(listing.len(), 1796);
+
+        let records: Vec<&str> = lcov.lines().filter(|l| l.starts_with("DA:")).collect();
+        assert_eq!(records.len(), 1790);
+        assert_eq!(records[..2], ["DA:2,1", "DA:3,1"]);
+        assert_eq!(records[5], "DA:7,0");
+        // After the label and the three data bytes
+        assert_eq!(records[128], "DA:134,0");
+        assert_eq!(records[1789], "DA:1795,3");
+        assert!(listing[1794].contains("FFD  1FFD"));
+        assert!(lcov.ends_with("LF:1790\nLH:6\nend_of_record\n"));
+    }
+
+    #[test]
+    fn only_used_bytes_past_the_rom_are_listed() {
+        let mut memory = vec![0; MEMORY_SIZE];
+        memory[PROGRAM_START..][..4].copy_from_slice(&[0xA4, 0x01, 0xF0, 0x55]);
+        let mut coverage = Coverage::new(&memory, 4);
+        coverage.record(0x200, 0xA401, 0);
+        coverage.record(0x202, 0xF055, 0x401);
+
+        let lines: Vec<(usize, usize)> = coverage
+            .lines()
+            .iter()
+            .map(|line| (line.address, line.len))
+            .collect();
+        assert_eq!(lines, [(0x200, 2), (0x202, 2), (0x401, 1)]);
+    }
+}
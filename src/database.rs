@@ -0,0 +1,272 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::Deserialize;
+
+use crate::platform::Platform;
+
+// Bundled copy of the CHIP-8 community database
+// (https://github.com/chip-8/chip-8-database)
+const PROGRAMS_JSON: &str = include_str!("../database/programs.json");
+const HASHES_JSON: &str = include_str!("../database/sha1-hashes.json");
+const PLATFORMS_JSON: &str = include_str!("../database/platforms.json");
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct Quirks {
+    pub shift: bool,
+    pub memory_increment_by_x: bool,
+    pub memory_leave_i_unchanged: bool,
+    pub wrap: bool,
+    pub jump: bool,
+    pub vblank: bool,
+    pub logic: bool,
+}
+
+// Per-ROM overrides of a platform's quirks
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct QuirkOverrides {
+    shift: Option<bool>,
+    memory_increment_by_x: Option<bool>,
+    memory_leave_i_unchanged: Option<bool>,
+    wrap: Option<bool>,
+    jump: Option<bool>,
+    vblank: Option<bool>,
+    logic: Option<bool>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlatformInfo {
+    pub id: String,
+    pub name: String,
+    pub default_tickrate: u32,
+    pub quirks: Quirks,
+}
+
+#[derive(Deserialize)]
+pub struct Program {
+    pub title: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub authors: Vec<String>,
+    #[serde(default)]
+    pub release: Option<String>,
+    pub roms: HashMap<String, RomEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RomEntry {
+    #[serde(default)]
+    pub file: Option<String>,
+    pub platforms: Vec<String>,
+    #[serde(default)]
+    pub tickrate: Option<u32>,
+    #[serde(default)]
+    colors: Option<RomColors>,
+    #[serde(default)]
+    keys: HashMap<String, u8>,
+    #[serde(default)]
+    quirky_platforms: HashMap<String, QuirkOverrides>,
+}
+
+#[derive(Deserialize)]
+struct RomColors {
+    #[serde(default)]
+    pixels: Vec<String>,
+}
+
+pub struct Database {
+    programs: Vec<Program>,
+    hashes: HashMap<String, usize>,
+    platforms: Vec<PlatformInfo>,
+}
+
+// A ROM found in the database
+pub struct Match<'a> {
+    pub program: &'a Program,
+    pub rom: &'a RomEntry,
+    pub platform: Option<&'a PlatformInfo>,
+}
+
+pub fn sha1_hex(bytes: &[u8]) -> String {
+    sha1_smol::Sha1::from(bytes).digest().to_string()
+}
+
+impl Database {
+    pub fn embedded() -> Self {
+        // The JSON is compiled in, so failing to parse it is a build mistake
+        Self::from_json(PROGRAMS_JSON, HASHES_JSON, PLATFORMS_JSON)
+            .unwrap_or_else(|err| panic!("Bad bundled database: {}", err))
+    }
+
+    // The three files of the database's `database/` directory
+    pub fn from_json(programs: &str, hashes: &str, platforms: &str) -> Result<Self, String> {
+        let parse_error = |name: &str, err: serde_json::Error| format!("{}: {}", name, err);
+        Ok(Self {
+            programs: serde_json::from_str(programs)
+                .map_err(|e| parse_error("programs.json", e))?,
+            hashes: serde_json::from_str(hashes).map_err(|e| parse_error("sha1-hashes.json", e))?,
+            platforms: serde_json::from_str(platforms)
+                .map_err(|e| parse_error("platforms.json", e))?,
+        })
+    }
+
+    pub fn platform(&self, platform: Platform) -> Option<&PlatformInfo> {
+        self.platforms.iter().find(|p| p.id == platform.id())
+    }
+
+    pub fn lookup(&self, sha1: &str) -> Option<Match<'_>> {
+        let program = self.programs.get(*self.hashes.get(sha1)?)?;
+        let rom = program.roms.get(sha1)?;
+
+        // Platforms are listed in order of preference, use the first one we know
+        let platform = rom
+            .platforms
+            .iter()
+            .filter_map(|id| Platform::from_id(id))
+            .find_map(|p| self.platform(p));
+
+        Some(Match {
+            program,
+            rom,
+            platform,
+        })
+    }
+}
+
+impl Match<'_> {
+    pub fn quirks(&self) -> Option<Quirks> {
+        self.platform.map(|p| self.quirks_on(p))
+    }
+
+    // Quirks of `platform` with this ROM's overrides applied
+    pub fn quirks_on(&self, platform: &PlatformInfo) -> Quirks {
+        let mut quirks = platform.quirks;
+
+        if let Some(o) = self.rom.quirky_platforms.get(&platform.id) {
+            quirks.shift = o.shift.unwrap_or(quirks.shift);
+            quirks.memory_increment_by_x = o
+                .memory_increment_by_x
+                .unwrap_or(quirks.memory_increment_by_x);
+            quirks.memory_leave_i_unchanged = o
+                .memory_leave_i_unchanged
+                .unwrap_or(quirks.memory_leave_i_unchanged);
+            quirks.wrap = o.wrap.unwrap_or(quirks.wrap);
+            quirks.jump = o.jump.unwrap_or(quirks.jump);
+            quirks.vblank = o.vblank.unwrap_or(quirks.vblank);
+            quirks.logic = o.logic.unwrap_or(quirks.logic);
+        }
+
+        quirks
+    }
+
+    // Instructions per frame
+    pub fn tickrate(&self) -> Option<u32> {
+        self.rom
+            .tickrate
+            .or(self.platform.map(|p| p.default_tickrate))
+    }
+
+    // Palette as "#rrggbb" colors, starting with the background
+    pub fn pixel_colors(&self) -> Option<&[String]> {
+        let pixels = &self.rom.colors.as_ref()?.pixels;
+        if pixels.is_empty() {
+            return None;
+        }
+
+        Some(pixels)
+    }
+
+    // Keyboard keys for the ROM's buttons. The database only names buttons,
+    // so which keyboard key each one gets is our own default, and a `[keys]`
+    // entry in a config file wins over it.
+    pub fn keys(&self) -> BTreeMap<String, u8> {
+        self.rom
+            .keys
+            .iter()
+            .filter_map(|(button, key)| {
+                let name = match button.as_str() {
+                    "up" => "up",
+                    "down" => "down",
+                    "left" => "left",
+                    "right" => "right",
+                    "a" => "space",
+                    "b" => "enter",
+                    "player2Up" => "w",
+                    "player2Down" => "s",
+                    _ => return None,
+                };
+                Some((name.to_string(), *key))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROM: &[u8] = &[0x00, 0xE0, 0x12, 0x02];
+
+    // A program in the upstream format, with one ROM that prefers the
+    // original VIP and changes one of its quirks
+    fn database() -> Database {
+        let sha1 = sha1_hex(ROM);
+        let programs = format!(
+            r#"[{{
+                "title": "Clear Loop",
+                "roms": {{
+                    "{}": {{
+                        "platforms": ["originalChip8", "modernChip8"],
+                        "tickrate": 15,
+                        "keys": {{ "up": 5, "a": 6, "player2Up": 1, "select": 2 }},
+                        "quirkyPlatforms": {{ "originalChip8": {{ "shift": true }} }}
+                    }}
+                }}
+            }}]"#,
+            sha1
+        );
+        let hashes = format!(r#"{{ "{}": 0 }}"#, sha1);
+        Database::from_json(&programs, &hashes, PLATFORMS_JSON).unwrap()
+    }
+
+    #[test]
+    fn bundled_files_parse() {
+        Database::embedded();
+    }
+
+    #[test]
+    fn lookup_resolves_platform_and_quirks() {
+        let db = database();
+        let found = db.lookup(&sha1_hex(ROM)).unwrap();
+
+        assert_eq!(found.program.title, "Clear Loop");
+        assert_eq!(found.platform.unwrap().id, "originalChip8");
+        assert_eq!(found.tickrate(), Some(15));
+
+        let vip = db.platform(Platform::OriginalChip8).unwrap().quirks;
+        let quirks = found.quirks().unwrap();
+        assert!(!vip.shift && quirks.shift);
+        assert_eq!(quirks.vblank, vip.vblank);
+        assert_eq!(quirks.memory_increment_by_x, vip.memory_increment_by_x);
+    }
+
+    #[test]
+    fn buttons_get_default_keyboard_keys() {
+        let db = database();
+        let keys = db.lookup(&sha1_hex(ROM)).unwrap().keys();
+        let expected = [("space", 6), ("up", 5), ("w", 1)];
+        assert_eq!(
+            keys,
+            expected.map(|(name, key)| (name.to_string(), key)).into()
+        );
+    }
+
+    #[test]
+    fn unknown_rom_is_not_found() {
+        assert!(database().lookup(&sha1_hex(&[0x12, 0x00])).is_none());
+    }
+}
@@ -0,0 +1,783 @@
+use std::{
+    collections::BTreeSet,
+    time::{Duration, Instant},
+};
+
+use crossterm::{
+    event::{KeyCode, KeyEvent},
+    style::Color,
+};
+
+use crate::chip8::{Chip8, MEMORY_SIZE};
+use crate::disasm;
+use crate::filter::Picture;
+use crate::hardware::Hardware;
+use crate::render::{self, CellGrid, RenderMode};
+use crate::symbols::Symbols;
+use crate::terminal;
+use crate::trace::State;
+
+// Smallest terminal the panes fit in
+const MIN_SIZE: (usize, usize) = (92, 26);
+// Where the right column of panes starts
+const RIGHT: usize = 66;
+// Where the bottom row of panes starts
+const BOTTOM: usize = 18;
+// Where the memory pane starts in the bottom row
+const MEMORY_LEFT: usize = 46;
+const STACK_LINES: usize = 7;
+
+const HELP: &str =
+    "F5 run/pause  F6 step  F7 run to cursor  F9 breakpoint  PgUp/PgDn/Home cursor  : command";
+const COMMANDS: [&str; 9] = [
+    "v3=1F, i=2A0, pc=@start, dt=3C, st=0  set a register or timer",
+    "m ADDR | m i                          show memory from ADDR, or follow I",
+    "m ADDR BYTE...                        write bytes to memory",
+    "g ADDR                                move the cursor",
+    "b [ADDR]                              toggle a breakpoint, or list them",
+    "s [N]                                 step N instructions",
+    "c                                     continue",
+    "bt                                    show the backtrace",
+    "q                                     quit, like Ctrl-C",
+];
+
+// The state of the debugger between frames. The CPU runs a frame at a time
+// like the main loop, but can stop between any two instructions.
+struct Debugger<'a> {
+    symbols: &'a Symbols,
+    bxnn: bool,
+    paused: bool,
+    // Where running stops, for run to cursor
+    target: Option<u16>,
+    breakpoints: BTreeSet<u16>,
+    // Instructions left in the current frame, or 0 between frames
+    remaining: u64,
+    frames: u64,
+    // The keypad, as last read
+    keys: u16,
+    // The registers when the program last stopped, to show what changed
+    stopped_at: State,
+    cursor: u16,
+    // First address in the disassembly pane
+    top: u16,
+    // Where the memory pane starts, or None to follow I
+    memory_at: Option<u16>,
+    // The command being typed, while the prompt is open
+    prompt: Option<String>,
+    message: String,
+    // Lines shown over the bottom panes until Esc
+    output: Vec<String>,
+}
+
+// Runs the program under the debugger until an exit is requested
+pub fn run(
+    cpu: &mut Chip8,
+    hardware: &mut Hardware,
+    picture: &mut Picture,
+    symbols: &Symbols,
+    bxnn: bool,
+) -> Result<(), String> {
+    let frame_duration = Duration::from_secs_f64(1.0 / 60.0);
+    let mut next_frame = Instant::now();
+    let mut fits = None;
+
+    hardware.collect_keys(KeyCode::Char(':'));
+    let mut debugger = Debugger::new(cpu, symbols, bxnn);
+
+    loop {
+        if terminal::exit_requested() {
+            return Ok(());
+        }
+
+        debugger.keys = match hardware.keypad() {
+            Ok(keys) => keys,
+            Err(_) if terminal::exit_requested() => return Ok(()),
+            Err(err) => return Err(err.to_string()),
+        };
+        for event in hardware.take_keys() {
+            debugger.handle_key(cpu, event);
+        }
+        if terminal::exit_requested() {
+            return Ok(());
+        }
+        hardware.set_typing(debugger.prompt.is_some());
+
+        if !debugger.paused {
+            debugger.run_frame(cpu);
+        }
+
+        picture.update(cpu.display());
+        let size = hardware.terminal_size();
+        let (width, height) = (size.0 as usize, size.1 as usize);
+        let fit = width >= MIN_SIZE.0 && height >= MIN_SIZE.1;
+        if fit {
+            hardware.present_cells(debugger.draw(cpu, picture, width, height))?;
+        } else if fits != Some(size) {
+            let message = format!(
+                "Terminal too small for the debugger, need {}x{}",
+                MIN_SIZE.0, MIN_SIZE.1
+            );
+            hardware.notice(&message)?;
+        }
+        fits = (!fit).then_some(size);
+
+        next_frame += frame_duration;
+        let now = Instant::now();
+        if next_frame > now {
+            std::thread::sleep(next_frame - now);
+        } else {
+            next_frame = now;
+        }
+    }
+}
+
+impl<'a> Debugger<'a> {
+    fn new(cpu: &Chip8, symbols: &'a Symbols, bxnn: bool) -> Self {
+        Debugger {
+            symbols,
+            bxnn,
+            paused: true,
+            target: None,
+            breakpoints: BTreeSet::new(),
+            remaining: 0,
+            frames: 0,
+            keys: 0,
+            stopped_at: cpu.trace_state(),
+            cursor: cpu.pc(),
+            top: cpu.pc(),
+            memory_at: None,
+            prompt: None,
+            message: "Paused at the start of the program".to_string(),
+            output: Vec::new(),
+        }
+    }
+
+    // Runs one instruction, starting or ending frames around it as needed
+    fn step(&mut self, cpu: &mut Chip8) -> Result<(), String> {
+        while self.remaining == 0 {
+            self.remaining = cpu.begin_frame(self.keys);
+            if self.remaining == 0 {
+                self.end_frame(cpu);
+            }
+        }
+
+        let result = cpu.run_instruction();
+        self.remaining -= 1;
+        if self.remaining == 0 {
+            self.end_frame(cpu);
+        }
+        result
+    }
+
+    fn end_frame(&mut self, cpu: &mut Chip8) {
+        cpu.end_frame();
+        self.frames += 1;
+    }
+
+    // Runs the rest of the frame, unless a breakpoint or the target is hit
+    fn run_frame(&mut self, cpu: &mut Chip8) {
+        loop {
+            if let Err(err) = self.step(cpu) {
+                self.crashed(cpu, err);
+                return;
+            }
+
+            let pc = cpu.pc();
+            if self.breakpoints.contains(&pc) {
+                self.stop(cpu, format!("Hit the breakpoint at {}", self.describe(pc)));
+                return;
+            }
+            if self.target == Some(pc) {
+                self.stop(cpu, format!("Reached {}", self.describe(pc)));
+                return;
+            }
+            if self.remaining == 0 {
+                return;
+            }
+        }
+    }
+
+    fn steps(&mut self, cpu: &mut Chip8, count: u64) {
+        self.stopped_at = cpu.trace_state();
+        for _ in 0..count {
+            if let Err(err) = self.step(cpu) {
+                self.crashed(cpu, err);
+                return;
+            }
+        }
+        self.stop(cpu, format!("Stopped at {}", self.describe(cpu.pc())));
+    }
+
+    fn stop(&mut self, cpu: &Chip8, message: String) {
+        self.paused = true;
+        self.target = None;
+        self.cursor = cpu.pc();
+        self.message = message;
+    }
+
+    fn resume(&mut self, cpu: &Chip8) {
+        self.paused = false;
+        self.stopped_at = cpu.trace_state();
+        self.output.clear();
+        self.message = "Running".to_string();
+    }
+
+    // The program can be fixed up and carried on with, so errors only stop
+    // it, back on the instruction that failed
+    fn crashed(&mut self, cpu: &mut Chip8, err: String) {
+        let backtrace = cpu.take_backtrace().unwrap_or_else(|| cpu.backtrace());
+        cpu.set_pc(backtrace.pc());
+        let message = match self.symbols.label(backtrace.pc()) {
+            Some(label) => format!("{} ({})", err, label),
+            None => err,
+        };
+        self.stop(cpu, message);
+        self.output = backtrace
+            .text(self.bxnn, self.symbols)
+            .lines()
+            .map(str::to_string)
+            .collect();
+    }
+
+    // "2A0" or "2A0 (draw+4)"
+    fn describe(&self, address: u16) -> String {
+        match self.symbols.label(address) {
+            Some(label) => format!("{:03X} ({})", address, label),
+            None => format!("{:03X}", address),
+        }
+    }
+
+    fn handle_key(&mut self, cpu: &mut Chip8, event: KeyEvent) {
+        if let Some(prompt) = &mut self.prompt {
+            match event.code {
+                KeyCode::Char(c) => prompt.push(c),
+                KeyCode::Backspace => {
+                    prompt.pop();
+                }
+                KeyCode::Esc => self.prompt = None,
+                KeyCode::Enter => {
+                    let line = self.prompt.take().unwrap_or_default();
+                    self.message = match self.command(cpu, line.trim()) {
+                        Ok(message) => message,
+                        Err(err) => format!("{}: {}", line.trim(), err),
+                    };
+                }
+                _ => {}
+            }
+            return;
+        }
+
+        match event.code {
+            KeyCode::F(5) if self.paused => self.resume(cpu),
+            KeyCode::F(5) => self.stop(cpu, format!("Paused at {}", self.describe(cpu.pc()))),
+            KeyCode::F(6) => self.steps(cpu, 1),
+            KeyCode::F(7) => {
+                self.target = Some(self.cursor);
+                self.resume(cpu);
+            }
+            KeyCode::F(9) => self.message = self.toggle_breakpoint(self.cursor),
+            KeyCode::PageUp => self.cursor = self.cursor.saturating_sub(2),
+            KeyCode::PageDown => self.cursor = (self.cursor + 2).min(MEMORY_SIZE as u16 - 2),
+            KeyCode::Home => self.cursor = cpu.pc(),
+            KeyCode::Esc => self.output.clear(),
+            KeyCode::Char(':') => self.prompt = Some(String::new()),
+            _ => {}
+        }
+    }
+
+    fn toggle_breakpoint(&mut self, address: u16) -> String {
+        if self.breakpoints.remove(&address) {
+            format!("Removed the breakpoint at {}", self.describe(address))
+        } else {
+            self.breakpoints.insert(address);
+            format!("Breakpoint at {}", self.describe(address))
+        }
+    }
+
+    // Runs a command typed at the prompt, returning a message to show
+    fn command(&mut self, cpu: &mut Chip8, line: &str) -> Result<String, String> {
+        Ok(match parse(line, self.symbols)? {
+            Command::Nothing => String::new(),
+            Command::Set(register, value) => {
+                match register {
+                    Register::V(x) => cpu.set_v(x, value as u8),
+                    Register::I => cpu.set_i(value),
+                    Register::Pc => {
+                        cpu.set_pc(value);
+                        self.cursor = value;
+                    }
+                    Register::Delay => cpu.set_delay(value as u8),
+                    Register::Sound => cpu.set_sound(value as u8),
+                }
+                match register {
+                    Register::I | Register::Pc => {
+                        format!("{} = {}", register.name(), self.describe(value))
+                    }
+                    _ => format!("{} = {:02X}", register.name(), value),
+                }
+            }
+            Command::MemoryFollowsI => {
+                self.memory_at = None;
+                "Memory follows I".to_string()
+            }
+            Command::Memory(at) => {
+                self.memory_at = Some(at);
+                format!("Memory from {}", self.describe(at))
+            }
+            Command::Write(start, bytes) => {
+                for (offset, &byte) in bytes.iter().enumerate() {
+                    cpu.set_memory(start + offset as u16, byte);
+                }
+                self.memory_at = Some(start);
+                format!("Wrote {} bytes at {}", bytes.len(), self.describe(start))
+            }
+            Command::Goto(at) => {
+                self.cursor = at;
+                format!("Cursor at {}", self.describe(at))
+            }
+            Command::Breakpoints if self.breakpoints.is_empty() => "No breakpoints".to_string(),
+            Command::Breakpoints => {
+                let list: Vec<String> =
+                    self.breakpoints.iter().map(|&a| self.describe(a)).collect();
+                format!("Breakpoints: {}", list.join(", "))
+            }
+            Command::Breakpoint(at) => self.toggle_breakpoint(at),
+            Command::Step(count) => {
+                self.steps(cpu, count);
+                self.message.clone()
+            }
+            Command::Continue => {
+                self.resume(cpu);
+                self.message.clone()
+            }
+            Command::Backtrace => {
+                let text = cpu.backtrace().text(self.bxnn, self.symbols);
+                self.output = text.lines().map(str::to_string).collect();
+                String::new()
+            }
+            Command::Help => {
+                self.output = COMMANDS.iter().map(|line| line.to_string()).collect();
+                String::new()
+            }
+            Command::Quit => {
+                terminal::request_exit();
+                String::new()
+            }
+        })
+    }
+
+    fn draw(&mut self, cpu: &Chip8, picture: &Picture, width: usize, height: usize) -> CellGrid {
+        let mut grid = CellGrid::new(width, height);
+        let state = cpu.trace_state();
+
+        let status = match (self.paused, self.target) {
+            (true, _) => "PAUSED",
+            (false, Some(_)) => "RUNNING TO CURSOR",
+            (false, None) => "RUNNING",
+        };
+        let title = format!(
+            " CHIP-8 debugger   {}   frame {}   cycle {}",
+            status,
+            self.frames,
+            cpu.cycles()
+        );
+        grid.print(
+            0,
+            0,
+            &format!("{:<width$}", title),
+            Color::Black,
+            Color::Grey,
+        );
+
+        let screen = render::render(picture.image(), RenderMode::Half, Color::Reset);
+        grid.paste(0, 1, &screen);
+
+        self.draw_registers(&mut grid, cpu, &state);
+        self.draw_stack(&mut grid, cpu);
+
+        let rows = height - 3 - BOTTOM;
+        if self.output.is_empty() {
+            self.draw_disassembly(&mut grid, cpu, rows);
+            self.draw_memory(&mut grid, cpu, &state, rows);
+        } else {
+            header(&mut grid, 0, BOTTOM, "Output (Esc to close)");
+            for (row, line) in self.output.iter().take(rows).enumerate() {
+                grid.print(0, BOTTOM + 1 + row, line, Color::Reset, Color::Reset);
+            }
+        }
+
+        grid.print(0, height - 2, &self.message, Color::Yellow, Color::Reset);
+        match &self.prompt {
+            Some(prompt) => grid.print(
+                0,
+                height - 1,
+                &format!(":{}_", prompt),
+                Color::Reset,
+                Color::Reset,
+            ),
+            None => grid.print(0, height - 1, HELP, Color::DarkGrey, Color::Reset),
+        }
+        grid
+    }
+
+    fn draw_registers(&self, grid: &mut CellGrid, cpu: &Chip8, state: &State) {
+        // Registers that changed since the program last stopped stand out
+        let color = |changed: bool| if changed { Color::Yellow } else { Color::Reset };
+        let before = &self.stopped_at;
+
+        header(grid, RIGHT, 1, "Registers");
+        grid.print(
+            RIGHT,
+            2,
+            &format!("PC {:03X}", cpu.pc()),
+            Color::Reset,
+            Color::Reset,
+        );
+        let i = format!("I {:03X}", state.i);
+        grid.print(RIGHT + 8, 2, &i, color(state.i != before.i), Color::Reset);
+        let sp = format!("SP {:X}", state.sp);
+        grid.print(
+            RIGHT + 16,
+            2,
+            &sp,
+            color(state.sp != before.sp),
+            Color::Reset,
+        );
+        let delay = format!("DT {:02X}", state.delay);
+        grid.print(
+            RIGHT,
+            3,
+            &delay,
+            color(state.delay != before.delay),
+            Color::Reset,
+        );
+        let sound = format!("ST {:02X}", state.sound);
+        grid.print(
+            RIGHT + 8,
+            3,
+            &sound,
+            color(state.sound != before.sound),
+            Color::Reset,
+        );
+
+        for (register, &value) in state.v.iter().enumerate() {
+            let x = RIGHT + (register % 4) * 7;
+            let y = 5 + register / 4;
+            let text = format!("V{:X} {:02X}", register, value);
+            let changed = value != before.v[register];
+            grid.print(x, y, &text, color(changed), Color::Reset);
+        }
+    }
+
+    fn draw_stack(&self, grid: &mut CellGrid, cpu: &Chip8) {
+        header(grid, RIGHT, 10, "Stack, innermost first");
+        let returns = cpu.stack();
+        if returns.is_empty() {
+            grid.print(RIGHT, 11, "(empty)", Color::DarkGrey, Color::Reset);
+        }
+        for (row, &address) in returns.iter().rev().enumerate().take(STACK_LINES) {
+            let text = if row == STACK_LINES - 1 && returns.len() > STACK_LINES {
+                format!("... {} more", returns.len() - row)
+            } else {
+                format!("{:<2} {}", row, self.describe(address))
+            };
+            grid.print(RIGHT, 11 + row, &text, Color::Reset, Color::Reset);
+        }
+    }
+
+    fn draw_disassembly(&mut self, grid: &mut CellGrid, cpu: &Chip8, rows: usize) {
+        header(grid, 0, BOTTOM, "Disassembly");
+
+        // Only scroll when the cursor leaves the pane
+        let span = 2 * rows as u16;
+        if self.cursor < self.top
+            || self.cursor >= self.top + span
+            || self.top % 2 != self.cursor % 2
+        {
+            self.top = self.cursor.saturating_sub(2 * (rows as u16 / 3));
+        }
+
+        let memory = cpu.memory();
+        for row in 0..rows {
+            let address = self.top + 2 * row as u16;
+            if address as usize + 1 >= MEMORY_SIZE {
+                break;
+            }
+            let opcode =
+                ((memory[address as usize] as u16) << 8) | memory[address as usize + 1] as u16;
+            let mnemonic = self.symbols.mnemonic(opcode, self.bxnn).unwrap_or_default();
+            let cursor = if address == self.cursor { '>' } else { ' ' };
+            let breakpoint = if self.breakpoints.contains(&address) {
+                '*'
+            } else {
+                ' '
+            };
+            let name = self.symbols.name(address).unwrap_or_default();
+            let line = format!(
+                "{}{} {:03X}  {:04X}  {:<18}{}",
+                cursor, breakpoint, address, opcode, mnemonic, name
+            );
+            let bg = if address == cpu.pc() {
+                Color::DarkGrey
+            } else {
+                Color::Reset
+            };
+            let y = BOTTOM + 1 + row;
+            grid.print(0, y, &format!("{:<44}", line), Color::Reset, bg);
+            if breakpoint != ' ' {
+                grid.print(1, y, "*", Color::Red, bg);
+            }
+        }
+    }
+
+    fn draw_memory(&self, grid: &mut CellGrid, cpu: &Chip8, state: &State, rows: usize) {
+        header(grid, MEMORY_LEFT, BOTTOM, "Memory");
+        let per_row = if grid.width() >= MEMORY_LEFT + 5 + 16 * 3 {
+            16
+        } else {
+            8
+        };
+
+        // The bytes the next instruction uses from I, or just the one at I
+        let memory = cpu.memory();
+        let pc = cpu.pc() as usize;
+        let opcode = ((memory[pc] as u16) << 8) | memory[(pc + 1) % MEMORY_SIZE] as u16;
+        let used = disasm::data_access(opcode).map_or(1, |(len, _)| len.max(1));
+        let i = state.i as usize;
+        let highlighted = i..i + used;
+
+        let at = self.memory_at.unwrap_or(state.i) as usize;
+        let last = MEMORY_SIZE.saturating_sub(rows * per_row);
+        let start = (at / per_row * per_row).saturating_sub(per_row).min(last);
+        for row in 0..rows {
+            let address = start + row * per_row;
+            if address >= MEMORY_SIZE {
+                break;
+            }
+            let y = BOTTOM + 1 + row;
+            grid.print(
+                MEMORY_LEFT,
+                y,
+                &format!("{:03X}", address),
+                Color::DarkGrey,
+                Color::Reset,
+            );
+            for column in 0..per_row {
+                let byte = address + column;
+                let bg = if highlighted.contains(&byte) {
+                    Color::DarkBlue
+                } else {
+                    Color::Reset
+                };
+                let x = MEMORY_LEFT + 5 + column * 3;
+                grid.print(x, y, &format!("{:02X}", memory[byte]), Color::Reset, bg);
+            }
+        }
+    }
+}
+
+// A command typed at the prompt, with its addresses looked up
+#[derive(Debug, PartialEq)]
+enum Command {
+    Nothing,
+    Set(Register, u16),
+    MemoryFollowsI,
+    Memory(u16),
+    Write(u16, Vec<u8>),
+    Goto(u16),
+    Breakpoints,
+    Breakpoint(u16),
+    Step(u64),
+    Continue,
+    Backtrace,
+    Help,
+    Quit,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Register {
+    V(u8),
+    I,
+    Pc,
+    Delay,
+    Sound,
+}
+
+impl Register {
+    fn name(self) -> String {
+        match self {
+            Register::V(x) => format!("V{:X}", x),
+            Register::I => "I".to_string(),
+            Register::Pc => "PC".to_string(),
+            Register::Delay => "DT".to_string(),
+            Register::Sound => "ST".to_string(),
+        }
+    }
+}
+
+fn parse(line: &str, symbols: &Symbols) -> Result<Command, String> {
+    let line = line.trim();
+    if let Some((name, value)) = line.split_once('=') {
+        return parse_assignment(&name.trim().to_lowercase(), value.trim(), symbols);
+    }
+
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let address = |text: &str| {
+        symbols
+            .parse_address(text)
+            .ok_or_else(|| format!("'{}' isn't an address or a known @label", text))
+    };
+    Ok(match words[..] {
+        [] => Command::Nothing,
+        ["m" | "mem", "i"] => Command::MemoryFollowsI,
+        ["m" | "mem", at] => Command::Memory(address(at)?),
+        ["m" | "mem", at, ref bytes @ ..] => {
+            let start = address(at)?;
+            let bytes = bytes
+                .iter()
+                .map(|byte| {
+                    u8::from_str_radix(byte, 16).map_err(|_| format!("'{}' isn't a byte", byte))
+                })
+                .collect::<Result<Vec<u8>, String>>()?;
+            if start as usize + bytes.len() > MEMORY_SIZE {
+                return Err("that goes past the end of memory".to_string());
+            }
+            Command::Write(start, bytes)
+        }
+        ["g" | "goto", at] => Command::Goto(address(at)?),
+        ["b" | "break"] => Command::Breakpoints,
+        ["b" | "break", at] => Command::Breakpoint(address(at)?),
+        ["s" | "step"] => Command::Step(1),
+        ["s" | "step", count] => Command::Step(
+            count
+                .parse()
+                .map_err(|_| format!("'{}' isn't a number", count))?,
+        ),
+        ["c" | "continue"] => Command::Continue,
+        ["bt" | "backtrace"] => Command::Backtrace,
+        ["h" | "help"] => Command::Help,
+        ["q" | "quit"] => Command::Quit,
+        _ => return Err("unknown command, try help".to_string()),
+    })
+}
+
+// A register or timer from hex, or an address from a @label
+fn parse_assignment(name: &str, value: &str, symbols: &Symbols) -> Result<Command, String> {
+    let bad = || format!("'{}' isn't a value for {}", value, name.to_uppercase());
+    let byte = || {
+        u8::from_str_radix(value, 16)
+            .map(u16::from)
+            .map_err(|_| bad())
+    };
+    let address = || symbols.parse_address(value).ok_or_else(bad);
+
+    Ok(match name {
+        "i" => Command::Set(Register::I, address()?),
+        "pc" => {
+            let pc = address()?;
+            if pc as usize >= MEMORY_SIZE - 1 {
+                return Err(bad());
+            }
+            Command::Set(Register::Pc, pc)
+        }
+        "dt" => Command::Set(Register::Delay, byte()?),
+        "st" => Command::Set(Register::Sound, byte()?),
+        _ => {
+            let register = name
+                .strip_prefix('v')
+                .filter(|digit| digit.len() == 1)
+                .and_then(|digit| u8::from_str_radix(digit, 16).ok())
+                .ok_or_else(|| format!("'{}' isn't a register", name))?;
+            Command::Set(Register::V(register), byte()?)
+        }
+    })
+}
+
+fn header(grid: &mut CellGrid, x: usize, y: usize, title: &str) {
+    grid.print(x, y, title, Color::Cyan, Color::Reset);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random;
+    use crate::rom::Rom;
+    use crate::settings::Settings;
+
+    fn symbols() -> Symbols {
+        Symbols::from_text("204 draw\n").unwrap()
+    }
+
+    #[test]
+    fn parses_commands_with_labels_and_hex() {
+        let symbols = symbols();
+        let parse = |line| parse(line, &symbols).unwrap();
+        assert_eq!(parse(""), Command::Nothing);
+        assert_eq!(parse("m i"), Command::MemoryFollowsI);
+        assert_eq!(parse("m @draw"), Command::Memory(0x204));
+        assert_eq!(parse("mem 0x300"), Command::Memory(0x300));
+        assert_eq!(parse("m 300 a0 1"), Command::Write(0x300, vec![0xA0, 0x01]));
+        assert_eq!(parse("g $2A0"), Command::Goto(0x2A0));
+        assert_eq!(parse("b"), Command::Breakpoints);
+        assert_eq!(parse("b @draw"), Command::Breakpoint(0x204));
+        assert_eq!(parse("s"), Command::Step(1));
+        assert_eq!(parse("step 10"), Command::Step(10));
+        assert_eq!(parse("c"), Command::Continue);
+        assert_eq!(parse("bt"), Command::Backtrace);
+        assert_eq!(parse("v3=1F"), Command::Set(Register::V(3), 0x1F));
+        assert_eq!(parse("PC = @draw"), Command::Set(Register::Pc, 0x204));
+        assert_eq!(parse("dt=3c"), Command::Set(Register::Delay, 0x3C));
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        let symbols = symbols();
+        let parse = |line| parse(line, &symbols).unwrap_err();
+        assert_eq!(
+            parse("g @nowhere"),
+            "'@nowhere' isn't an address or a known @label"
+        );
+        assert_eq!(parse("b 1000"), "'1000' isn't an address or a known @label");
+        assert_eq!(parse("m 300 1g"), "'1g' isn't a byte");
+        assert_eq!(parse("m FFF 1 2"), "that goes past the end of memory");
+        assert_eq!(parse("s ten"), "'ten' isn't a number");
+        assert_eq!(parse("bt 2"), "unknown command, try help");
+        assert_eq!(parse("vg=1"), "'vg' isn't a register");
+        assert_eq!(parse("v0=100"), "'100' isn't a value for V0");
+        assert_eq!(parse("pc=FFF"), "'FFF' isn't a value for PC");
+    }
+
+    #[test]
+    fn crash_stops_on_the_failing_instruction() {
+        // CALL 204; JP 202; SYS 123
+        let bytes = [0x22, 0x04, 0x12, 0x02, 0x01, 0x23];
+        let rom = Rom::from_bytes(bytes.to_vec(), bytes.len()).unwrap();
+        let mut config = Settings::defaults().to_config().unwrap();
+        config.mute = true;
+        let mut cpu = Chip8::new(&rom, config, random::new(0));
+        let symbols = symbols();
+        let mut debugger = Debugger::new(&cpu, &symbols, false);
+
+        debugger.command(&mut cpu, "s 5").unwrap();
+        assert_eq!(cpu.pc(), 0x204);
+        assert_eq!(debugger.cursor, 0x204);
+        assert!(debugger.paused);
+        assert_eq!(
+            debugger.message,
+            "bad opcode at opcode 0x123 at address 0x204 (draw)"
+        );
+        assert_eq!(
+            debugger.output[..3],
+            [
+                "Backtrace, innermost call first:",
+                "  #0   204  0123                     in draw",
+                "  #1   200  2204  CALL draw          in main",
+            ]
+        );
+
+        // Fixed up with a RET, the program carries on from there
+        debugger.command(&mut cpu, "m @draw 00 EE").unwrap();
+        assert_eq!(debugger.command(&mut cpu, "s").unwrap(), "Stopped at 202");
+        assert_eq!(cpu.pc(), 0x202);
+    }
+}
@@ -0,0 +1,428 @@
+use std::{collections::VecDeque, fs, path::Path};
+
+use clap::ValueEnum;
+
+use crate::chip8::Chip8;
+use crate::disasm;
+use crate::symbols::Symbols;
+use crate::trace::{self, Entry, State};
+
+// Whether reference lines show the state before or after their instruction runs
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+pub enum Timing {
+    Before,
+    After,
+}
+
+// What one column of a reference line holds
+#[derive(Clone, Copy, PartialEq)]
+pub enum Column {
+    Skip,
+    Pc,
+    Opcode,
+    V(u8),
+    I,
+    Sp,
+    Dt,
+    St,
+}
+
+impl Column {
+    fn name(&self) -> String {
+        match self {
+            Column::Skip => "_".to_string(),
+            Column::Pc => "PC".to_string(),
+            Column::Opcode => "opcode".to_string(),
+            Column::V(register) => format!("V{:X}", register),
+            Column::I => "I".to_string(),
+            Column::Sp => "SP".to_string(),
+            Column::Dt => "DT".to_string(),
+            Column::St => "ST".to_string(),
+        }
+    }
+
+    fn format(&self, value: u32) -> String {
+        match self {
+            Column::Pc | Column::I => format!("{:03X}", value),
+            Column::Opcode => format!("{:04X}", value),
+            _ => format!("{:02X}", value),
+        }
+    }
+}
+
+// A comma separated list of columns: pc, opcode, v0 to vf, v for all 16,
+// i, sp, dt, st, or _ for a column to ignore
+pub fn parse_layout(spec: &str) -> Result<Vec<Column>, String> {
+    let mut layout = Vec::new();
+    for name in spec.split(',') {
+        let name = name.trim().to_lowercase();
+        match name.as_str() {
+            "_" => layout.push(Column::Skip),
+            "pc" => layout.push(Column::Pc),
+            "op" | "opcode" => layout.push(Column::Opcode),
+            "v" => layout.extend((0..16).map(Column::V)),
+            "i" => layout.push(Column::I),
+            "sp" => layout.push(Column::Sp),
+            "dt" => layout.push(Column::Dt),
+            "st" => layout.push(Column::St),
+            _ => match name
+                .strip_prefix('v')
+                .and_then(|r| u8::from_str_radix(r, 16).ok())
+            {
+                Some(register) if register < 16 && name.len() == 2 => {
+                    layout.push(Column::V(register))
+                }
+                _ => return Err(format!("Unknown trace column '{}'", name)),
+            },
+        }
+    }
+    Ok(layout)
+}
+
+// One line of the reference trace
+struct Line {
+    number: usize,
+    text: String,
+    values: Vec<(Column, u32)>,
+}
+
+impl Line {
+    fn get(&self, column: Column) -> Option<u32> {
+        self.values
+            .iter()
+            .find(|(c, _)| *c == column)
+            .map(|(_, value)| *value)
+    }
+}
+
+// Fields are split on whitespace and commas, and a "NAME:" or "NAME="
+// label in front of a value is ignored. Values are hex, with or without 0x.
+fn parse_line(number: usize, text: &str, layout: &[Column]) -> Result<Line, String> {
+    let fields: Vec<&str> = text
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|field| !field.is_empty())
+        .collect();
+    if fields.len() < layout.len() {
+        return Err(format!(
+            "Reference line {} has {} fields, the layout needs {}",
+            number,
+            fields.len(),
+            layout.len()
+        ));
+    }
+
+    let mut values = Vec::new();
+    for (column, field) in layout.iter().zip(fields) {
+        if *column == Column::Skip {
+            continue;
+        }
+        let value = field.rsplit([':', '=']).next().unwrap_or(field);
+        let value = value.trim_start_matches("0x").trim_start_matches('$');
+        let value = u32::from_str_radix(value, 16).map_err(|_| {
+            format!(
+                "Reference line {}: '{}' is not a hex value for {}",
+                number,
+                field,
+                column.name()
+            )
+        })?;
+        values.push((*column, value));
+    }
+
+    Ok(Line {
+        number,
+        text: text.to_string(),
+        values,
+    })
+}
+
+pub struct Options {
+    pub layout: Vec<Column>,
+    pub timing: Timing,
+    // Header lines to skip at the start of the reference
+    pub skip_lines: usize,
+    // Cycles to compare, or all of the reference
+    pub cycles: Option<u64>,
+    // Instructions shown before the divergence
+    pub context: usize,
+    // How BNNN is shown
+    pub bxnn: bool,
+    // Labels for the report
+    pub symbols: Symbols,
+}
+
+pub enum Outcome {
+    Matched(u64),
+    // A report of where and how the run went differently
+    Diverged(String),
+}
+
+// Runs `cpu` an instruction at a time with no keys pressed, comparing every
+// instruction with a line of the reference. Numbers from CXNN are copied
+// from the reference when it has them, since no two emulators' random
+// numbers agree.
+pub fn run(cpu: &mut Chip8, reference: &Path, options: &Options) -> Result<Outcome, String> {
+    let text = fs::read_to_string(reference)
+        .map_err(|e| format!("Failed to read {}: {}", reference.display(), e))?;
+    run_text(cpu, &text, options)
+}
+
+fn run_text(cpu: &mut Chip8, text: &str, options: &Options) -> Result<Outcome, String> {
+    let mut lines = Vec::new();
+    for (index, line) in text.lines().enumerate().skip(options.skip_lines) {
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+        lines.push(parse_line(index + 1, line, &options.layout)?);
+    }
+
+    let count = match options.cycles {
+        Some(cycles) => (cycles as usize).min(lines.len()),
+        None => lines.len(),
+    };
+    let mut history = VecDeque::new();
+    let mut cycle = 0;
+
+    loop {
+        let instructions = cpu.begin_frame(0);
+        for _ in 0..instructions {
+            if cycle == count {
+                return Ok(Outcome::Matched(cycle as u64));
+            }
+
+            let pc = cpu.pc();
+            let memory = cpu.memory();
+            let opcode = ((memory[pc as usize] as u16) << 8) | (memory[pc as usize + 1] as u16);
+            let before = cpu.trace_state();
+            if options.timing == Timing::Before
+                && let Some(report) = compare(&lines, cycle, pc, opcode, &before, &history, options)
+            {
+                return Ok(Outcome::Diverged(report));
+            }
+
+            let result = cpu.run_instruction();
+            if disasm::pattern(opcode) == Some("CXNN") {
+                let register = ((opcode & 0x0F00) >> 8) as u8;
+                let line = match options.timing {
+                    Timing::Before => lines.get(cycle + 1),
+                    Timing::After => lines.get(cycle),
+                };
+                if let Some(value) = line.and_then(|line| line.get(Column::V(register))) {
+                    cpu.set_v(register, value as u8);
+                }
+            }
+            let after = cpu.trace_state();
+
+            history.push_back(Entry {
+                cycle: cycle as u64,
+                pc,
+                opcode,
+                changes: trace::changes(&before, &after),
+            });
+            if history.len() > options.context {
+                history.pop_front();
+            }
+
+            if let Err(err) = result {
+                let mut report = format!("Stopped at cycle {}: {}\n", cycle, err);
+                report += &context(&lines, cycle, &history, options);
+                return Ok(Outcome::Diverged(report));
+            }
+            if options.timing == Timing::After
+                && let Some(report) = compare(&lines, cycle, pc, opcode, &after, &history, options)
+            {
+                return Ok(Outcome::Diverged(report));
+            }
+
+            cycle += 1;
+        }
+        cpu.end_frame();
+    }
+}
+
+// A report if the state differs from the reference at `cycle`
+fn compare(
+    lines: &[Line],
+    cycle: usize,
+    pc: u16,
+    opcode: u16,
+    state: &State,
+    history: &VecDeque<Entry>,
+    options: &Options,
+) -> Option<String> {
+    let line = &lines[cycle];
+    let mut differences = String::new();
+    for &(column, theirs) in &line.values {
+        let ours = match column {
+            Column::Skip => continue,
+            Column::Pc => pc as u32,
+            Column::Opcode => opcode as u32,
+            Column::V(register) => state.v[register as usize] as u32,
+            Column::I => state.i as u32,
+            Column::Sp => state.sp as u32,
+            Column::Dt => state.delay as u32,
+            Column::St => state.sound as u32,
+        };
+        if ours != theirs {
+            differences += &format!(
+                "  {:<7} ours {:<5} reference {}\n",
+                column.name(),
+                column.format(ours),
+                column.format(theirs)
+            );
+        }
+    }
+    if differences.is_empty() {
+        return None;
+    }
+
+    let mut report = format!(
+        "Diverged at cycle {} (reference line {}):\n{}",
+        cycle, line.number, differences
+    );
+    report += &context(lines, cycle, history, options);
+    Some(report)
+}
+
+fn context(lines: &[Line], cycle: usize, history: &VecDeque<Entry>, options: &Options) -> String {
+    let mut text = String::from("\nLast instructions run:\n");
+    for entry in history {
+        text += &format!("  {}\n", entry.to_text(options.bxnn, &options.symbols));
+    }
+
+    text += "\nReference:\n";
+    let start = cycle.saturating_sub(options.context);
+    let end = (cycle + 3).min(lines.len());
+    for (index, line) in lines[start..end].iter().enumerate() {
+        let marker = if start + index == cycle { '>' } else { ' ' };
+        text += &format!("{} {:>7}  {}\n", marker, line.number, line.text);
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random;
+    use crate::rom::Rom;
+    use crate::settings::Settings;
+
+    // LD V0, 5; RND V1, 0F; ADD V1, 1; JP 206
+    const ROM: &[u8] = &[0x60, 0x05, 0xC1, 0x0F, 0x71, 0x01, 0x12, 0x06];
+
+    fn options(layout: &str, timing: Timing) -> Options {
+        Options {
+            layout: parse_layout(layout).unwrap(),
+            timing,
+            skip_lines: 1,
+            cycles: None,
+            context: 2,
+            bxnn: false,
+            symbols: Symbols::default(),
+        }
+    }
+
+    fn diff(reference: &str, options: &Options) -> Outcome {
+        let rom = Rom::from_bytes(ROM.to_vec(), ROM.len()).unwrap();
+        let mut config = Settings::defaults().to_config().unwrap();
+        config.mute = true;
+        let mut cpu = Chip8::new(&rom, config, random::new(1));
+        run_text(&mut cpu, reference, options).unwrap()
+    }
+
+    fn diverged(outcome: Outcome) -> String {
+        match outcome {
+            Outcome::Matched(cycles) => panic!("matched {} cycles", cycles),
+            Outcome::Diverged(report) => report,
+        }
+    }
+
+    // States before each instruction. The 7 from RND is copied into the run.
+    const BEFORE: &str = "\
+        pc   op   v0   v1
+        PC:200 6005 V0:00 V1:00
+        PC:202 C10F V0:05 V1:00
+        # the random number
+        PC:204 7101 V0:05 V1:07
+        PC:206 1206 V0:05 V1:08
+    ";
+
+    // States after each instruction
+    const AFTER: &str = "\
+        pc,opcode,v0,v1
+        0x200, 0x6005, $05, $00
+        0x202, 0xC10F, $05, $07
+        0x204, 0x7101, $05, $08
+        0x206, 0x1206, $05, $08
+    ";
+
+    #[test]
+    fn parses_layouts() {
+        let layout = parse_layout("pc, OP,v,_,vf,sp,dt,st").unwrap();
+        assert_eq!(layout.len(), 2 + 16 + 5);
+        assert!(layout[2] == Column::V(0) && layout[17] == Column::V(15));
+        assert!(layout[18] == Column::Skip && layout[19] == Column::V(15));
+        assert!(layout[20..] == [Column::Sp, Column::Dt, Column::St]);
+
+        for bad in ["x", "v10", "vg", "pc,,i"] {
+            assert!(parse_layout(bad).is_err(), "{}", bad);
+        }
+        assert_eq!(
+            parse_layout("pc,v10").err().unwrap(),
+            "Unknown trace column 'v10'"
+        );
+    }
+
+    #[test]
+    fn parses_lines_with_labels_and_prefixes() {
+        let layout = parse_layout("pc,_,i,sp,v3").unwrap();
+        let line = parse_line(7, "PC=0x2A0 junk, I:$3F0 SP:1 0a extra", &layout).unwrap();
+        assert_eq!(line.number, 7);
+        assert_eq!(line.get(Column::Pc), Some(0x2A0));
+        assert_eq!(line.get(Column::I), Some(0x3F0));
+        assert_eq!(line.get(Column::Sp), Some(1));
+        assert_eq!(line.get(Column::V(3)), Some(0x0A));
+        assert_eq!(line.get(Column::Skip), None);
+
+        let err = parse_line(3, "200 0 0", &layout).err().unwrap();
+        assert_eq!(err, "Reference line 3 has 3 fields, the layout needs 5");
+        let err = parse_line(4, "200 0 I:xyz 0 0", &layout).err().unwrap();
+        assert_eq!(err, "Reference line 4: 'I:xyz' is not a hex value for I");
+    }
+
+    #[test]
+    fn matching_runs_copy_random_numbers() {
+        let outcome = diff(BEFORE, &options("pc,op,v0,v1", Timing::Before));
+        assert!(matches!(outcome, Outcome::Matched(4)));
+        let outcome = diff(AFTER, &options("pc,op,v0,v1", Timing::After));
+        assert!(matches!(outcome, Outcome::Matched(4)));
+
+        let mut options = options("pc,op,v0,v1", Timing::Before);
+        options.cycles = Some(2);
+        assert!(matches!(diff(BEFORE, &options), Outcome::Matched(2)));
+    }
+
+    #[test]
+    fn divergence_names_the_cycle_and_column() {
+        let reference = BEFORE.replace("V1:08", "V1:09");
+        let report = diverged(diff(&reference, &options("pc,op,v0,v1", Timing::Before)));
+        assert!(
+            report.starts_with(
+                "Diverged at cycle 3 (reference line 6):\n  V1      ours 08    reference 09\n"
+            ),
+            "{}",
+            report
+        );
+        assert!(report.contains("> "));
+
+        let reference = AFTER.replace("0x200, 0x6005, $05", "0x200, 0x6005, $06");
+        let report = diverged(diff(&reference, &options("pc,op,v0,v1", Timing::After)));
+        assert!(
+            report.starts_with(
+                "Diverged at cycle 0 (reference line 2):\n  V0      ours 05    reference 06\n"
+            ),
+            "{}",
+            report
+        );
+    }
+}
@@ -0,0 +1,180 @@
+// Every name `pattern` returns
+pub const PATTERNS: [&str; 34] = [
+    "00E0", "00EE", "1NNN", "2NNN", "3XNN", "4XNN", "5XY0", "6XNN", "7XNN", "8XY0", "8XY1", "8XY2",
+    "8XY3", "8XY4", "8XY5", "8XY6", "8XY7", "8XYE", "9XY0", "ANNN", "BNNN", "CXNN", "DXYN", "EX9E",
+    "EXA1", "FX07", "FX0A", "FX15", "FX18", "FX1E", "FX29", "FX33", "FX55", "FX65",
+];
+
+// Opcode names in the form used in comments and docs, like "8XY4", or None
+// for opcodes the interpreter doesn't run
+pub fn pattern(opcode: u16) -> Option<&'static str> {
+    let n = opcode & 0x000F;
+    let nn = opcode & 0x00FF;
+
+    let pattern = match opcode >> 12 {
+        0x0 if opcode == 0x00E0 => "00E0",
+        0x0 if opcode == 0x00EE => "00EE",
+        0x1 => "1NNN",
+        0x2 => "2NNN",
+        0x3 => "3XNN",
+        0x4 => "4XNN",
+        0x5 if n == 0 => "5XY0",
+        0x6 => "6XNN",
+        0x7 => "7XNN",
+        0x8 => match n {
+            0x0 => "8XY0",
+            0x1 => "8XY1",
+            0x2 => "8XY2",
+            0x3 => "8XY3",
+            0x4 => "8XY4",
+            0x5 => "8XY5",
+            0x6 => "8XY6",
+            0x7 => "8XY7",
+            0xE => "8XYE",
+            _ => return None,
+        },
+        0x9 if n == 0 => "9XY0",
+        0xA => "ANNN",
+        0xB => "BNNN",
+        0xC => "CXNN",
+        0xD => "DXYN",
+        0xE if nn == 0x9E => "EX9E",
+        0xE if nn == 0xA1 => "EXA1",
+        0xF => match nn {
+            0x07 => "FX07",
+            0x0A => "FX0A",
+            0x15 => "FX15",
+            0x18 => "FX18",
+            0x1E => "FX1E",
+            0x29 => "FX29",
+            0x33 => "FX33",
+            0x55 => "FX55",
+            0x65 => "FX65",
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    Some(pattern)
+}
+
+// Assembly for `opcode`, like "ADD V1, V2", or None for opcodes the
+// interpreter doesn't run. `bxnn` picks how BNNN is shown.
+pub fn mnemonic(opcode: u16, bxnn: bool) -> Option<String> {
+    let x = (opcode & 0x0F00) >> 8;
+    let y = (opcode & 0x00F0) >> 4;
+    let n = opcode & 0x000F;
+    let nn = opcode & 0x00FF;
+    let nnn = opcode & 0x0FFF;
+
+    let text = match pattern(opcode)? {
+        "00E0" => "CLS".to_string(),
+        "00EE" => "RET".to_string(),
+        "1NNN" => format!("JP {:03X}", nnn),
+        "2NNN" => format!("CALL {:03X}", nnn),
+        "3XNN" => format!("SE V{:X}, {:02X}", x, nn),
+        "4XNN" => format!("SNE V{:X}, {:02X}", x, nn),
+        "5XY0" => format!("SE V{:X}, V{:X}", x, y),
+        "6XNN" => format!("LD V{:X}, {:02X}", x, nn),
+        "7XNN" => format!("ADD V{:X}, {:02X}", x, nn),
+        "8XY0" => format!("LD V{:X}, V{:X}", x, y),
+        "8XY1" => format!("OR V{:X}, V{:X}", x, y),
+        "8XY2" => format!("AND V{:X}, V{:X}", x, y),
+        "8XY3" => format!("XOR V{:X}, V{:X}", x, y),
+        "8XY4" => format!("ADD V{:X}, V{:X}", x, y),
+        "8XY5" => format!("SUB V{:X}, V{:X}", x, y),
+        "8XY6" => format!("SHR V{:X}, V{:X}", x, y),
+        "8XY7" => format!("SUBN V{:X}, V{:X}", x, y),
+        "8XYE" => format!("SHL V{:X}, V{:X}", x, y),
+        "9XY0" => format!("SNE V{:X}, V{:X}", x, y),
+        "ANNN" => format!("LD I, {:03X}", nnn),
+        "BNNN" if bxnn => format!("JP V{:X}, {:03X}", x, nnn),
+        "BNNN" => format!("JP V0, {:03X}", nnn),
+        "CXNN" => format!("RND V{:X}, {:02X}", x, nn),
+        "DXYN" => format!("DRW V{:X}, V{:X}, {:X}", x, y, n),
+        "EX9E" => format!("SKP V{:X}", x),
+        "EXA1" => format!("SKNP V{:X}", x),
+        "FX07" => format!("LD V{:X}, DT", x),
+        "FX0A" => format!("LD V{:X}, K", x),
+        "FX15" => format!("LD DT, V{:X}", x),
+        "FX18" => format!("LD ST, V{:X}", x),
+        "FX1E" => format!("ADD I, V{:X}", x),
+        "FX29" => format!("LD F, V{:X}", x),
+        "FX33" => format!("LD B, V{:X}", x),
+        "FX55" => format!("LD [I], V{:X}", x),
+        "FX65" => format!("LD V{:X}, [I]", x),
+        _ => unreachable!(),
+    };
+    Some(text)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+// How many bytes from I on `opcode` reads or writes, if any
+pub fn data_access(opcode: u16) -> Option<(usize, Access)> {
+    let x = ((opcode & 0x0F00) >> 8) as usize;
+    match pattern(opcode)? {
+        "DXYN" => Some(((opcode & 0xF) as usize, Access::Read)),
+        "FX65" => Some((x + 1, Access::Read)),
+        "FX55" => Some((x + 1, Access::Write)),
+        "FX33" => Some((3, Access::Write)),
+        _ => None,
+    }
+}
+
+// Instructions from other platforms that the interpreter doesn't run
+#[derive(Clone, Copy, PartialEq)]
+pub enum Extension {
+    // 0NNN, which calls machine code on the COSMAC VIP
+    MachineCode,
+    SuperChip,
+    XoChip,
+}
+
+impl Extension {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Extension::MachineCode => "machine code call",
+            Extension::SuperChip => "SUPER-CHIP instruction",
+            Extension::XoChip => "XO-CHIP instruction",
+        }
+    }
+}
+
+pub fn extension(opcode: u16) -> Option<Extension> {
+    if pattern(opcode).is_some() {
+        return None;
+    }
+
+    match opcode {
+        0x00C0..=0x00CF | 0x00FB..=0x00FF => Some(Extension::SuperChip),
+        0x00D0..=0x00DF | 0xF000 | 0xF002 => Some(Extension::XoChip),
+        _ if opcode & 0xF000 == 0 => Some(Extension::MachineCode),
+        _ => match opcode & 0xF0FF {
+            0xF030 | 0xF075 | 0xF085 => Some(Extension::SuperChip),
+            0xF001 | 0xF03A => Some(Extension::XoChip),
+            _ if matches!(opcode & 0xF00F, 0x5002 | 0x5003) => Some(Extension::XoChip),
+            _ => None,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns_lists_every_pattern() {
+        let mut seen: Vec<&str> = (0..=0xFFFF).filter_map(pattern).collect();
+        seen.sort();
+        seen.dedup();
+
+        let mut listed = PATTERNS.to_vec();
+        listed.sort();
+        assert_eq!(seen, listed);
+    }
+}
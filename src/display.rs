@@ -0,0 +1,98 @@
+use crate::palette::Palette;
+use crate::render::Image;
+
+pub const WIDTH: usize = 64;
+pub const HEIGHT: usize = 32;
+
+#[derive(Clone, PartialEq)]
+pub struct Display {
+    buffer: [[u8; 8]; 32],
+}
+
+impl Display {
+    pub fn new() -> Self {
+        Self {
+            buffer: [[0; 8]; 32],
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.buffer = [[0; 8]; 32]
+    }
+
+    pub fn set(&mut self, x: u8, y: u8, pixel: bool) -> bool {
+        let curr = self.get_bit(x as usize, y as usize);
+        self.set_bit(x as usize, y as usize, curr ^ pixel);
+        curr && pixel
+    }
+
+    fn get_bit(&self, x: usize, y: usize) -> bool {
+        let byte = self.buffer[y][x / 8];
+        let bit = x % 8;
+        (byte & (1 << bit)) != 0
+    }
+
+    fn set_bit(&mut self, x: usize, y: usize, value: bool) {
+        let byte = &mut self.buffer[y][x / 8];
+        let bit = x % 8;
+        if value {
+            *byte |= 1 << bit;
+        } else {
+            *byte &= !(1 << bit);
+        }
+    }
+
+    pub fn draw_row(&mut self, byte: u8, x: u8, y: u8) -> Result<bool, &'static str> {
+        let pixels = Self::extract_pixels(byte);
+        let mut collision = false;
+
+        if y > 31 {
+            return Ok(false);
+        }
+
+        for i in 0..8 {
+            if x + i > 63 {
+                break;
+            }
+            if self.set(x + i, y, pixels[i as usize]) {
+                collision = true;
+            }
+        }
+
+        Ok(collision)
+    }
+
+    fn extract_pixels(byte: u8) -> [bool; 8] {
+        let mut pixels = [false; 8];
+
+        for i in 0..8 {
+            pixels[i] = ((byte >> (7 - i)) & 1) == 1;
+        }
+
+        pixels
+    }
+
+    // The display as the CPU sees it, in the palette's colors
+    pub fn image(&self, palette: &Palette) -> Image {
+        let mut image = Image::new(WIDTH, HEIGHT, palette.off());
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                if self.get_bit(x, y) {
+                    image.set(x, y, palette.on());
+                }
+            }
+        }
+        image
+    }
+
+    // Every pixel, a row at a time
+    pub fn pixels(&self) -> Vec<bool> {
+        (0..WIDTH * HEIGHT)
+            .map(|i| self.get_bit(i % WIDTH, i / WIDTH))
+            .collect()
+    }
+
+    pub fn bytes(&self) -> &[[u8; 8]; 32] {
+        &self.buffer
+    }
+}
@@ -0,0 +1,257 @@
+use std::collections::VecDeque;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::display::{Display, HEIGHT, WIDTH};
+use crate::palette::{self, Palette};
+use crate::render::Image;
+
+// Smooths out the flicker from sprites being erased and redrawn. Only the
+// presented picture is filtered; the CPU always sees the real display.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterMode {
+    None,
+    // Average each pixel over the last N frames
+    Blend,
+    // Keep pixels fully lit for N frames after they turn off
+    Hold,
+    // Fade pixels out over N frames after they turn off, like phosphor
+    Decay,
+}
+
+pub struct DisplayFilter {
+    mode: FilterMode,
+    frames: usize,
+    // The last `frames` frames, newest last, for blending
+    history: VecDeque<Vec<bool>>,
+    // Frames since each pixel was last on, for hold and decay
+    age: Vec<usize>,
+}
+
+impl DisplayFilter {
+    pub fn new(mode: FilterMode, frames: usize) -> Self {
+        Self {
+            mode,
+            frames: frames.max(1),
+            history: VecDeque::new(),
+            age: Vec::new(),
+        }
+    }
+
+    // Takes the display for one frame and returns how lit each pixel looks,
+    // from 0.0 to 1.0
+    pub fn apply(&mut self, pixels: &[bool]) -> Vec<f32> {
+        let lit = |on: bool| if on { 1.0 } else { 0.0 };
+
+        match self.mode {
+            FilterMode::None => pixels.iter().map(|&on| lit(on)).collect(),
+            FilterMode::Blend => {
+                if self
+                    .history
+                    .front()
+                    .is_some_and(|f| f.len() != pixels.len())
+                {
+                    self.history.clear();
+                }
+                self.history.push_back(pixels.to_vec());
+                while self.history.len() > self.frames {
+                    self.history.pop_front();
+                }
+
+                let count = self.history.len() as f32;
+                (0..pixels.len())
+                    .map(|i| self.history.iter().filter(|f| f[i]).count() as f32 / count)
+                    .collect()
+            }
+            FilterMode::Hold | FilterMode::Decay => {
+                if self.age.len() != pixels.len() {
+                    self.age = vec![usize::MAX; pixels.len()];
+                }
+
+                let frames = self.frames;
+                let decay = self.mode == FilterMode::Decay;
+                self.age
+                    .iter_mut()
+                    .zip(pixels)
+                    .map(|(age, &on)| {
+                        *age = if on { 0 } else { age.saturating_add(1) };
+                        match *age {
+                            0 => 1.0,
+                            a if a > frames => 0.0,
+                            _ if !decay => 1.0,
+                            a => 1.0 - a as f32 / (frames + 1) as f32,
+                        }
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    // Whether the output would stay the same if the display doesn't change,
+    // so there's no need to keep presenting
+    pub fn is_settled(&self) -> bool {
+        match self.mode {
+            FilterMode::None => true,
+            FilterMode::Blend => match self.history.back() {
+                Some(last) => self.history.iter().all(|f| f == last),
+                None => true,
+            },
+            FilterMode::Hold | FilterMode::Decay => {
+                self.age.iter().all(|&a| a == 0 || a > self.frames)
+            }
+        }
+    }
+}
+
+// The picture that gets shown and recorded: the display in the palette's
+// colors, run through the filter once per frame
+pub struct Picture {
+    filter: DisplayFilter,
+    palette: Palette,
+    image: Image,
+    last: Option<Display>,
+}
+
+impl Picture {
+    pub fn new(filter: DisplayFilter, palette: Palette) -> Self {
+        Self {
+            filter,
+            image: Image::new(WIDTH, HEIGHT, palette.off()),
+            palette,
+            last: None,
+        }
+    }
+
+    // Returns whether the picture changed
+    pub fn update(&mut self, display: &Display) -> bool {
+        if self.last.as_ref() == Some(display) && self.filter.is_settled() {
+            return false;
+        }
+        self.last = Some(display.clone());
+
+        let (off, on) = (self.palette.off(), self.palette.on());
+        let brightness = self.filter.apply(&display.pixels());
+        for (i, amount) in brightness.into_iter().enumerate() {
+            self.image
+                .set(i % WIDTH, i / WIDTH, palette::blend(off, on, amount));
+        }
+        true
+    }
+
+    pub fn image(&self) -> &Image {
+        &self.image
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Three pixels: a sprite flickering every frame, one that stays on and
+    // one that turns off after the first frame
+    const FRAMES: [[bool; 3]; 4] = [
+        [true, true, true],
+        [false, true, false],
+        [true, true, false],
+        [false, true, false],
+    ];
+
+    fn run(mode: FilterMode, frames: usize) -> (Vec<Vec<f32>>, DisplayFilter) {
+        let mut filter = DisplayFilter::new(mode, frames);
+        let output = FRAMES.iter().map(|frame| filter.apply(frame)).collect();
+        (output, filter)
+    }
+
+    fn assert_close(actual: &[Vec<f32>], expected: &[[f32; 3]]) {
+        assert_eq!(actual.len(), expected.len());
+        for (frame, (a, e)) in actual.iter().zip(expected).enumerate() {
+            for (pixel, (a, e)) in a.iter().zip(e).enumerate() {
+                assert!(
+                    (a - e).abs() < 1e-6,
+                    "frame {} pixel {}: {} != {}",
+                    frame,
+                    pixel,
+                    a,
+                    e
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn none_passes_the_display_through() {
+        let (output, filter) = run(FilterMode::None, 3);
+        assert_close(
+            &output,
+            &[
+                [1.0, 1.0, 1.0],
+                [0.0, 1.0, 0.0],
+                [1.0, 1.0, 0.0],
+                [0.0, 1.0, 0.0],
+            ],
+        );
+        assert!(filter.is_settled());
+    }
+
+    #[test]
+    fn blend_averages_the_last_frames() {
+        let (output, filter) = run(FilterMode::Blend, 3);
+        let third = 1.0 / 3.0;
+        assert_close(
+            &output,
+            &[
+                [1.0, 1.0, 1.0],
+                [0.5, 1.0, 0.5],
+                [2.0 * third, 1.0, third],
+                [third, 1.0, 0.0],
+            ],
+        );
+        assert!(!filter.is_settled());
+    }
+
+    #[test]
+    fn hold_keeps_pixels_lit_for_n_frames() {
+        let (output, mut filter) = run(FilterMode::Hold, 2);
+        assert_close(
+            &output,
+            &[
+                [1.0, 1.0, 1.0],
+                [1.0, 1.0, 1.0],
+                [1.0, 1.0, 1.0],
+                [1.0, 1.0, 0.0],
+            ],
+        );
+        assert!(!filter.is_settled());
+        filter.apply(&[false, true, false]);
+        filter.apply(&[false, true, false]);
+        assert!(filter.is_settled());
+    }
+
+    #[test]
+    fn decay_fades_pixels_out() {
+        let (output, filter) = run(FilterMode::Decay, 2);
+        let (high, low) = (2.0 / 3.0, 1.0 / 3.0);
+        assert_close(
+            &output,
+            &[
+                [1.0, 1.0, 1.0],
+                [high, 1.0, high],
+                [1.0, 1.0, low],
+                [high, 1.0, 0.0],
+            ],
+        );
+        assert!(!filter.is_settled());
+    }
+
+    #[test]
+    fn pixels_never_lit_start_dark() {
+        let mut filter = DisplayFilter::new(FilterMode::Decay, 2);
+        assert_eq!(filter.apply(&[false, true]), [0.0, 1.0]);
+        let faded = filter.apply(&[false, false]);
+        assert!(faded[0] == 0.0 && (faded[1] - 2.0 / 3.0).abs() < 1e-6);
+        // A different display size starts over
+        assert_eq!(filter.apply(&[false]), [0.0]);
+    }
+}
@@ -0,0 +1,145 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::chip8::{MEMORY_SIZE, PROGRAM_START};
+use crate::disasm;
+
+// An instruction the program can reach, found without running it
+pub struct Instruction {
+    pub opcode: u16,
+    // Where the program can go next in the same routine. Calls go on to the
+    // instruction after them, as if they returned.
+    pub next: Vec<u16>,
+}
+
+// Where each BNNN jump went in a run, by the jump's address
+pub type Jumps = BTreeMap<u16, BTreeSet<u16>>;
+
+// The instructions reachable from the start of the program, following
+// jumps, calls and both sides of skips
+pub struct Flow {
+    pub instructions: BTreeMap<u16, Instruction>,
+    // Entry points of routines called with 2NNN
+    pub routines: BTreeSet<u16>,
+    // Addresses loaded into I by ANNN, which are usually data
+    pub data: BTreeSet<u16>,
+    // BNNN jumps, whose targets depend on V0. Only targets seen in a run
+    // are followed.
+    pub indirect: Vec<u16>,
+    // Places the program can go that weren't followed, by instruction: the
+    // interpreter area below the program, or past the end of the ROM
+    pub bad_targets: Vec<(u16, u16)>,
+}
+
+impl Flow {
+    // `memory` is the program loaded at PROGRAM_START, `rom_len` long
+    pub fn build(memory: &[u8], rom_len: usize, jumps: &Jumps) -> Self {
+        let end = PROGRAM_START + rom_len;
+        let mut flow = Flow {
+            instructions: BTreeMap::new(),
+            routines: BTreeSet::new(),
+            data: BTreeSet::new(),
+            indirect: Vec::new(),
+            bad_targets: Vec::new(),
+        };
+
+        let mut pending = vec![PROGRAM_START as u16];
+        while let Some(pc) = pending.pop() {
+            let address = pc as usize;
+            if flow.instructions.contains_key(&pc) || address + 1 >= end.min(MEMORY_SIZE) {
+                continue;
+            }
+
+            let opcode = ((memory[address] as u16) << 8) | memory[address + 1] as u16;
+            let nnn = opcode & 0x0FFF;
+            let after = pc + 2;
+            let mut next = Vec::new();
+            let mut call = None;
+
+            match disasm::pattern(opcode) {
+                Some("00EE") => {}
+                Some("1NNN") => next.push(nnn),
+                Some("2NNN") => {
+                    call = Some(nnn);
+                    flow.routines.insert(nnn);
+                    next.push(after);
+                }
+                Some("3XNN" | "4XNN" | "5XY0" | "9XY0" | "EX9E" | "EXA1") => {
+                    next.extend([after, after + 2])
+                }
+                Some("ANNN") => {
+                    flow.data.insert(nnn);
+                    next.push(after);
+                }
+                Some("BNNN") => {
+                    flow.indirect.push(pc);
+                    next.extend(jumps.get(&pc).into_iter().flatten());
+                }
+                Some(_) => next.push(after),
+                // Other platforms' instructions carry on, except for exiting
+                // and 0000, which is usually the program running into padding
+                None if opcode == 0x00FD || opcode == 0x0000 => {}
+                None if disasm::extension(opcode).is_some() => next.push(after),
+                None => {}
+            }
+
+            for &target in next.iter().chain(call.iter()) {
+                if (target as usize) < PROGRAM_START || target as usize + 1 >= end {
+                    flow.bad_targets.push((pc, target));
+                } else {
+                    pending.push(target);
+                }
+            }
+            flow.instructions.insert(pc, Instruction { opcode, next });
+        }
+
+        flow
+    }
+
+    // Whether `address` is one of the two bytes of a reachable instruction
+    pub fn is_code(&self, address: u16) -> bool {
+        self.instructions.contains_key(&address)
+            || address
+                .checked_sub(1)
+                .is_some_and(|a| self.instructions.contains_key(&a))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(rom: &[u8], jumps: &Jumps) -> Flow {
+        let mut memory = vec![0; MEMORY_SIZE];
+        memory[PROGRAM_START..][..rom.len()].copy_from_slice(rom);
+        Flow::build(&memory, rom.len(), jumps)
+    }
+
+    #[test]
+    fn follows_skips_calls_and_seen_jumps() {
+        let rom = [
+            0x30, 0x00, // 200: SE V0, 0
+            0x22, 0x0A, // 202: CALL 20A
+            0xA2, 0x0E, // 204: LD I, 20E
+            0xB2, 0x0C, // 206: JP V0, 20C
+            0x11, 0x00, // 208: JP 100
+            0x00, 0xEE, // 20A: RET
+            0x13, 0x00, // 20C: JP 300
+            0xFF, 0xFF, // 20E: data
+        ];
+        let flow = build(&rom, &Jumps::new());
+        let addresses: Vec<u16> = flow.instructions.keys().copied().collect();
+        assert_eq!(addresses, [0x200, 0x202, 0x204, 0x206, 0x20A]);
+        assert_eq!(flow.instructions[&0x200].next, [0x202, 0x204]);
+        assert_eq!(flow.instructions[&0x202].next, [0x204]);
+        assert_eq!(flow.routines, BTreeSet::from([0x20A]));
+        assert_eq!(flow.data, BTreeSet::from([0x20E]));
+        assert_eq!(flow.indirect, [0x206]);
+        assert_eq!(flow.bad_targets, []);
+        assert!(flow.is_code(0x20B) && !flow.is_code(0x208));
+
+        let jumps = Jumps::from([(0x206, BTreeSet::from([0x208, 0x20C]))]);
+        let flow = build(&rom, &jumps);
+        assert_eq!(flow.instructions[&0x206].next, [0x208, 0x20C]);
+        assert_eq!(flow.bad_targets, [(0x20C, 0x300), (0x208, 0x100)]);
+    }
+}
@@ -0,0 +1,264 @@
+use std::collections::BTreeSet;
+
+use crate::chip8::{Chip8, PROGRAM_START};
+use crate::disasm;
+use crate::flow::{Flow, Jumps};
+use crate::symbols::Symbols;
+
+// Instructions that run one after the other, entered only at the first
+struct Block {
+    start: u16,
+    // Addresses of its instructions, in order
+    instructions: Vec<u16>,
+}
+
+// Splits the program into basic blocks. Blocks start at the program and
+// routine entries and wherever a jump, skip or return can go, and end at
+// jumps, skips, calls and returns.
+fn blocks(flow: &Flow) -> Vec<Block> {
+    let mut leaders = BTreeSet::from([PROGRAM_START as u16]);
+    leaders.extend(&flow.routines);
+    for (&pc, instruction) in &flow.instructions {
+        if ends_block(pc, instruction.opcode, &instruction.next) {
+            leaders.extend(&instruction.next);
+        }
+    }
+
+    let mut blocks = Vec::new();
+    for &start in &leaders {
+        if !flow.instructions.contains_key(&start) {
+            continue;
+        }
+
+        let mut instructions = vec![start];
+        let mut pc = start;
+        loop {
+            let instruction = &flow.instructions[&pc];
+            if ends_block(pc, instruction.opcode, &instruction.next)
+                || leaders.contains(&(pc + 2))
+                || !flow.instructions.contains_key(&(pc + 2))
+            {
+                break;
+            }
+            pc += 2;
+            instructions.push(pc);
+        }
+        blocks.push(Block {
+            start,
+            instructions,
+        });
+    }
+    blocks
+}
+
+fn ends_block(pc: u16, opcode: u16, next: &[u16]) -> bool {
+    next != [pc + 2] || disasm::pattern(opcode) == Some("2NNN")
+}
+
+// The control-flow graph in Graphviz's DOT language. Dashed edges are calls
+// and dotted ones are BNNN jumps seen in a run.
+pub fn dot(flow: &Flow, name: &str, bxnn: bool, symbols: &Symbols) -> String {
+    let node = |address: u16| format!("b{:03X}", address);
+    let mut text = format!(
+        "digraph \"{}\" {{\n    node [shape=box, fontname=\"monospace\"];\n",
+        name.replace('\\', "\\\\").replace('"', "\\\"")
+    );
+
+    let mut outside = BTreeSet::new();
+    for block in blocks(flow) {
+        let mut label = String::new();
+        if block.start == PROGRAM_START as u16 || flow.routines.contains(&block.start) {
+            label += &format!("{}\\l", symbols.routine_name(block.start));
+        } else if let Some(name) = symbols.name(block.start) {
+            label += &format!("{}\\l", name);
+        }
+        for pc in &block.instructions {
+            let opcode = flow.instructions[pc].opcode;
+            let mnemonic = symbols.mnemonic(opcode, bxnn).unwrap_or_default();
+            label += format!("{:03X}  {:04X}  {}", pc, opcode, mnemonic).trim_end();
+            label += "\\l";
+        }
+        text += &format!("    {} [label=\"{}\"];\n", node(block.start), label);
+
+        let last = *block.instructions.last().unwrap();
+        let instruction = &flow.instructions[&last];
+        let pattern = disasm::pattern(instruction.opcode);
+        for &target in &instruction.next {
+            if !flow.instructions.contains_key(&target) {
+                outside.insert(target);
+            }
+            let attributes = match pattern {
+                Some("BNNN") => " [style=dotted]",
+                _ if pattern.is_some_and(is_skip) && target == last + 4 => " [label=\"skip\"]",
+                _ => "",
+            };
+            text += &format!(
+                "    {} -> {}{};\n",
+                node(block.start),
+                node(target),
+                attributes
+            );
+        }
+        if pattern == Some("2NNN") {
+            let target = instruction.opcode & 0x0FFF;
+            if !flow.instructions.contains_key(&target) {
+                outside.insert(target);
+            }
+            text += &format!(
+                "    {} -> {} [style=dashed];\n",
+                node(block.start),
+                node(target)
+            );
+        }
+    }
+
+    // Places the program goes that aren't code in the ROM
+    for address in outside {
+        text += &format!(
+            "    {} [label=\"{:03X}\", shape=octagon];\n",
+            node(address),
+            address
+        );
+    }
+    text += "}\n";
+    text
+}
+
+fn is_skip(pattern: &str) -> bool {
+    matches!(pattern, "3XNN" | "4XNN" | "5XY0" | "9XY0" | "EX9E" | "EXA1")
+}
+
+// Runs the CPU with `keys` down in each frame, noting where BNNN jumps go.
+// A run that stops on an error still returns what it saw, with the error.
+pub fn observe_jumps(cpu: &mut Chip8, keys: &[u16]) -> (Jumps, Option<String>) {
+    let mut jumps = Jumps::new();
+    for (frame, &keys) in keys.iter().enumerate() {
+        let instructions = cpu.begin_frame(keys);
+        for _ in 0..instructions {
+            let pc = cpu.pc();
+            let memory = cpu.memory();
+            let opcode = ((memory[pc as usize] as u16) << 8) | (memory[pc as usize + 1] as u16);
+            if let Err(err) = cpu.run_instruction() {
+                return (jumps, Some(format!("frame {}: {}", frame, err)));
+            }
+            if disasm::pattern(opcode) == Some("BNNN") {
+                jumps.entry(pc).or_default().insert(cpu.pc());
+            }
+        }
+        cpu.end_frame();
+    }
+    (jumps, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random;
+    use crate::rom::Rom;
+    use crate::settings::Settings;
+
+    // Calls 214 once, then jumps with BNNN to 20C and later 210 as V0 goes
+    // from 0 to 4
+    const ROM: [u8; 0x16] = [
+        0x60, 0x00, // 200: LD V0, 0
+        0x30, 0x04, // 202: SE V0, 4
+        0x22, 0x14, // 204: CALL 214
+        0xB2, 0x0C, // 206: JP V0, 20C
+        0x00, 0x00, 0x00, 0x00, // 208: data
+        0x70, 0x04, // 20C: ADD V0, 4
+        0x61, 0x01, // 20E: LD V1, 1
+        0x12, 0x02, // 210: JP 202
+        0x00, 0x00, // 212: data
+        0x00, 0xEE, // 214: RET
+    ];
+
+    fn memory(rom: &[u8]) -> Vec<u8> {
+        let mut memory = vec![0; 4096];
+        memory[PROGRAM_START..][..rom.len()].copy_from_slice(rom);
+        memory
+    }
+
+    fn observed() -> Jumps {
+        let rom = Rom::from_bytes(ROM.to_vec(), ROM.len()).unwrap();
+        let mut config = Settings::defaults().to_config().unwrap();
+        config.mute = true;
+        let mut cpu = Chip8::new(&rom, config, random::new(0));
+        let (jumps, err) = observe_jumps(&mut cpu, &[0; 3]);
+        assert_eq!(err, None);
+        jumps
+    }
+
+    #[test]
+    fn observes_every_bnnn_target() {
+        assert_eq!(
+            observed(),
+            Jumps::from([(0x206, BTreeSet::from([0x20C, 0x210]))])
+        );
+    }
+
+    #[test]
+    fn splits_blocks_at_skips_jumps_calls_and_returns() {
+        let flow = Flow::build(&memory(&ROM), ROM.len(), &observed());
+        let split: Vec<Vec<u16>> = blocks(&flow).into_iter().map(|b| b.instructions).collect();
+        assert_eq!(
+            split,
+            [
+                vec![0x200],
+                vec![0x202],
+                vec![0x204],
+                vec![0x206],
+                vec![0x20C, 0x20E],
+                vec![0x210],
+                vec![0x214],
+            ]
+        );
+
+        // Without a run, BNNN leads nowhere and nothing jumps back to 202
+        let flow = Flow::build(&memory(&ROM), ROM.len(), &Jumps::new());
+        let split: Vec<Vec<u16>> = blocks(&flow).into_iter().map(|b| b.instructions).collect();
+        assert_eq!(
+            split,
+            [vec![0x200, 0x202], vec![0x204], vec![0x206], vec![0x214]]
+        );
+    }
+
+    #[test]
+    fn draws_edges() {
+        let flow = Flow::build(&memory(&ROM), ROM.len(), &observed());
+        let expected = r#"digraph "test \"rom\"" {
+    node [shape=box, fontname="monospace"];
+    b200 [label="main\l200  6000  LD V0, 00\l"];
+    b200 -> b202;
+    b202 [label="202  3004  SE V0, 04\l"];
+    b202 -> b204;
+    b202 -> b206 [label="skip"];
+    b204 [label="204  2214  CALL 214\l"];
+    b204 -> b206;
+    b204 -> b214 [style=dashed];
+    b206 [label="206  B20C  JP V0, 20C\l"];
+    b206 -> b20C [style=dotted];
+    b206 -> b210 [style=dotted];
+    b20C [label="20C  7004  ADD V0, 04\l20E  6101  LD V1, 01\l"];
+    b20C -> b210;
+    b210 [label="210  1202  JP 202\l"];
+    b210 -> b202;
+    b214 [label="sub_214\l214  00EE  RET\l"];
+}
+"#;
+        assert_eq!(
+            dot(&flow, "test \"rom\"", false, &Symbols::default()),
+            expected
+        );
+    }
+
+    #[test]
+    fn marks_targets_outside_the_rom() {
+        // CALL 300; JP 202
+        let rom = [0x23, 0x00, 0x12, 0x02];
+        let flow = Flow::build(&memory(&rom), rom.len(), &Jumps::new());
+        let text = dot(&flow, "rom", false, &Symbols::default());
+        assert!(text.contains("    b200 -> b300 [style=dashed];\n"));
+        assert!(text.contains("    b202 -> b202;\n"));
+        assert!(text.contains("    b300 [label=\"300\", shape=octagon];\n"));
+    }
+}
@@ -1,284 +1,273 @@
 use std::{
-    io::{self, Stdout, Write},
-    time::Duration,
+    io::{self, Stdout},
+    time::{Duration, Instant},
 };
 
 use crossterm::{
-    ExecutableCommand, QueueableCommand, cursor,
-    event::{Event, KeyCode, KeyEventKind, poll, read},
-    style::{self, Stylize},
+    event::{Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, poll, read},
+    style::Color,
     terminal,
 };
 
-struct Display {
-    buffer: [[u8; 8]; 32],
+use crate::keymap::{Hotkey, Hotkeys, Keymap, SCREENSHOT_KEY};
+use crate::render::{self, CellGrid, Image, Presenter, RenderMode};
+use crate::terminal as term;
+
+// The terminal side of the interpreter: reads the keyboard and draws pictures
+pub struct Hardware {
+    stdout: Stdout,
+    keymap: Keymap,
+    hotkeys: Hotkeys,
+    render_mode: RenderMode,
+    background: Color,
+    presenter: Presenter,
+    // Whether the terminal was resized or the status changed since the last
+    // present
+    redraw: bool,
+    // When each CHIP-8 key was last pressed
+    pressed_at: [Option<Instant>; 16],
+    // Keys pressed since the keypad was last read, so quick taps aren't missed
+    pressed_since: u16,
+    // Set by the screenshot hotkey until the main loop takes it
+    screenshot_requested: bool,
+    // Hotkeys pressed since the main loop last took them
+    hotkeys_pressed: Vec<Hotkey>,
+    // When the fast forward hotkey was last pressed
+    fast_forward_at: Option<Instant>,
+    // A line shown over the bottom of the picture, and when it was shown
+    status: Option<(String, Instant)>,
+    // Presses of keys that aren't on the keypad, kept for take_keys once
+    // collect_keys was called
+    collected: Option<Vec<KeyEvent>>,
+    // Pressing this starts typing, which sends every press to take_keys
+    typing_key: Option<KeyCode>,
+    typing: bool,
 }
 
-impl Display {
-    pub fn new() -> Self {
+// Most terminals only report presses, repeating them while a key is held
+// after a delay of about half a second, so without releases a key counts as
+// down for this long
+const KEY_HOLD: Duration = Duration::from_millis(500);
+// Fast forward is held rather than tapped, so it waits out the slowest
+// repeat delays instead of dropping out before the first repeat
+const FAST_FORWARD_HOLD: Duration = Duration::from_millis(700);
+// How long a status line stays up
+const STATUS_TIME: Duration = Duration::from_secs(2);
+
+// Whether a key last pressed at `at` is still down. Where the terminal
+// reports releases, they clear `at`; otherwise it's a guess from `hold`.
+fn held(at: Option<Instant>, hold: Duration) -> bool {
+    at.is_some_and(|at| term::reports_releases() || at.elapsed() < hold)
+}
+
+impl Hardware {
+    pub fn new(
+        keymap: Keymap,
+        hotkeys: Hotkeys,
+        render_mode: RenderMode,
+        background: Color,
+    ) -> Self {
         Self {
-            buffer: [[0; 8]; 32],
+            stdout: io::stdout(),
+            keymap,
+            hotkeys,
+            render_mode,
+            background,
+            presenter: Presenter::new(),
+            redraw: false,
+            pressed_at: [None; 16],
+            pressed_since: 0,
+            screenshot_requested: false,
+            hotkeys_pressed: Vec::new(),
+            fast_forward_at: None,
+            status: None,
+            collected: None,
+            typing_key: None,
+            typing: false,
         }
     }
 
-    pub fn clear(&mut self) {
-        self.buffer = [[0; 8]; 32]
+    // Starts keeping presses of other keys for take_keys. Typing starts as
+    // soon as `typing_key` is pressed, so the keys after it aren't missed.
+    pub fn collect_keys(&mut self, typing_key: KeyCode) {
+        self.collected.get_or_insert_with(Vec::new);
+        self.typing_key = Some(typing_key);
     }
 
-    pub fn set(&mut self, x: u8, y: u8, pixel: bool) -> bool {
-        let curr = self.get_bit(x as usize, y as usize);
-        self.set_bit(x as usize, y as usize, curr ^ pixel);
-        curr && pixel
+    pub fn take_keys(&mut self) -> Vec<KeyEvent> {
+        self.collected
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
     }
 
-    pub fn get(&self, x: u8, y: u8) -> bool {
-        self.get_bit(x as usize, y as usize)
+    // While typing, keys go to take_keys instead of the keypad
+    pub fn set_typing(&mut self, typing: bool) {
+        self.typing = typing;
     }
 
-    fn get_bit(&self, x: usize, y: usize) -> bool {
-        let byte = self.buffer[y][x / 8];
-        let bit = x % 8;
-        (byte & (1 << bit)) != 0
-    }
+    // The CHIP-8 keys that are down, one bit per key
+    pub fn keypad(&mut self) -> Result<u16, &'static str> {
+        self.read_events()?;
 
-    fn set_bit(&mut self, x: usize, y: usize, value: bool) {
-        let byte = &mut self.buffer[y][x / 8];
-        let bit = x % 8;
-        if value {
-            *byte |= 1 << bit;
-        } else {
-            *byte &= !(1 << bit);
+        let mut keys = std::mem::take(&mut self.pressed_since);
+        for (key, at) in self.pressed_at.iter().enumerate() {
+            if held(*at, KEY_HOLD) {
+                keys |= 1 << key;
+            }
         }
+        Ok(keys)
     }
-}
-
-// TODO: Config for inputs
-pub struct Hardware {
-    stdout: Stdout,
-    display: Display,
-    old_dimensions: (usize, usize),
-}
 
-fn value_to_char(value: u8) -> Result<char, &'static str> {
-    match value {
-        0 => Ok('0'),
-        1 => Ok('1'),
-        2 => Ok('2'),
-        3 => Ok('3'),
-        4 => Ok('4'),
-        5 => Ok('5'),
-        6 => Ok('6'),
-        7 => Ok('7'),
-        8 => Ok('8'),
-        9 => Ok('9'),
-        0xA => Ok('a'),
-        0xB => Ok('b'),
-        0xC => Ok('c'),
-        0xD => Ok('d'),
-        0xE => Ok('e'),
-        0xF => Ok('f'),
-        _ => Err("Invalid value used for character"),
-    }
-}
-
-fn char_to_value(c: char) -> Result<u8, &'static str> {
-    match c {
-        '0' => Ok(0),
-        '1' => Ok(1),
-        '2' => Ok(2),
-        '3' => Ok(3),
-        '4' => Ok(4),
-        '5' => Ok(5),
-        '6' => Ok(6),
-        '7' => Ok(7),
-        '8' => Ok(8),
-        '9' => Ok(9),
-        'a' | 'A' => Ok(0xA),
-        'b' | 'B' => Ok(0xB),
-        'c' | 'C' => Ok(0xC),
-        'd' | 'D' => Ok(0xD),
-        'e' | 'E' => Ok(0xE),
-        'f' | 'F' => Ok(0xF),
-        _ => Err("Invalid character used for value"),
-    }
-}
-
-impl Hardware {
-    pub fn new() -> Self {
-        let mut stdout = io::stdout();
-        let old_dimensions = term_size::dimensions().expect("Unable to get terminal size");
-        stdout.queue(terminal::SetSize(64, 32)).unwrap();
-        stdout
-            .queue(terminal::SetTitle("CHIP-8 Interpreter"))
-            .unwrap();
-        Self {
-            stdout,
-            display: Display::new(),
-            old_dimensions,
+    // Handles every event that is already waiting, without blocking
+    fn read_events(&mut self) -> Result<(), &'static str> {
+        while poll(Duration::ZERO).map_err(|_| "Polling error")? {
+            let event = read().map_err(|_| "Event reading error")?;
+            self.handle_event(event)?;
         }
-    }
 
-    pub fn clear(&mut self) -> Result<(), &'static str> {
-        self.display.clear();
-        self.draw()
+        Ok(())
     }
 
-    pub fn check_key(key: u8) -> Result<bool, &'static str> {
-        let key = match value_to_char(key) {
-            Ok(v) => v,
-            Err(err) => return Err(err),
+    // Resizes redraw on the next present, F12 asks for a screenshot, and
+    // Ctrl-C asks to exit, since raw mode stops it from sending SIGINT
+    fn handle_event(&mut self, event: Event) -> Result<(), &'static str> {
+        let event = match event {
+            Event::Resize(_, _) => {
+                self.redraw = true;
+                return Ok(());
+            }
+            Event::Key(event) => event,
+            _ => return Ok(()),
         };
 
-        match poll(Duration::from_millis(500)) {
-            Ok(available) => {
-                if !available {
-                    return Ok(false);
-                }
-
-                let event = match read() {
-                    Ok(e) => e,
-                    Err(_) => return Err("Event reading error"),
-                };
-
-                match event.as_key_press_event() {
-                    None => return Ok(false),
-                    Some(event) => {
-                        return Ok(event.code.is_char(key));
-                    }
-                }
+        // Only some terminals report releases
+        if event.kind == KeyEventKind::Release {
+            if let Some(key) = self.keymap.key_for(event.code) {
+                self.pressed_at[key as usize] = None;
+            }
+            if self.hotkeys.hotkey_for(event.code) == Some(Hotkey::FastForward) {
+                self.fast_forward_at = None;
             }
-            Err(_) => return Err("Polling error"),
+            return Ok(());
         }
-    }
 
-    pub fn get_key() -> Result<u8, &'static str> {
-        match Self::read_until() {
-            Ok(c) => match char_to_value(c) {
-                Ok(v) => return Ok(v),
-                Err(err) => return Err(err),
-            },
-            Err(err) => return Err(err),
+        if self.typing_key == Some(event.code) {
+            self.typing = true;
         }
+        if event.code == SCREENSHOT_KEY {
+            self.screenshot_requested = true;
+        } else if event.code == KeyCode::Char('c')
+            && event.modifiers.contains(KeyModifiers::CONTROL)
+        {
+            term::request_exit();
+            return Err("Interrupted");
+        } else if let Some(key) = self.keymap.key_for(event.code).filter(|_| !self.typing) {
+            self.pressed_at[key as usize] = Some(Instant::now());
+            self.pressed_since |= 1 << key;
+        } else if let Some(collected) = &mut self.collected {
+            collected.push(event);
+        } else if let Some(hotkey) = self.hotkeys.hotkey_for(event.code) {
+            match hotkey {
+                Hotkey::FastForward => self.fast_forward_at = Some(Instant::now()),
+                _ => self.hotkeys_pressed.push(hotkey),
+            }
+        }
+
+        Ok(())
     }
 
-    fn read_until() -> Result<char, &'static str> {
-        loop {
-            match read() {
-                Ok(e) => {
-                    if let Event::Key(key_event) = e {
-                        if key_event.kind == KeyEventKind::Press {
-                            if key_event.code == KeyCode::Char('0')
-                                || key_event.code == KeyCode::Char('1')
-                                || key_event.code == KeyCode::Char('2')
-                                || key_event.code == KeyCode::Char('3')
-                                || key_event.code == KeyCode::Char('4')
-                                || key_event.code == KeyCode::Char('5')
-                                || key_event.code == KeyCode::Char('6')
-                                || key_event.code == KeyCode::Char('7')
-                                || key_event.code == KeyCode::Char('8')
-                                || key_event.code == KeyCode::Char('9')
-                                || key_event.code == KeyCode::Char('a')
-                                || key_event.code == KeyCode::Char('b')
-                                || key_event.code == KeyCode::Char('c')
-                                || key_event.code == KeyCode::Char('d')
-                                || key_event.code == KeyCode::Char('e')
-                                || key_event.code == KeyCode::Char('f')
-                            {
-                                match key_event.code.as_char() {
-                                    None => return Err("Event reading error"),
-                                    Some(ch) => return Ok(ch),
-                                }
-                            }
-                        } else {
-                            continue;
-                        }
-                    }
-                }
-                Err(_) => return Err("Event reading error"),
-            };
-        }
+    pub fn take_screenshot_request(&mut self) -> bool {
+        std::mem::take(&mut self.screenshot_requested)
     }
 
-    pub fn display_row(&mut self, byte: u8, x: u8, y: u8) -> Result<bool, &'static str> {
-        let pixels = Self::extract_pixels(byte);
-        let mut collision = false;
+    pub fn take_hotkeys(&mut self) -> Vec<Hotkey> {
+        std::mem::take(&mut self.hotkeys_pressed)
+    }
 
-        if y > 31 {
-            return Ok(false);
-        }
+    // Whether the fast forward hotkey is held down
+    pub fn fast_forward(&self) -> bool {
+        held(self.fast_forward_at, FAST_FORWARD_HOLD)
+    }
 
-        for i in 0..8 {
-            if x + i > 63 {
-                break;
-            }
-            if self.display.set(x + i, y, pixels[i as usize]) {
-                collision = true;
-            }
+    // Shows `text` over the bottom of the picture for a moment. Showing the
+    // same text again keeps it up.
+    pub fn show_status(&mut self, text: &str) {
+        if self.status.as_ref().is_none_or(|(shown, _)| shown != text) {
+            self.redraw = true;
         }
+        self.status = Some((text.to_string(), Instant::now()));
+    }
 
-        Ok(collision)
+    pub fn has_status(&self) -> bool {
+        self.status
+            .as_ref()
+            .is_some_and(|(_, at)| at.elapsed() < STATUS_TIME)
     }
 
-    pub fn draw(&mut self) -> Result<(), &'static str> {
-        match self
-            .stdout
-            .execute(terminal::Clear(terminal::ClearType::All))
-        {
-            Ok(_) => {}
-            Err(_) => {
-                return Err("Clear display error");
-            }
+    // Called once per frame, and only redraws when the picture changed, the
+    // terminal was resized or the status changed
+    pub fn present(&mut self, image: &Image, changed: bool) -> Result<(), &'static str> {
+        if self.status.is_some() && !self.has_status() {
+            self.status = None;
+            self.redraw = true;
+        }
+        if !changed && !std::mem::take(&mut self.redraw) {
+            return Ok(());
         }
 
-        for y in 0..32 {
-            for x in 0..64 {
-                if self.display.get(x, y) {
-                    let _ = self
-                        .stdout
-                        .queue(cursor::MoveTo(x as u16, y as u16))
-                        .unwrap()
-                        .queue(style::PrintStyledContent("█".with(style::Color::Yellow)));
-                } else {
-                    let _ = self
-                        .stdout
-                        .queue(cursor::MoveTo(x as u16, y as u16))
-                        .unwrap()
-                        .queue(style::PrintStyledContent(
-                            "█".with(style::Color::DarkYellow),
-                        ));
+        let terminal_size = self.terminal_size();
+        let result = match render::fit(
+            self.render_mode,
+            terminal_size,
+            image.width(),
+            image.height(),
+        ) {
+            Ok((mode, scale)) => {
+                let picture = render::render(&image.scaled(scale), mode, self.background);
+                let (width, height) = (terminal_size.0 as usize, terminal_size.1 as usize);
+                let mut grid = CellGrid::new(width, height);
+                grid.paste(
+                    (width - picture.width()) / 2,
+                    (height - picture.height()) / 2,
+                    &picture,
+                );
+                if let Some((status, _)) = &self.status {
+                    let line = format!(" {} ", status);
+                    grid.print(0, height - 1, &line, Color::Black, Color::Grey);
                 }
+                self.presenter
+                    .present(&mut self.stdout, grid, terminal_size)
             }
-        }
+            Err((cols, rows)) => {
+                let message = format!("Terminal too small, need {}x{}", cols, rows);
+                self.presenter
+                    .notice(&mut self.stdout, &message, terminal_size)
+            }
+        };
 
-        match self.stdout.flush() {
+        match result {
             Ok(()) => Ok(()),
-            Err(_) => Err("Stdout flush error"),
+            Err(_) => Err("Terminal write error"),
         }
     }
 
-    fn extract_pixels(byte: u8) -> [bool; 8] {
-        let mut pixels = [false; 8];
-
-        for i in 0..8 {
-            pixels[i] = ((byte >> (7 - i)) & 1) == 1;
-        }
+    pub fn terminal_size(&self) -> (u16, u16) {
+        terminal::size().unwrap_or((64, 32))
+    }
 
-        pixels
+    // Draws a grid made for the whole terminal, redrawing only what changed
+    pub fn present_cells(&mut self, grid: CellGrid) -> Result<(), &'static str> {
+        let terminal_size = self.terminal_size();
+        self.presenter
+            .present(&mut self.stdout, grid, terminal_size)
+            .map_err(|_| "Terminal write error")
     }
 
-    pub fn clean_up(&mut self) -> Result<(), &'static str> {
-        // Clears, and resizes terminal
-        match self.stdout.execute(terminal::ScrollDown(32)) {
-            Ok(_stdout) => {
-                match self.stdout.execute(terminal::SetSize(
-                    self.old_dimensions.0 as u16,
-                    self.old_dimensions.1 as u16,
-                )) {
-                    Ok(_) => Ok(()),
-                    Err(_err) => Err("Failed to restore terminal size"),
-                }
-            }
-            Err(_err) => Err("Failed to scroll terminal"),
-        }
+    pub fn notice(&mut self, message: &str) -> Result<(), &'static str> {
+        let terminal_size = self.terminal_size();
+        self.presenter
+            .notice(&mut self.stdout, message, terminal_size)
+            .map_err(|_| "Terminal write error")
     }
 }
@@ -0,0 +1,110 @@
+use crossterm::event::KeyCode;
+
+// Maps keyboard keys to the 16 CHIP-8 keys
+#[derive(Clone)]
+pub struct Keymap {
+    bindings: Vec<(KeyCode, u8)>,
+}
+
+impl Keymap {
+    // Each CHIP-8 key is pressed with the matching hex digit
+    pub fn hex() -> Self {
+        let bindings = (0..16u8)
+            .map(|key| {
+                let c = char::from_digit(key as u32, 16).unwrap();
+                (KeyCode::Char(c), key)
+            })
+            .collect();
+
+        Self { bindings }
+    }
+
+    // Replaces any binding already using `code`
+    pub fn bind(&mut self, code: KeyCode, key: u8) {
+        self.bindings.retain(|(c, _)| *c != code);
+        self.bindings.push((code, key & 0xF));
+    }
+
+    pub fn key_for(&self, code: KeyCode) -> Option<u8> {
+        self.bindings
+            .iter()
+            .find(|(c, _)| *c == code)
+            .map(|(_, key)| *key)
+    }
+}
+
+// Saves a screenshot. It's handled before any other binding, so it can't
+// be bound to anything else.
+pub const SCREENSHOT_KEY: KeyCode = KeyCode::F(12);
+
+// Keys that control the interpreter rather than the program
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Hotkey {
+    Pause,
+    Reset,
+    Faster,
+    Slower,
+    Mute,
+    // Runs several frames for each one shown while held
+    FastForward,
+    Quit,
+}
+
+// Maps keyboard keys to hotkeys
+#[derive(Clone, Default)]
+pub struct Hotkeys {
+    bindings: Vec<(KeyCode, Hotkey)>,
+}
+
+impl Hotkeys {
+    pub fn bind(&mut self, code: KeyCode, hotkey: Hotkey) {
+        self.bindings.push((code, hotkey));
+    }
+
+    pub fn hotkey_for(&self, code: KeyCode) -> Option<Hotkey> {
+        self.bindings
+            .iter()
+            .find(|(c, _)| *c == code)
+            .map(|(_, hotkey)| *hotkey)
+    }
+}
+
+// Names used for keys in config files
+pub fn parse_key_name(name: &str) -> Option<KeyCode> {
+    let code = match name.to_lowercase().as_str() {
+        "up" => KeyCode::Up,
+        "down" => KeyCode::Down,
+        "left" => KeyCode::Left,
+        "right" => KeyCode::Right,
+        "space" => KeyCode::Char(' '),
+        "enter" => KeyCode::Enter,
+        "tab" => KeyCode::Tab,
+        "backspace" => KeyCode::Backspace,
+        "esc" => KeyCode::Esc,
+        "pageup" => KeyCode::PageUp,
+        "pagedown" => KeyCode::PageDown,
+        "home" => KeyCode::Home,
+        "end" => KeyCode::End,
+        "insert" => KeyCode::Insert,
+        "delete" => KeyCode::Delete,
+        name if name.len() > 1 && name.starts_with('f') => match name[1..].parse() {
+            Ok(n @ 1..=12) => KeyCode::F(n),
+            _ => return None,
+        },
+        _ => {
+            let mut chars = name.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => KeyCode::Char(c.to_ascii_lowercase()),
+                _ => return None,
+            }
+        }
+    };
+
+    Some(code)
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Self::hex()
+    }
+}
@@ -0,0 +1,497 @@
+use std::collections::BTreeSet;
+
+use crate::chip8::PROGRAM_START;
+use crate::disasm;
+use crate::flow::{Flow, Jumps};
+use crate::platform::Platform;
+use crate::symbols::Symbols;
+
+// How many addresses a grouped note lists before "and N more"
+const LISTED: usize = 6;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Note,
+}
+
+pub struct Finding {
+    pub severity: Severity,
+    pub address: u16,
+    pub message: String,
+}
+
+// Looks for likely mistakes in the program loaded in `memory`, without
+// running it. `platform_name` is how `platform` is shown in messages.
+pub fn lint(
+    memory: &[u8],
+    rom_len: usize,
+    platform: Platform,
+    platform_name: &str,
+    bxnn: bool,
+    symbols: &Symbols,
+) -> Vec<Finding> {
+    let flow = Flow::build(memory, rom_len, &Jumps::new());
+    let mut findings = Vec::new();
+    let mut warn = |address: u16, message: String| {
+        findings.push(Finding {
+            severity: Severity::Warning,
+            address,
+            message,
+        })
+    };
+    let show = |opcode: u16| {
+        symbols
+            .mnemonic(opcode, bxnn)
+            .unwrap_or_else(|| format!("{:04X}", opcode))
+    };
+
+    let mut bad_targets: Vec<(u16, String)> = flow
+        .bad_targets
+        .iter()
+        .map(|&(pc, target)| {
+            let instruction = show(flow.instructions[&pc].opcode);
+            let message = if (target as usize) < PROGRAM_START {
+                format!("{} goes into the interpreter and font area", instruction)
+            } else if target == pc + 2 || target == pc + 4 {
+                format!("{} can run past the end of the ROM", instruction)
+            } else {
+                format!("{} goes past the end of the ROM", instruction)
+            };
+            (pc, message)
+        })
+        .collect();
+    // A skip at the end of the ROM has both of its targets past it
+    bad_targets.dedup();
+    for (pc, message) in bad_targets {
+        warn(pc, message);
+    }
+
+    for (&pc, instruction) in &flow.instructions {
+        let opcode = instruction.opcode;
+        let target = opcode & 0x0FFF;
+
+        if matches!(disasm::pattern(opcode), Some("1NNN" | "2NNN")) && target % 2 == 1 {
+            let message = if flow.instructions.contains_key(&(target - 1)) {
+                format!(
+                    "{} goes into the middle of the instruction at {:03X}",
+                    show(opcode),
+                    target - 1
+                )
+            } else {
+                format!("{} goes to an odd address", show(opcode))
+            };
+            warn(pc, message);
+        }
+
+        match disasm::extension(opcode) {
+            Some(extension) if !platform.has(extension) => warn(
+                pc,
+                format!(
+                    "{:04X} is a {}, which {} doesn't have",
+                    opcode,
+                    extension.name(),
+                    platform_name
+                ),
+            ),
+            Some(extension) => warn(
+                pc,
+                format!(
+                    "{:04X} is a {}, which this interpreter doesn't run",
+                    opcode,
+                    extension.name()
+                ),
+            ),
+            None if disasm::pattern(opcode).is_none() => {
+                warn(pc, format!("{:04X} is not an instruction", opcode))
+            }
+            None => {}
+        }
+        if disasm::pattern(opcode) == Some("DXYN") && opcode & 0xF == 0 {
+            warn(
+                pc,
+                format!(
+                    "{} draws nothing; 16x16 sprites are SUPER-CHIP only",
+                    show(opcode)
+                ),
+            );
+        }
+    }
+
+    // Returns that can run in the program's main flow, outside any routine
+    for pc in reachable(&flow, PROGRAM_START as u16) {
+        if flow.instructions[&pc].opcode == 0x00EE {
+            warn(
+                pc,
+                "RET can run without a call, emptying the stack".to_string(),
+            );
+        }
+    }
+    for &routine in &flow.routines {
+        let body = reachable(&flow, routine);
+        let returns = body.iter().any(|pc| flow.instructions[pc].opcode == 0x00EE);
+        let indirect = body.iter().any(|pc| flow.indirect.contains(pc));
+        if !body.is_empty() && !returns && !indirect {
+            warn(
+                routine,
+                format!(
+                    "{} never returns, so each call leaves an address on the stack",
+                    symbols.routine_name(routine)
+                ),
+            );
+        }
+    }
+
+    findings.extend(unreachable(&flow, memory, rom_len));
+    findings.extend(quirk_notes(&flow));
+
+    findings.sort_by_key(|finding| (finding.severity, finding.address));
+    findings
+}
+
+// Instructions reachable from `start` without following calls
+fn reachable(flow: &Flow, start: u16) -> BTreeSet<u16> {
+    let mut seen = BTreeSet::new();
+    let mut pending = vec![start];
+    while let Some(pc) = pending.pop() {
+        if let Some(instruction) = flow.instructions.get(&pc)
+            && seen.insert(pc)
+        {
+            pending.extend(&instruction.next);
+        }
+    }
+    seen
+}
+
+// Stretches of the ROM that nothing reaches but that look like code. Bytes
+// from an address loaded into I on are taken to be data.
+fn unreachable(flow: &Flow, memory: &[u8], rom_len: usize) -> Vec<Finding> {
+    let end = (PROGRAM_START + rom_len) as u16;
+    let mut findings = Vec::new();
+
+    let mut address = PROGRAM_START as u16;
+    while address < end {
+        if flow.is_code(address) {
+            address += 1;
+            continue;
+        }
+
+        let start = address;
+        while address < end && !flow.is_code(address) && !flow.data.contains(&address) {
+            address += 1;
+        }
+        let stop = address;
+        // Skip over the data, up to the next code
+        while address < end && !flow.is_code(address) {
+            address += 1;
+        }
+
+        let mut words: Vec<u16> = (start..stop.saturating_sub(1))
+            .step_by(2)
+            .map(|a| ((memory[a as usize] as u16) << 8) | memory[a as usize + 1] as u16)
+            .collect();
+        // Padding after code isn't counted
+        while words.last() == Some(&0) {
+            words.pop();
+        }
+        let instructions = words
+            .iter()
+            .filter(|&&word| word != 0 && disasm::pattern(word).is_some())
+            .count();
+        if instructions == 0 || instructions * 2 < words.len() {
+            continue;
+        }
+
+        let (severity, reason) = match flow.indirect.first() {
+            Some(bnnn) => (
+                Severity::Note,
+                format!(", unless BNNN at {:03X} jumps there", bnnn),
+            ),
+            None => (Severity::Warning, String::new()),
+        };
+        findings.push(Finding {
+            severity,
+            address: start,
+            message: format!(
+                "{} bytes that look like code are never reached{}",
+                words.len() * 2,
+                reason
+            ),
+        });
+    }
+    findings
+}
+
+// Instructions that do different things depending on quirk settings,
+// grouped by quirk
+fn quirk_notes(flow: &Flow) -> Vec<Finding> {
+    let quirks: [(&[&str], &str); 4] = [
+        (
+            &["8XY6", "8XYE"],
+            "shift VY into VX, or VX in place with --shift-in-place-8xy",
+        ),
+        (&["BNNN"], "jump to NNN + V0, or XNN + VX with --bnnn=false"),
+        (
+            &["FX55", "FX65"],
+            "leave I alone, or add X + 1 to it with --increment-i-on-mem",
+        ),
+        (
+            &["FX1E"],
+            "only set VF on overflow with --flag-fx1e-overflow",
+        ),
+    ];
+
+    let mut findings = Vec::new();
+    for (patterns, effect) in quirks {
+        let addresses: Vec<u16> = flow
+            .instructions
+            .iter()
+            .filter(|(_, instruction)| {
+                let opcode = instruction.opcode;
+                let x = (opcode & 0x0F00) >> 8;
+                let y = (opcode & 0x00F0) >> 4;
+                match disasm::pattern(opcode) {
+                    Some(pattern) if !patterns.contains(&pattern) => false,
+                    // Only differ when X and Y are different registers
+                    Some("8XY6" | "8XYE") => x != y,
+                    // Only differs when X isn't 0
+                    Some("BNNN") => x != 0,
+                    Some(_) => true,
+                    None => false,
+                }
+            })
+            .map(|(&pc, _)| pc)
+            .collect();
+        let Some(&first) = addresses.first() else {
+            continue;
+        };
+
+        let mut listed: Vec<String> = addresses
+            .iter()
+            .take(LISTED)
+            .map(|a| format!("{:03X}", a))
+            .collect();
+        if addresses.len() > LISTED {
+            listed.push(format!("and {} more", addresses.len() - LISTED));
+        }
+        findings.push(Finding {
+            severity: Severity::Note,
+            address: first,
+            message: format!("{} at {} {}", patterns.join("/"), listed.join(", "), effect),
+        });
+    }
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn findings(rom: &[u8], platform: Platform) -> Vec<(&'static str, u16, String)> {
+        let mut memory = vec![0; 4096];
+        memory[PROGRAM_START..][..rom.len()].copy_from_slice(rom);
+        lint(
+            &memory,
+            rom.len(),
+            platform,
+            platform.id(),
+            false,
+            &Symbols::default(),
+        )
+        .into_iter()
+        .map(|f| {
+            let severity = match f.severity {
+                Severity::Warning => "warning",
+                Severity::Note => "note",
+            };
+            (severity, f.address, f.message)
+        })
+        .collect()
+    }
+
+    fn warning(address: u16, message: &str) -> (&'static str, u16, String) {
+        ("warning", address, message.to_string())
+    }
+
+    fn note(address: u16, message: &str) -> (&'static str, u16, String) {
+        ("note", address, message.to_string())
+    }
+
+    fn vip(rom: &[u8]) -> Vec<(&'static str, u16, String)> {
+        findings(rom, Platform::OriginalChip8)
+    }
+
+    #[test]
+    fn clean_rom_has_no_findings() {
+        // LD V0, 1; CALL 206; JP 204; ADD V0, 1; RET
+        let rom = [0x60, 0x01, 0x22, 0x06, 0x12, 0x04, 0x70, 0x01, 0x00, 0xEE];
+        assert_eq!(vip(&rom), []);
+    }
+
+    #[test]
+    fn jumps_out_of_the_rom() {
+        // SE V0, 0; JP 100; JP 300
+        let rom = [0x30, 0x00, 0x11, 0x00, 0x13, 0x00];
+        assert_eq!(
+            vip(&rom),
+            [
+                warning(0x202, "JP 100 goes into the interpreter and font area"),
+                warning(0x204, "JP 300 goes past the end of the ROM"),
+            ]
+        );
+
+        // JP 202; SNE V0, 0
+        let rom = [0x12, 0x02, 0x40, 0x00];
+        assert_eq!(
+            vip(&rom),
+            [warning(0x202, "SNE V0, 00 can run past the end of the ROM")]
+        );
+    }
+
+    #[test]
+    fn jumps_to_odd_addresses() {
+        let mut rom = [0; 0x1A];
+        // JP 202; LD V0, 12; JP 203, which runs 1212 from the middle of 202
+        rom[..6].copy_from_slice(&[0x12, 0x02, 0x60, 0x12, 0x12, 0x03]);
+        // JP 217, which runs JP 212 from the middle of two words
+        rom[0x12..].copy_from_slice(&[0x12, 0x17, 0x00, 0x00, 0x00, 0x12, 0x12, 0x00]);
+        assert_eq!(
+            vip(&rom),
+            [
+                warning(
+                    0x204,
+                    "JP 203 goes into the middle of the instruction at 202"
+                ),
+                warning(0x212, "JP 217 goes to an odd address"),
+            ]
+        );
+    }
+
+    #[test]
+    fn instructions_from_other_platforms() {
+        // 00FF (hires); SYS 123; DRW V0, V1, 0; FFFF
+        let rom = [0x00, 0xFF, 0x01, 0x23, 0xD0, 0x10, 0xFF, 0xFF];
+        let sprite = "DRW V0, V1, 0 draws nothing; 16x16 sprites are SUPER-CHIP only";
+        assert_eq!(
+            vip(&rom),
+            [
+                warning(
+                    0x200,
+                    "00FF is a SUPER-CHIP instruction, which originalChip8 doesn't have"
+                ),
+                warning(
+                    0x202,
+                    "0123 is a machine code call, which this interpreter doesn't run"
+                ),
+                warning(0x204, sprite),
+                warning(0x206, "FFFF is not an instruction"),
+            ]
+        );
+        assert_eq!(
+            findings(&rom, Platform::Superchip),
+            [
+                warning(
+                    0x200,
+                    "00FF is a SUPER-CHIP instruction, which this interpreter doesn't run"
+                ),
+                warning(
+                    0x202,
+                    "0123 is a machine code call, which superchip doesn't have"
+                ),
+                warning(0x204, sprite),
+                warning(0x206, "FFFF is not an instruction"),
+            ]
+        );
+
+        // F000 NNNN, loaded as JP 200
+        let rom = [0xF0, 0x00, 0x12, 0x00];
+        assert_eq!(
+            vip(&rom),
+            [warning(
+                0x200,
+                "F000 is a XO-CHIP instruction, which originalChip8 doesn't have"
+            )]
+        );
+    }
+
+    #[test]
+    fn returns_without_calls() {
+        assert_eq!(
+            vip(&[0x00, 0xEE]),
+            [warning(
+                0x200,
+                "RET can run without a call, emptying the stack"
+            )]
+        );
+
+        // CALL 204; JP 202; JP 204
+        let rom = [0x22, 0x04, 0x12, 0x02, 0x12, 0x04];
+        assert_eq!(
+            vip(&rom),
+            [warning(
+                0x204,
+                "sub_204 never returns, so each call leaves an address on the stack"
+            )]
+        );
+    }
+
+    #[test]
+    fn unreachable_code() {
+        // JP 200; LD V0, 1; ADD V0, 1; LD V0, V1; padding
+        let rom = [0x12, 0x00, 0x60, 0x01, 0x70, 0x01, 0x80, 0x10, 0, 0];
+        assert_eq!(
+            vip(&rom),
+            [warning(
+                0x202,
+                "6 bytes that look like code are never reached"
+            )]
+        );
+
+        // Loading I with 204 makes what follows data
+        let rom = [0xA2, 0x04, 0x12, 0x02, 0x60, 0x01, 0x70, 0x01];
+        assert_eq!(vip(&rom), []);
+
+        // BNNN could go anywhere, so it's only a note
+        let rom = [0xB2, 0x00, 0x60, 0x01, 0x70, 0x01, 0x80, 0x10];
+        let bnnn = "BNNN at 200 jump to NNN + V0, or XNN + VX with --bnnn=false";
+        assert_eq!(
+            vip(&rom),
+            [
+                note(0x200, bnnn),
+                note(
+                    0x202,
+                    "6 bytes that look like code are never reached, unless BNNN at 200 jumps there"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn quirky_instructions_are_grouped() {
+        let mut rom = vec![0x80, 0x06]; // shifts in place either way
+        for _ in 0..7 {
+            rom.extend([0x81, 0x26]);
+        }
+        rom.extend([0xF1, 0x55, 0xF1, 0x1E, 0xB2, 0x00]);
+        let shifts = "8XY6/8XYE at 202, 204, 206, 208, 20A, 20C, and 1 more \
+                      shift VY into VX, or VX in place with --shift-in-place-8xy";
+        assert_eq!(
+            vip(&rom),
+            [
+                note(0x202, shifts),
+                note(
+                    0x210,
+                    "FX55/FX65 at 210 leave I alone, or add X + 1 to it with --increment-i-on-mem"
+                ),
+                note(
+                    0x212,
+                    "FX1E at 212 only set VF on overflow with --flag-fx1e-overflow"
+                ),
+                note(
+                    0x214,
+                    "BNNN at 214 jump to NNN + V0, or XNN + VX with --bnnn=false"
+                ),
+            ]
+        );
+    }
+}
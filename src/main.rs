@@ -1,104 +1,1150 @@
 use std::{
-    fs::File,
-    io::Read,
-    path::PathBuf,
-    sync::atomic::AtomicBool,
-    time::{Duration, Instant},
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use clap::{Parser, command};
+use clap::{Parser, Subcommand, builder::PossibleValuesParser};
 
-use crate::{chip8::Chip8, config::Config};
+use crate::{
+    chip8::{Chip8, MEMORY_SIZE, PROGRAM_START},
+    config::Config,
+    coverage::Coverage,
+    database::{Database, Match, sha1_hex},
+    diff::{Outcome, Timing},
+    filter::{DisplayFilter, FilterMode, Picture},
+    flow::Flow,
+    hardware::Hardware,
+    keymap::Hotkey,
+    lint::Severity,
+    movie::Movie,
+    overlap::Overlaps,
+    palette::BUILTIN_PALETTES,
+    platform::Platform,
+    profile::Profiler,
+    record::Recorder,
+    render::RenderMode,
+    rom::Rom,
+    settings::{AudioSettings, ColorSettings, DisplaySettings, QuirkSettings, Settings},
+    symbols::Symbols,
+    terminal::TerminalGuard,
+    trace::{Filter, Reader, Tracer},
+};
 
+mod backtrace;
 mod chip8;
 mod config;
+mod coverage;
+mod database;
+mod debugger;
+mod diff;
+mod disasm;
+mod display;
+mod filter;
+mod flow;
+mod graph;
 mod hardware;
+mod keymap;
+mod lint;
+mod movie;
+mod overlap;
+mod palette;
+mod platform;
+mod profile;
+mod random;
+mod record;
 mod registers;
+mod render;
+mod rom;
+mod screenshot;
+mod settings;
 mod stack;
+mod symbols;
+mod terminal;
 mod timers;
+mod trace;
 
 #[derive(Parser)]
 #[command(name = "CHIP-8 Interpreter")]
 #[command(about = "A CHIP-8 interpreter for the terminal", long_about = None)]
 #[command(next_line_help = true)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    file: PathBuf,
-    #[arg(long, default_value_t = true)]
-    #[arg(help = "Use BNNN behavior instead of BXNN")]
-    bnnn: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[arg(required_unless_present = "print_config")]
+    file: Option<PathBuf>,
+    #[command(flatten)]
+    emulation: EmulationArgs,
+    #[arg(long, conflicts_with = "replay")]
+    #[arg(
+        help = "Seed CXNN's random numbers, so runs with the same input repeat exactly [default: random]"
+    )]
+    seed: Option<u64>,
+    #[arg(short, long, num_args = 0..=1, default_missing_value = "true")]
+    #[arg(
+        value_name = "BOOL",
+        require_equals = true,
+        hide_possible_values = true
+    )]
+    #[arg(help = "Mute sound output")]
+    mute: Option<bool>,
+    #[arg(long, value_parser = PossibleValuesParser::new(BUILTIN_PALETTES.map(|(name, _)| name)))]
+    #[arg(help = "Use a built-in color palette")]
+    palette: Option<String>,
+    #[arg(long, value_name = "COLOR")]
+    #[arg(help = "Color of on pixels: a name, an ANSI-256 index, #rrggbb or rgb(r, g, b)")]
+    fg: Option<String>,
+    #[arg(long, value_name = "COLOR")]
+    #[arg(help = "Color of off pixels")]
+    bg: Option<String>,
+    #[arg(long, value_enum)]
+    #[arg(help = "How pixels are drawn in the terminal [default: auto]")]
+    render: Option<RenderMode>,
+    #[arg(long, value_enum)]
+    #[arg(
+        help = "Reduce flicker by blending, holding or fading pixels over frames [default: none]"
+    )]
+    filter: Option<FilterMode>,
+    #[arg(long, value_name = "N")]
+    #[arg(help = "Number of frames the filter works over [default: 3]")]
+    filter_frames: Option<u32>,
+    #[arg(long, value_name = "N")]
+    #[arg(help = "Scale screenshots and recordings up by N [default: 1]")]
+    screenshot_scale: Option<u32>,
+    #[arg(long, num_args = 2, value_names = ["N", "FILE"])]
+    #[arg(help = "Save a screenshot (.png, .ppm or .pbm) after N frames")]
+    screenshot_at_frame: Option<Vec<String>>,
+    #[arg(long, value_name = "FILE")]
+    #[arg(help = "Record every frame to a .gif, or to numbered .ppm files in a directory")]
+    record: Option<PathBuf>,
+    #[arg(long, value_name = "FILE", conflicts_with = "replay")]
+    #[arg(help = "Record the keypad, seed and settings to a movie file for --replay")]
+    record_input: Option<PathBuf>,
+    #[arg(long, value_name = "FILE")]
+    #[arg(help = "Play back a movie recorded with --record-input and check the final state")]
+    replay: Option<PathBuf>,
+    #[arg(long, default_value_t = false, requires = "replay")]
+    #[arg(help = "Run the replay as fast as possible without the terminal or sound")]
+    headless: bool,
+    #[arg(long, value_name = "FILE")]
+    #[arg(
+        help = "Log every instruction run, with the registers it changed, as text or as binary if FILE ends in .bin"
+    )]
+    trace: Option<PathBuf>,
+    #[arg(long, value_name = "RANGE", requires = "trace")]
+    #[arg(help = "Only trace instructions at these addresses, like 200-2FF or @start-@end")]
+    trace_addresses: Option<String>,
+    #[arg(long, value_name = "LIST", requires = "trace")]
+    #[arg(help = "Only trace these opcodes or opcode groups, like D,8XY4,FX0A")]
+    trace_ops: Option<String>,
+    #[arg(long, value_name = "RANGE", requires = "trace")]
+    #[arg(help = "Only trace instructions in this range of cycles, like 1000-2000")]
+    trace_cycles: Option<String>,
     #[arg(long, default_value_t = false)]
-    #[arg(help = "Skip stack underflow errors (returning subroutines from an empty stack)")]
-    skip_stack_underflow: bool,
+    #[arg(help = "Print the hottest routines, instructions and loops on exit")]
+    profile_report: bool,
+    #[arg(long, value_name = "FILE")]
+    #[arg(
+        help = "Save the instructions run in each call stack as folded stacks for flamegraph tools"
+    )]
+    profile_folded: Option<PathBuf>,
+    #[arg(long, value_name = "FILE")]
+    #[arg(
+        help = "Save a disassembly showing which instructions ran and which bytes were used as data"
+    )]
+    coverage: Option<PathBuf>,
+    #[arg(long, value_name = "FILE", requires = "coverage")]
+    #[arg(help = "Save the coverage as an LCOV tracefile for the --coverage disassembly")]
+    coverage_lcov: Option<PathBuf>,
     #[arg(long, default_value_t = false)]
-    #[arg(help = "Set VF to 1 if I + VX > 0xFFF")]
-    flag_fx1e_overflow: bool,
+    #[arg(help = "Report code that is written over and written bytes that run as code on exit")]
+    check_overlaps: bool,
+    #[arg(long, value_name = "FILE")]
+    #[arg(help = "Label addresses with a symbol file [default: the ROM's .sym file, if any]")]
+    symbols: Option<PathBuf>,
+    #[arg(long, default_value_t = false, conflicts_with_all = ["replay", "record_input"])]
+    #[arg(help = "Run in a debugger showing the registers, stack, disassembly and memory")]
+    tui_debug: bool,
+    #[arg(long, value_enum)]
+    #[arg(help = "Use the quirks and speed of a platform instead of the detected one")]
+    platform: Option<Platform>,
     #[arg(long, default_value_t = false)]
-    #[arg(help = "Ignore Y for 8XY6 and 8XYE shifts")]
-    shift_in_place_8xy: bool,
+    #[arg(help = "Don't look the ROM up in the ROM database")]
+    no_database: bool,
+    #[arg(long, value_name = "FILE")]
+    #[arg(help = "Use this config file instead of the global one")]
+    config: Option<PathBuf>,
     #[arg(long, default_value_t = false)]
+    #[arg(help = "Print the effective settings as TOML and exit")]
+    print_config: bool,
+}
+
+// Options that change how programs run, shared with `trace diff`
+#[derive(clap::Args)]
+struct EmulationArgs {
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    #[arg(
+        value_name = "BOOL",
+        require_equals = true,
+        hide_possible_values = true
+    )]
+    #[arg(help = "Use BNNN behavior instead of BXNN")]
+    bnnn: Option<bool>,
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    #[arg(
+        value_name = "BOOL",
+        require_equals = true,
+        hide_possible_values = true
+    )]
+    #[arg(help = "Skip stack underflow errors (returning subroutines from an empty stack)")]
+    skip_stack_underflow: Option<bool>,
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    #[arg(
+        value_name = "BOOL",
+        require_equals = true,
+        hide_possible_values = true
+    )]
+    #[arg(help = "Set VF to 1 if I + VX > 0xFFF")]
+    flag_fx1e_overflow: Option<bool>,
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    #[arg(
+        value_name = "BOOL",
+        require_equals = true,
+        hide_possible_values = true
+    )]
+    #[arg(help = "Ignore Y for 8XY6 and 8XYE shifts")]
+    shift_in_place_8xy: Option<bool>,
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    #[arg(
+        value_name = "BOOL",
+        require_equals = true,
+        hide_possible_values = true
+    )]
     #[arg(help = "Increment I by X + 1 after FX55 and FX65")]
-    increment_i_on_mem: bool,
-    #[arg(long, default_value_t = false)]
+    increment_i_on_mem: Option<bool>,
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    #[arg(
+        value_name = "BOOL",
+        require_equals = true,
+        hide_possible_values = true
+    )]
     #[arg(help = "Skip invalid opcodes instead of crashing program")]
-    skip_bad_opcodes: bool,
-    #[arg(long, default_value_t = 500)]
-    #[arg(help = "Set the instruction speed in Hz")]
-    cpu_hz: u32,
-    #[arg(short, long, default_value_t = false)]
-    #[arg(help = "Mute sound output")]
-    mute: bool,
+    skip_bad_opcodes: Option<bool>,
+    #[arg(long, value_name = "N")]
+    #[arg(help = "Allow N nested calls, or 0 for no limit [default: 12 on the VIP, otherwise 16]")]
+    stack_depth: Option<u32>,
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    #[arg(
+        value_name = "BOOL",
+        require_equals = true,
+        hide_possible_values = true
+    )]
+    #[arg(help = "Keep the stack in memory below 0xED0, where the COSMAC VIP has it")]
+    stack_in_memory: Option<bool>,
+    #[arg(long)]
+    #[arg(help = "Set the instruction speed in Hz [default: 500, or the ROM's tick rate]")]
+    cpu_hz: Option<u32>,
+}
+
+impl EmulationArgs {
+    fn settings(&self, platform: Option<Platform>) -> Settings {
+        Settings {
+            platform,
+            cpu_hz: self.cpu_hz,
+            quirks: QuirkSettings {
+                bxnn: self.bnnn.map(|bnnn| !bnnn),
+                shift_in_place_8xy: self.shift_in_place_8xy,
+                increment_i_on_mem: self.increment_i_on_mem,
+                fx1e_overflow: self.flag_fx1e_overflow,
+                skip_stack_underflow: self.skip_stack_underflow,
+                skip_bad_opcodes: self.skip_bad_opcodes,
+                stack_depth: self.stack_depth,
+                stack_in_memory: self.stack_in_memory,
+            },
+            ..Default::default()
+        }
+    }
+}
+
+impl Args {
+    // Settings given on the command line, the highest precedence layer
+    fn settings(&self) -> Settings {
+        Settings {
+            colors: ColorSettings {
+                palette: self.palette.clone(),
+                pixels: None,
+                on: self.fg.clone(),
+                off: self.bg.clone(),
+            },
+            audio: AudioSettings {
+                mute: self.mute,
+                ..Default::default()
+            },
+            display: DisplaySettings {
+                render: self.render,
+                filter: self.filter,
+                filter_frames: self.filter_frames,
+                screenshot_scale: self.screenshot_scale,
+            },
+            ..self.emulation.settings(self.platform)
+        }
+    }
 }
 
-static TERMINATE: AtomicBool = AtomicBool::new(false);
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Show what the ROM database knows about a ROM")]
+    Info { file: PathBuf },
+    #[command(about = "Print a ROM's control-flow graph in Graphviz's DOT language")]
+    Cfg {
+        file: PathBuf,
+        #[arg(long, value_enum)]
+        #[arg(help = "Use the quirks and speed of a platform instead of the detected one")]
+        platform: Option<Platform>,
+        #[arg(long, value_name = "FILE")]
+        #[arg(help = "Use this config file instead of the global one")]
+        config: Option<PathBuf>,
+        #[arg(short, long, value_name = "FILE")]
+        #[arg(help = "Write the graph to a file instead of standard output")]
+        output: Option<PathBuf>,
+        #[arg(long, value_name = "N", conflicts_with = "replay")]
+        #[arg(help = "Run N frames with no keys down and add the BNNN jumps seen")]
+        frames: Option<u64>,
+        #[arg(long, value_name = "FILE")]
+        #[arg(help = "Replay a movie and add the BNNN jumps seen")]
+        replay: Option<PathBuf>,
+        #[arg(long, value_name = "FILE")]
+        #[arg(help = "Label addresses with a symbol file [default: the ROM's .sym file, if any]")]
+        symbols: Option<PathBuf>,
+    },
+    #[command(about = "Check a ROM for likely mistakes without running it")]
+    Lint {
+        file: PathBuf,
+        #[arg(long, value_enum)]
+        #[arg(help = "Check against a platform instead of the detected one [default: vip]")]
+        platform: Option<Platform>,
+        #[arg(long, value_name = "FILE")]
+        #[arg(help = "Use this config file instead of the global one")]
+        config: Option<PathBuf>,
+        #[arg(long, value_name = "FILE")]
+        #[arg(help = "Label addresses with a symbol file [default: the ROM's .sym file, if any]")]
+        symbols: Option<PathBuf>,
+    },
+    #[command(about = "Print execution traces or compare them with other emulators")]
+    Trace {
+        #[command(subcommand)]
+        command: TraceCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum TraceCommand {
+    #[command(about = "Print a binary trace as text")]
+    Dump {
+        file: PathBuf,
+        #[arg(long, value_name = "FILE")]
+        #[arg(help = "Label addresses with a symbol file")]
+        symbols: Option<PathBuf>,
+    },
+    #[command(about = "Run a ROM and compare every instruction with another emulator's log")]
+    Diff {
+        rom: PathBuf,
+        reference: PathBuf,
+        #[command(flatten)]
+        emulation: EmulationArgs,
+        #[arg(long, value_enum)]
+        #[arg(help = "Use the quirks and speed of a platform instead of the detected one")]
+        platform: Option<Platform>,
+        #[arg(long, value_name = "FILE")]
+        #[arg(help = "Use this config file instead of the global one")]
+        config: Option<PathBuf>,
+        #[arg(long, value_name = "N")]
+        #[arg(help = "Compare the first N instructions [default: all of the reference]")]
+        cycles: Option<u64>,
+        #[arg(long, value_name = "LAYOUT", default_value = "pc,opcode,v,i,sp,dt,st")]
+        #[arg(
+            help = "Columns of the reference: pc, opcode, v0-vf, v for all 16, i, sp, dt, st or _ to ignore one"
+        )]
+        columns: String,
+        #[arg(long, value_enum, default_value_t = Timing::Before)]
+        #[arg(help = "Whether reference lines show the state before or after their instruction")]
+        state: Timing,
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        #[arg(help = "Skip N header lines at the start of the reference")]
+        skip_lines: usize,
+        #[arg(long, value_name = "N", default_value_t = 8)]
+        #[arg(help = "Show the N instructions before a divergence")]
+        context: usize,
+        #[arg(long, value_name = "FILE")]
+        #[arg(help = "Label addresses with a symbol file [default: the ROM's .sym file, if any]")]
+        symbols: Option<PathBuf>,
+    },
+}
+
+fn exit_with_error(err: &str) -> ! {
+    eprintln!("Error: {}", err);
+    std::process::exit(1);
+}
+
+fn load_rom(path: &Path) -> Rom {
+    Rom::load(path, MEMORY_SIZE - PROGRAM_START).unwrap_or_else(|err| exit_with_error(&err))
+}
+
+// The platform and an in-memory stack can leave less room than load_rom allows
+fn check_rom_fits(rom: &Rom, path: &Path, config: &Config) {
+    if let Err(err) = rom.check_fits(config.rom_capacity) {
+        exit_with_error(&format!("Bad ROM {}: {}", path.display(), err));
+    }
+}
+
+fn resolve_settings(
+    db: &Database,
+    found: Option<&Match>,
+    config: Option<&Path>,
+    rom: Option<&Path>,
+    cli: Settings,
+) -> Settings {
+    settings::load_and_resolve(db, found, config, rom, cli)
+        .unwrap_or_else(|err| exit_with_error(&err))
+}
+
+// The symbol file at `path`, or the .sym file next to the ROM if there is one
+fn load_symbols(path: Option<&Path>, rom: &Path) -> Symbols {
+    let sidecar = rom.with_extension("sym");
+    let path = match path {
+        Some(path) => path,
+        None if sidecar.exists() => &sidecar,
+        None => return Symbols::default(),
+    };
+    Symbols::load(path).unwrap_or_else(|err| exit_with_error(&err))
+}
+
+fn info(path: &Path) {
+    let rom = load_rom(path);
+    let sha1 = sha1_hex(rom.bytes());
+    let db = Database::embedded();
+
+    println!("File:     {}", path.display());
+    println!("Size:     {} bytes", rom.bytes().len());
+    println!("SHA-1:    {}", sha1);
+
+    let found = match db.lookup(&sha1) {
+        Some(found) => found,
+        None => {
+            println!("Not found in the ROM database");
+            return;
+        }
+    };
+
+    println!("Title:    {}", found.program.title);
+    if !found.program.authors.is_empty() {
+        println!("Authors:  {}", found.program.authors.join(", "));
+    }
+    if let Some(release) = &found.program.release {
+        println!("Release:  {}", release);
+    }
+    if let Some(file) = &found.rom.file {
+        println!("Filename: {}", file);
+    }
+    match found.platform {
+        Some(platform) => println!("Platform: {}", platform.name),
+        None => println!(
+            "Platform: none supported (listed: {})",
+            found.rom.platforms.join(", ")
+        ),
+    }
+    if let Some(tickrate) = found.tickrate() {
+        println!("Speed:    {} instructions per frame", tickrate);
+    }
+    if let Some(q) = found.quirks() {
+        println!("Quirks:");
+        println!("  shift (8XY6/8XYE ignore Y):       {}", q.shift);
+        println!("  jump (BXNN):                      {}", q.jump);
+        println!(
+            "  memory (FX55/FX65 I += X + 1):    {}",
+            !q.memory_leave_i_unchanged && !q.memory_increment_by_x
+        );
+        println!(
+            "  wrap, vblank, logic (unsupported): {}, {}, {}",
+            q.wrap, q.vblank, q.logic
+        );
+    }
+    if let Some(pixels) = found.pixel_colors() {
+        println!("Colors:   {}", pixels.join(", "));
+    }
+    let keys = found.keys();
+    if !keys.is_empty() {
+        let keys: Vec<String> = keys
+            .iter()
+            .map(|(name, key)| format!("{} -> {:X}", name, key))
+            .collect();
+        println!("Keys:     {}", keys.join(", "));
+    }
+    if let Some(description) = &found.program.description {
+        println!();
+        println!("{}", description);
+    }
+}
+
+fn print_graph(
+    path: &Path,
+    platform: Option<Platform>,
+    config: Option<&Path>,
+    output: Option<&Path>,
+    frames: Option<u64>,
+    replay: Option<&Path>,
+    symbols: Option<&Path>,
+) {
+    let rom = load_rom(path);
+    let symbols = load_symbols(symbols, path);
+    let sha1 = sha1_hex(rom.bytes());
+    let db = Database::embedded();
+    let found = db.lookup(&sha1);
+    let cli = Settings {
+        platform,
+        ..Default::default()
+    };
+
+    let (settings, seed, keys) = match replay.map(Movie::load) {
+        Some(Ok(movie)) => {
+            if movie.rom_sha1 != sha1 {
+                exit_with_error("The movie was recorded with a different ROM");
+            }
+            let keys = movie.keys().unwrap_or_else(|err| exit_with_error(&err));
+            (movie.settings, movie.seed, keys)
+        }
+        Some(Err(err)) => exit_with_error(&err),
+        None => {
+            let settings = resolve_settings(&db, found.as_ref(), config, Some(path), cli);
+            (settings, 0, vec![0; frames.unwrap_or(0) as usize])
+        }
+    };
+    let mut config = settings
+        .to_config()
+        .unwrap_or_else(|err| exit_with_error(&err));
+    check_rom_fits(&rom, path, &config);
+    config.mute = true;
+    let bxnn = config.bxnn;
+
+    let random = random::new(seed);
+    let mut cpu = Chip8::new(&rom, config, random);
+    let (jumps, err) = graph::observe_jumps(&mut cpu, &keys);
+    if let Some(err) = err {
+        eprintln!("Run stopped at {}", err);
+    }
+
+    let mut memory = vec![0; MEMORY_SIZE];
+    memory[PROGRAM_START..][..rom.bytes().len()].copy_from_slice(rom.bytes());
+    let flow = Flow::build(&memory, rom.bytes().len(), &jumps);
+    let name = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let text = graph::dot(&flow, &name, bxnn, &symbols);
+
+    match output {
+        Some(output) => std::fs::write(output, text).unwrap_or_else(|e| {
+            exit_with_error(&format!("Failed to write {}: {}", output.display(), e))
+        }),
+        None => print!("{}", text),
+    }
+}
+
+// Exits with an error if there are any warnings
+fn lint_rom(
+    path: &Path,
+    platform: Option<Platform>,
+    config: Option<&Path>,
+    symbols: Option<&Path>,
+) {
+    let rom = load_rom(path);
+    let symbols = load_symbols(symbols, path);
+    let db = Database::embedded();
+    let found = db.lookup(&sha1_hex(rom.bytes()));
+    let settings = resolve_settings(
+        &db,
+        found.as_ref(),
+        config,
+        Some(path),
+        Settings {
+            platform,
+            ..Default::default()
+        },
+    );
+    let config = settings
+        .to_config()
+        .unwrap_or_else(|err| exit_with_error(&err));
+    check_rom_fits(&rom, path, &config);
+    let bxnn = config.bxnn;
+    let platform = settings.platform.unwrap_or(Platform::OriginalChip8);
+    let name = db
+        .platform(platform)
+        .map_or(platform.id(), |info| info.name.as_str());
+
+    let mut memory = vec![0; MEMORY_SIZE];
+    memory[PROGRAM_START..][..rom.bytes().len()].copy_from_slice(rom.bytes());
+    let findings = lint::lint(&memory, rom.bytes().len(), platform, name, bxnn, &symbols);
+
+    let mut warnings = 0;
+    for finding in &findings {
+        let label = match finding.severity {
+            Severity::Warning => {
+                warnings += 1;
+                "warning"
+            }
+            Severity::Note => "note",
+        };
+        println!("{}: {:03X}: {}", label, finding.address, finding.message);
+    }
+    println!("{} warnings, {} notes", warnings, findings.len() - warnings);
+    if warnings > 0 {
+        std::process::exit(1);
+    }
+}
+
+fn dump_trace(path: &Path, symbols: Option<&Path>) -> Result<(), String> {
+    let symbols = symbols.map(Symbols::load).transpose()?.unwrap_or_default();
+    let mut reader = Reader::open(path)?;
+    let mut out = std::io::stdout().lock();
+    while let Some(entry) = reader.next_entry()? {
+        // Stops quietly when piped into something like head
+        if writeln!(out, "{}", entry.to_listing(reader.bxnn, &symbols)).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// Exits with an error if the run doesn't match the reference
+fn diff_trace(
+    rom_path: &Path,
+    reference: &Path,
+    config: Option<&Path>,
+    cli: Settings,
+    mut options: diff::Options,
+) {
+    let rom = load_rom(rom_path);
+    let db = Database::embedded();
+    let found = db.lookup(&sha1_hex(rom.bytes()));
+    let settings = resolve_settings(&db, found.as_ref(), config, Some(rom_path), cli);
+
+    let mut config = settings
+        .to_config()
+        .unwrap_or_else(|err| exit_with_error(&err));
+    check_rom_fits(&rom, rom_path, &config);
+    config.mute = true;
+    options.bxnn = config.bxnn;
+
+    let random = random::new(0);
+    let mut cpu = Chip8::new(&rom, config, random);
+    match diff::run(&mut cpu, reference, &options) {
+        Ok(Outcome::Matched(cycles)) => println!("No differences in {} instructions", cycles),
+        Ok(Outcome::Diverged(report)) => {
+            print!("{}", report);
+            std::process::exit(1);
+        }
+        Err(err) => exit_with_error(&err),
+    }
+}
 
 fn main() {
-    ctrlc::set_handler(|| {
-        TERMINATE.store(true, std::sync::atomic::Ordering::Relaxed);
-    })
-    .expect("Error setting Ctrl-C handler");
+    terminal::handle_signals();
 
     let args = Args::parse();
-    let mut buffer = [0u8; 3584];
-    let mut file = File::open(&args.file)
-        .unwrap_or_else(|e| panic!("Failed to open file {}: {}", args.file.display(), e));
-
-    let _bytes_read = file
-        .read(&mut buffer)
-        .unwrap_or_else(|e| panic!("Failed to read file {}: {}", args.file.display(), e));
-
-    let config = Config {
-        skip_stack_underflow: args.skip_stack_underflow,
-        bxnn: !args.bnnn,
-        fx1e_overflow: args.flag_fx1e_overflow,
-        shift_in_place_8xy: args.shift_in_place_8xy,
-        increment_i_on_mem: args.increment_i_on_mem,
-        mute: args.mute,
+    match &args.command {
+        Some(Command::Info { file }) => {
+            info(file);
+            return;
+        }
+        Some(Command::Cfg {
+            file,
+            platform,
+            config,
+            output,
+            frames,
+            replay,
+            symbols,
+        }) => {
+            print_graph(
+                file,
+                *platform,
+                config.as_deref(),
+                output.as_deref(),
+                *frames,
+                replay.as_deref(),
+                symbols.as_deref(),
+            );
+            return;
+        }
+        Some(Command::Lint {
+            file,
+            platform,
+            config,
+            symbols,
+        }) => {
+            lint_rom(file, *platform, config.as_deref(), symbols.as_deref());
+            return;
+        }
+        Some(Command::Trace {
+            command: TraceCommand::Dump { file, symbols },
+        }) => {
+            dump_trace(file, symbols.as_deref()).unwrap_or_else(|err| exit_with_error(&err));
+            return;
+        }
+        Some(Command::Trace {
+            command:
+                TraceCommand::Diff {
+                    rom,
+                    reference,
+                    emulation,
+                    platform,
+                    config,
+                    cycles,
+                    columns,
+                    state,
+                    skip_lines,
+                    context,
+                    symbols,
+                },
+        }) => {
+            let layout = diff::parse_layout(columns).unwrap_or_else(|err| exit_with_error(&err));
+            let options = diff::Options {
+                layout,
+                timing: *state,
+                skip_lines: *skip_lines,
+                cycles: *cycles,
+                context: *context,
+                bxnn: false,
+                symbols: load_symbols(symbols.as_deref(), rom),
+            };
+            diff_trace(
+                rom,
+                reference,
+                config.as_deref(),
+                emulation.settings(*platform),
+                options,
+            );
+            return;
+        }
+        None => {}
+    }
+
+    let rom_path = args.file.as_deref();
+    let rom = rom_path.map(load_rom);
+    let rom_sha1 = rom.as_ref().map(|rom| sha1_hex(rom.bytes()));
+
+    let db = Database::embedded();
+    let found = match &rom_sha1 {
+        Some(sha1) if !args.no_database => db.lookup(sha1),
+        _ => None,
+    };
+
+    let mut replay = args
+        .replay
+        .as_deref()
+        .map(|path| Movie::load(path).unwrap_or_else(|err| exit_with_error(&err)));
+
+    let settings = match &mut replay {
+        // A replay brings the settings it was recorded with
+        Some(movie) => {
+            if rom_sha1.as_ref() != Some(&movie.rom_sha1) {
+                exit_with_error("The movie was recorded with a different ROM");
+            }
+            std::mem::take(&mut movie.settings)
+        }
+        None => resolve_settings(
+            &db,
+            found.as_ref(),
+            args.config.as_deref(),
+            rom_path,
+            args.settings(),
+        ),
+    };
+
+    let mut config = settings
+        .to_config()
+        .unwrap_or_else(|err| exit_with_error(&err));
+
+    if args.print_config {
+        let files = [
+            ("global", args.config.clone().or_else(settings::global_path)),
+            ("sidecar", rom_path.map(settings::sidecar_path)),
+        ];
+        for (name, path) in files {
+            if let Some(path) = path {
+                let status = if path.exists() { "" } else { " (not found)" };
+                println!("# {} config: {}{}", name, path.display(), status);
+            }
+        }
+        if let Some(found) = &found {
+            println!("# detected: {}", found.program.title);
+        }
+        println!("# keys are added to the hex keypad (0-9, a-f)");
+        print!("{}", settings.to_toml());
+        return;
+    }
+
+    let rom_path = rom_path.unwrap();
+    check_rom_fits(rom.as_ref().unwrap(), rom_path, &config);
+    let symbols = load_symbols(args.symbols.as_deref(), rom_path);
+    let screenshots = Screenshots {
+        prefix: rom_path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "screenshot".to_string()),
+        at_frame: args.screenshot_at_frame.as_deref().map(|values| {
+            let frame = values[0].parse().unwrap_or_else(|_| {
+                exit_with_error(&format!("'{}' is not a frame number", values[0]))
+            });
+            (frame, PathBuf::from(&values[1]))
+        }),
+    };
+
+    let tracer = args.trace.as_deref().map(|path| {
+        let filter = Filter::parse(
+            args.trace_addresses.as_deref(),
+            args.trace_ops.as_deref(),
+            args.trace_cycles.as_deref(),
+            &symbols,
+        )
+        .unwrap_or_else(|err| exit_with_error(&err));
+        Tracer::create(path, filter, config.bxnn, symbols.clone())
+            .unwrap_or_else(|err| exit_with_error(&err))
+    });
+    let recorder = args.record.as_deref().map(|path| {
+        Recorder::create(path, config.screenshot_scale).unwrap_or_else(|err| exit_with_error(&err))
+    });
+    let replay_keys = replay
+        .as_ref()
+        .map(|movie| movie.keys().unwrap_or_else(|err| exit_with_error(&err)));
+    let seed = match &replay {
+        Some(movie) => movie.seed,
+        None => args.seed.unwrap_or_else(random_seed),
     };
 
-    let mut cpu = Chip8::new(&buffer, config);
+    if args.headless {
+        config.mute = true;
+    }
+    let mut session = Session {
+        hardware: (!args.headless).then(|| {
+            Hardware::new(
+                config.keymap.clone(),
+                config.hotkeys.clone(),
+                config.render_mode,
+                config.palette.off(),
+            )
+        }),
+        picture: Picture::new(
+            DisplayFilter::new(config.filter, config.filter_frames),
+            config.palette.clone(),
+        ),
+        screenshots,
+        recorder,
+        movie: replay_keys.is_some() || args.record_input.is_some(),
+        replay: replay_keys,
+        input: Vec::new(),
+    };
+
+    let terminal =
+        (!args.headless).then(|| TerminalGuard::enter().unwrap_or_else(|err| exit_with_error(err)));
+    let random = random::new(seed);
+    let bxnn = config.bxnn;
+    let mut cpu = Chip8::new(rom.as_ref().unwrap(), config, random);
+    if let Some(tracer) = tracer {
+        cpu.set_tracer(tracer);
+    }
+    if args.profile_report || args.profile_folded.is_some() {
+        cpu.set_profiler(Profiler::new(bxnn));
+    }
+    if args.check_overlaps {
+        cpu.set_overlaps(Overlaps::new(bxnn));
+    }
+    if args.coverage.is_some() {
+        let rom_len = rom.as_ref().unwrap().bytes().len();
+        cpu.set_coverage(Coverage::new(cpu.memory(), rom_len));
+    }
+    let mut result = match &mut session.hardware {
+        Some(hardware) if args.tui_debug => {
+            debugger::run(&mut cpu, hardware, &mut session.picture, &symbols, bxnn)
+        }
+        _ => run(&mut cpu, &mut session),
+    };
+    let final_hash = cpu.state_hash();
+    let frames = session.input.len() as u64;
+    let tracer = cpu.take_tracer();
+    let profiler = cpu.take_profiler();
+    let coverage = cpu.take_coverage();
+    let overlaps = cpu.take_overlaps();
+    let backtrace = cpu.take_backtrace();
+
+    // Restore the terminal before printing anything
+    drop(cpu);
+    drop(terminal);
+
+    // Whatever was recorded is kept, even if the program failed
+    if let Some(recorder) = session.recorder
+        && let Err(err) = recorder.finish()
+    {
+        result = result.and(Err(err));
+    }
+    if let Some(tracer) = tracer
+        && let Err(err) = tracer.finish()
+    {
+        result = result.and(Err(err));
+    }
+    if let Some(profiler) = &profiler {
+        if args.profile_report {
+            print!("{}", profiler.report(&symbols));
+        }
+        if let Some(path) = &args.profile_folded
+            && let Err(err) = profiler.save_folded(path, &symbols)
+        {
+            result = result.and(Err(err));
+        }
+    }
+    if let Some(overlaps) = &overlaps {
+        print!("{}", overlaps.report_text(&symbols));
+    }
+    if let Some(coverage) = &coverage {
+        let listing = args.coverage.as_deref().unwrap();
+        let name = rom_path.file_name().unwrap_or_default().to_string_lossy();
+        let mut saved = coverage.save_listing(listing, &name, bxnn, &symbols);
+        if let Some(path) = &args.coverage_lcov {
+            saved = saved.and_then(|()| coverage.save_lcov(path, listing, &symbols));
+        }
+        result = result.and(saved);
+    }
+    if let Some(path) = &args.record_input {
+        let mut movie = Movie::new(rom_sha1.unwrap(), seed, settings);
+        movie.set_input(&session.input);
+        movie.final_hash = final_hash.clone();
+        movie.error = result.as_ref().err().cloned();
+        if let Err(err) = movie.save(path) {
+            result = result.and(Err(err));
+        }
+    }
+
+    if let Some(movie) = &replay {
+        if frames < movie.frames {
+            exit_with_error(&format!(
+                "Replay stopped after {} of {} frames",
+                frames, movie.frames
+            ));
+        }
+        if result.as_ref().err() != movie.error.as_ref() {
+            exit_with_error(&format!(
+                "Replay ended with {} but the recording ended with {}",
+                describe_end(&result),
+                describe_end(&movie.error.clone().map_or(Ok(()), Err)),
+            ));
+        }
+        if final_hash != movie.final_hash {
+            exit_with_error(&format!(
+                "Replay state hash {} doesn't match the recorded {}",
+                final_hash, movie.final_hash
+            ));
+        }
+        println!("Replay matched after {} frames: {}", frames, final_hash);
+        return;
+    }
+
+    if let Err(err) = result {
+        match backtrace {
+            Some(backtrace) => {
+                let place = match symbols.label(backtrace.pc()) {
+                    Some(label) => format!(" ({})", label),
+                    None => String::new(),
+                };
+                exit_with_error(&format!(
+                    "{}{}\n{}",
+                    err,
+                    place,
+                    backtrace.text(bxnn, &symbols)
+                ))
+            }
+            None => exit_with_error(&err),
+        }
+    }
+}
+
+fn random_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_nanos() as u64)
+}
+
+fn describe_end(result: &Result<(), String>) -> String {
+    match result {
+        Ok(()) => "no error".to_string(),
+        Err(err) => format!("error '{}'", err),
+    }
+}
+
+struct Screenshots {
+    // The screenshot hotkey saves to <prefix>-<frame>.png
+    prefix: String,
+    at_frame: Option<(u64, PathBuf)>,
+}
+
+// Everything the main loop drives besides the CPU
+struct Session {
+    // None when running headless
+    hardware: Option<Hardware>,
+    picture: Picture,
+    screenshots: Screenshots,
+    recorder: Option<Recorder>,
+    // Keypad state for each frame, played back instead of the keyboard
+    replay: Option<Vec<u16>>,
+    // Keypad state of every frame run so far
+    input: Vec<u16>,
+    // Whether input is recorded or played back as a movie
+    movie: bool,
+}
 
-    let cycle_duration = Duration::from_secs_f32(1.0 / args.cpu_hz as f32);
-    let mut last_cycle = Instant::now();
+// Runs a frame at a time until the program errors, an exit is requested or
+// the replay runs out. Headless runs go as fast as they can.
+fn run(cpu: &mut Chip8, session: &mut Session) -> Result<(), String> {
+    let frame_duration = Duration::from_secs_f64(1.0 / 60.0);
+    let mut next_frame = Instant::now();
+    let mut paused = false;
 
     loop {
-        if TERMINATE.load(std::sync::atomic::Ordering::Relaxed) {
-            cpu.clean_up().unwrap();
-            break;
+        if terminal::exit_requested() {
+            return Ok(());
         }
-        let elapsed = last_cycle.elapsed();
-        if elapsed < cycle_duration {
-            std::thread::sleep(cycle_duration - elapsed);
-        }
-        last_cycle = Instant::now();
-        match cpu.step() {
-            Ok(()) => {}
-            Err(err) => {
-                if args.skip_bad_opcodes && err.starts_with("bad opcode") {
-                    continue;
-                } else {
-                    panic!("Err: {}", err);
+
+        let live_keys = match &mut session.hardware {
+            Some(hardware) => match hardware.keypad() {
+                Ok(keys) => keys,
+                // Ctrl-C is reported as an error to stop whatever was running
+                Err(_) if terminal::exit_requested() => return Ok(()),
+                Err(err) => return Err(err.to_string()),
+            },
+            None => 0,
+        };
+
+        let mut frames = 1;
+        if let Some(hardware) = &mut session.hardware {
+            for hotkey in hardware.take_hotkeys() {
+                if let Some(status) = apply_hotkey(hotkey, cpu, &mut paused, session.movie) {
+                    hardware.show_status(&status);
+                }
+            }
+            if paused {
+                frames = 0;
+                if !hardware.has_status() {
+                    hardware.show_status("Paused");
                 }
+            } else if hardware.fast_forward() {
+                frames = FAST_FORWARD;
+                hardware.show_status("Fast forward");
             }
         }
+        if terminal::exit_requested() {
+            return Ok(());
+        }
+
+        let mut changed = false;
+        for _ in 0..frames {
+            let frame = session.input.len();
+            let keys = match &session.replay {
+                Some(replay) => match replay.get(frame) {
+                    Some(keys) => *keys,
+                    None => return Ok(()),
+                },
+                None => live_keys,
+            };
+            session.input.push(keys);
+
+            cpu.run_frame(keys)?;
+            let frame = frame as u64 + 1;
+
+            changed |= session.picture.update(cpu.display());
+            if let Some(recorder) = &mut session.recorder {
+                recorder.add(session.picture.image())?;
+            }
+            if let Some((at, path)) = &session.screenshots.at_frame
+                && *at == frame
+            {
+                cpu.screenshot(path)?;
+            }
+        }
+
+        let Some(hardware) = &mut session.hardware else {
+            continue;
+        };
+        hardware.present(session.picture.image(), changed)?;
+        if hardware.take_screenshot_request() {
+            let path = format!(
+                "{}-{:06}.png",
+                session.screenshots.prefix,
+                session.input.len()
+            );
+            cpu.screenshot(Path::new(&path))?;
+        }
+
+        next_frame += frame_duration;
+        let now = Instant::now();
+        if next_frame > now {
+            std::thread::sleep(next_frame - now);
+        } else if now - next_frame > frame_duration {
+            // Too far behind to catch up, so start counting again from now
+            next_frame = now;
+        }
+    }
+}
+
+// Frames run for each one shown while fast forwarding
+const FAST_FORWARD: usize = 4;
+// Most instructions a frame the speed hotkeys go up to
+const MAX_PER_FRAME: u32 = 1000;
+
+// Carries out a hotkey and returns the status to show, if any. Movies can't
+// hold resets or speed changes, so those are refused while one is recorded
+// or played back.
+fn apply_hotkey(hotkey: Hotkey, cpu: &mut Chip8, paused: &mut bool, movie: bool) -> Option<String> {
+    match hotkey {
+        Hotkey::Pause => {
+            *paused = !*paused;
+            // While paused, the main loop keeps the status up itself
+            (!*paused).then(|| "Running".to_string())
+        }
+        Hotkey::Reset | Hotkey::Faster | Hotkey::Slower if movie => {
+            Some("Can't change the run while recording or replaying a movie".to_string())
+        }
+        Hotkey::Reset => {
+            cpu.reset();
+            Some("Reset".to_string())
+        }
+        Hotkey::Faster | Hotkey::Slower => {
+            // Steps of about a quarter, and at least one instruction a frame
+            let per_frame = (cpu.cpu_hz() / 60).max(1);
+            let step = (per_frame / 4).max(1);
+            let per_frame = match hotkey {
+                Hotkey::Faster => (per_frame + step).min(MAX_PER_FRAME),
+                _ => per_frame.saturating_sub(step).max(1),
+            };
+            cpu.set_cpu_hz(per_frame * 60);
+            Some(format!(
+                "Speed {} Hz, {} instructions a frame",
+                per_frame * 60,
+                per_frame
+            ))
+        }
+        Hotkey::Mute => {
+            let status = match cpu.set_mute(!cpu.muted()) {
+                Ok(()) if cpu.muted() => "Muted",
+                Ok(()) => "Sound on",
+                Err(err) => err,
+            };
+            Some(status.to_string())
+        }
+        // Held rather than pressed, see Hardware::fast_forward
+        Hotkey::FastForward => None,
+        Hotkey::Quit => {
+            terminal::request_exit();
+            None
+        }
     }
 }
@@ -0,0 +1,166 @@
+use std::{fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::settings::Settings;
+
+const VERSION: u32 = 3;
+
+// A recorded run: everything needed to play it back exactly, and the state
+// it should end in
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Movie {
+    pub version: u32,
+    pub rom_sha1: String,
+    pub seed: u64,
+    pub frames: u64,
+    // State hash after the last frame
+    pub final_hash: String,
+    // Set if the run stopped on an error
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    // Keypad state for each frame as a hex bit mask, with "*N" for repeats
+    pub input: Vec<String>,
+    pub settings: Settings,
+}
+
+impl Movie {
+    pub fn new(rom_sha1: String, seed: u64, settings: Settings) -> Self {
+        Self {
+            version: VERSION,
+            rom_sha1,
+            seed,
+            frames: 0,
+            final_hash: String::new(),
+            error: None,
+            input: Vec::new(),
+            settings,
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let bad_movie = |e: toml::de::Error| format!("Bad movie {}: {}", path.display(), e);
+
+        // Other versions may have other fields, so check the version first
+        let table: toml::Table = toml::from_str(&text).map_err(bad_movie)?;
+        let version = table.get("version").and_then(|v| v.as_integer());
+        if version != Some(VERSION as i64) {
+            return Err(format!(
+                "{} is a version {} movie, only version {} is supported",
+                path.display(),
+                version.map_or("unknown".to_string(), |v| v.to_string()),
+                VERSION
+            ));
+        }
+        table.try_into().map_err(bad_movie)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let text = toml::to_string(self).map_err(|e| e.to_string())?;
+        fs::write(path, text).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    }
+
+    pub fn set_input(&mut self, keys: &[u16]) {
+        self.frames = keys.len() as u64;
+        self.input.clear();
+
+        let mut i = 0;
+        while i < keys.len() {
+            let run = keys[i..].iter().take_while(|&&k| k == keys[i]).count();
+            if run > 1 {
+                self.input.push(format!("{:04x}*{}", keys[i], run));
+            } else {
+                self.input.push(format!("{:04x}", keys[i]));
+            }
+            i += run;
+        }
+    }
+
+    pub fn keys(&self) -> Result<Vec<u16>, String> {
+        let mut keys = Vec::new();
+        for entry in &self.input {
+            let (mask, count) = entry.split_once('*').unwrap_or((entry, "1"));
+            let mask = u16::from_str_radix(mask, 16);
+            let count = count.parse::<usize>();
+            match (mask, count) {
+                (Ok(mask), Ok(count)) => keys.extend(std::iter::repeat_n(mask, count)),
+                _ => return Err(format!("Bad movie input '{}'", entry)),
+            }
+        }
+
+        if keys.len() as u64 != self.frames {
+            return Err(format!(
+                "Movie has input for {} frames but says it has {}",
+                keys.len(),
+                self.frames
+            ));
+        }
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::{Chip8, MEMORY_SIZE, PROGRAM_START};
+    use crate::database::sha1_hex;
+    use crate::random;
+    use crate::rom::Rom;
+
+    // Stores a random number, and counts the frames key 0 is down in V2
+    const ROM: &[u8] = &[
+        0xC0, 0xFF, // V0 = rand
+        0xE1, 0x9E, // skip if key V1 is down
+        0x12, 0x08, // jump 208
+        0x72, 0x01, // V2 += 1
+        0xA3, 0x00, // I = 300
+        0xF2, 0x55, // store V0-V2
+        0x12, 0x00, // jump 200
+    ];
+
+    // Runs a frame for each entry of `keys` and returns the final state hash
+    fn play(settings: &Settings, seed: u64, keys: &[u16]) -> String {
+        let rom = Rom::from_bytes(ROM.to_vec(), MEMORY_SIZE - PROGRAM_START).unwrap();
+        let mut config = settings.to_config().unwrap();
+        config.mute = true;
+        let random = random::new(seed);
+        let mut cpu = Chip8::new(&rom, config, random);
+        for &keys in keys {
+            cpu.run_frame(keys).unwrap();
+        }
+        cpu.state_hash()
+    }
+
+    #[test]
+    fn input_round_trips() {
+        let keys = [0, 0, 0, 1, 0x8000, 0x8000, 0];
+        let mut movie = Movie::new(String::new(), 0, Settings::defaults());
+        movie.set_input(&keys);
+
+        assert_eq!(movie.input, ["0000*3", "0001", "8000*2", "0000"]);
+        assert_eq!(movie.keys().unwrap(), keys);
+    }
+
+    #[test]
+    fn replay_matches_recording() {
+        let keys: Vec<u16> = (0..120).map(|frame| (frame / 10 % 2) as u16).collect();
+        let mut movie = Movie::new(sha1_hex(ROM), 1234, Settings::defaults());
+        movie.set_input(&keys);
+        movie.final_hash = play(&movie.settings, movie.seed, &keys);
+
+        let text = toml::to_string(&movie).unwrap();
+        let loaded: Movie = toml::from_str(&text).unwrap();
+        let replayed = play(&loaded.settings, loaded.seed, &loaded.keys().unwrap());
+        assert_eq!(replayed, movie.final_hash);
+
+        // Other input or another seed end somewhere else
+        assert_ne!(
+            play(&movie.settings, movie.seed, &[0; 120]),
+            movie.final_hash
+        );
+        assert_ne!(play(&movie.settings, 4321, &keys), movie.final_hash);
+    }
+}
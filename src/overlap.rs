@@ -0,0 +1,257 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::backtrace::Run;
+use crate::chip8::MEMORY_SIZE;
+use crate::disasm::{self, Access};
+use crate::symbols::Symbols;
+
+// Instructions kept to show what led up to an overlap
+const HISTORY: usize = 8;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Kind {
+    // Wrote over bytes that had run as code
+    WroteCode,
+    // Ran bytes that had been written
+    RanData,
+}
+
+struct Report {
+    kind: Kind,
+    run: Run,
+    // The instruction on the other side: the code written over, or the
+    // instruction that wrote the data
+    other: Run,
+    // The bytes involved
+    addresses: Vec<u16>,
+    history: Vec<Run>,
+    // How many times it happened
+    count: u64,
+}
+
+// Watches for FX55 and FX33 writing over code, and for code running from
+// bytes that were written. Each overlap is reported once per instruction,
+// with how often it happened.
+pub struct Overlaps {
+    bxnn: bool,
+    // The instruction that last ran at each byte, counting both of its bytes
+    last_run: Vec<Option<Run>>,
+    // The instruction that last wrote each byte
+    last_write: Vec<Option<Run>>,
+    history: VecDeque<Run>,
+    reports: Vec<Report>,
+    // Report index by what happened and where
+    seen: HashMap<(Kind, u16), usize>,
+}
+
+impl Overlaps {
+    pub fn new(bxnn: bool) -> Self {
+        Self {
+            bxnn,
+            last_run: vec![None; MEMORY_SIZE],
+            last_write: vec![None; MEMORY_SIZE],
+            history: VecDeque::new(),
+            reports: Vec::new(),
+            seen: HashMap::new(),
+        }
+    }
+
+    // Called before each instruction runs, with I as it is then
+    pub fn record(&mut self, cycle: u64, pc: u16, opcode: u16, i: u16) {
+        let run = Run { cycle, pc, opcode };
+
+        let code = [pc as usize % MEMORY_SIZE, (pc as usize + 1) % MEMORY_SIZE];
+        let written: Vec<(u16, Run)> = code
+            .iter()
+            .filter_map(|&a| self.last_write[a].map(|writer| (a as u16, writer)))
+            .collect();
+        if let Some(&(_, writer)) = written.first() {
+            let addresses = written.iter().map(|&(a, _)| a).collect();
+            self.report(Kind::RanData, run, writer, addresses);
+        }
+        for address in code {
+            self.last_run[address] = Some(run);
+        }
+
+        if let Some((len, Access::Write)) = disasm::data_access(opcode) {
+            let mut overwritten = Vec::new();
+            for offset in 0..len {
+                let address = (i as usize + offset) % MEMORY_SIZE;
+                if let Some(code) = self.last_run[address] {
+                    overwritten.push((address as u16, code));
+                }
+                self.last_write[address] = Some(run);
+            }
+            if let Some(&(_, code)) = overwritten.first() {
+                let addresses = overwritten.iter().map(|&(a, _)| a).collect();
+                self.report(Kind::WroteCode, run, code, addresses);
+            }
+        }
+
+        self.history.push_back(run);
+        if self.history.len() > HISTORY {
+            self.history.pop_front();
+        }
+    }
+
+    fn report(&mut self, kind: Kind, run: Run, other: Run, addresses: Vec<u16>) {
+        if let Some(&index) = self.seen.get(&(kind, run.pc)) {
+            self.reports[index].count += 1;
+            return;
+        }
+
+        self.seen.insert((kind, run.pc), self.reports.len());
+        self.reports.push(Report {
+            kind,
+            run,
+            other,
+            addresses,
+            history: self.history.iter().copied().collect(),
+            count: 1,
+        });
+    }
+
+    pub fn report_text(&self, symbols: &Symbols) -> String {
+        let describe = |run: &Run| {
+            let mnemonic = symbols.mnemonic(run.opcode, self.bxnn).unwrap_or_default();
+            format!("{:03X}  {:04X}  {}", run.pc, run.opcode, mnemonic)
+                .trim_end()
+                .to_string()
+        };
+
+        let mut text = format!("{} code and data overlaps found\n", self.reports.len());
+        for report in &self.reports {
+            let addresses = match report.addresses.as_slice() {
+                [only] => format!("{:03X}", only),
+                [first, .., last] => format!("{:03X}-{:03X}", first, last),
+                [] => String::new(),
+            };
+            let times = match report.count {
+                1 => String::new(),
+                n => format!(" ({} times)", n),
+            };
+
+            text += &format!(
+                "\nCycle {}: {}{}\n",
+                report.run.cycle,
+                describe(&report.run),
+                times
+            );
+            text += &match report.kind {
+                Kind::WroteCode => format!(
+                    "  wrote over code at {}, last run at cycle {}: {}\n",
+                    addresses,
+                    report.other.cycle,
+                    describe(&report.other)
+                ),
+                Kind::RanData => format!(
+                    "  ran bytes at {} written at cycle {} by {}\n",
+                    addresses,
+                    report.other.cycle,
+                    describe(&report.other)
+                ),
+            };
+            text += "  after:\n";
+            for run in &report.history {
+                text += &format!("  {:>10}  {}\n", run.cycle, describe(run));
+            }
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::Chip8;
+    use crate::random;
+    use crate::rom::Rom;
+    use crate::settings::Settings;
+
+    // Runs 20A, writes a jump over it and runs that, then keeps writing
+    // over the jump's second byte in a loop
+    const ROM: &[u8] = &[
+        0xA2, 0x0A, // 200: LD I, 20A
+        0x60, 0x12, // 202: LD V0, 12
+        0x61, 0x0C, // 204: LD V1, 0C
+        0x12, 0x0A, // 206: JP 20A
+        0xF1, 0x55, // 208: LD [I], V1
+        0x12, 0x08, // 20A: JP 208, then JP 20C
+        0xA2, 0x0B, // 20C: LD I, 20B
+        0xF0, 0x55, // 20E: LD [I], V0
+        0x12, 0x0C, // 210: JP 20C
+    ];
+
+    fn overlaps(instructions: usize) -> Overlaps {
+        let rom = Rom::from_bytes(ROM.to_vec(), ROM.len()).unwrap();
+        let mut config = Settings::defaults().to_config().unwrap();
+        config.mute = true;
+        let mut cpu = Chip8::new(&rom, config, random::new(0));
+        cpu.set_overlaps(Overlaps::new(false));
+        for _ in 0..instructions {
+            cpu.run_instruction().unwrap();
+        }
+        cpu.take_overlaps().unwrap()
+    }
+
+    // Kind, run cycle and pc, other cycle and pc, addresses, count
+    type Summary<'a> = (Kind, u64, u16, u64, u16, &'a [u16], u64);
+
+    #[test]
+    fn reports_each_kind_once_per_instruction() {
+        let overlaps = overlaps(15);
+        let reports: Vec<Summary> = overlaps
+            .reports
+            .iter()
+            .map(|r| {
+                let addresses = r.addresses.as_slice();
+                (
+                    r.kind,
+                    r.run.cycle,
+                    r.run.pc,
+                    r.other.cycle,
+                    r.other.pc,
+                    addresses,
+                    r.count,
+                )
+            })
+            .collect();
+        assert!(
+            reports
+                == [
+                    (Kind::WroteCode, 5, 0x208, 4, 0x20A, &[0x20A, 0x20B][..], 1),
+                    (Kind::RanData, 6, 0x20A, 5, 0x208, &[0x20A, 0x20B][..], 1),
+                    (Kind::WroteCode, 8, 0x20E, 6, 0x20A, &[0x20B][..], 3),
+                ]
+        );
+    }
+
+    #[test]
+    fn report_text_shows_ranges_and_counts() {
+        let text = overlaps(15).report_text(&Symbols::default());
+        let lines: Vec<&str> = text.lines().collect();
+
+        assert_eq!(lines[0], "3 code and data overlaps found");
+        assert_eq!(lines[2], "Cycle 5: 208  F155  LD [I], V1");
+        assert_eq!(
+            lines[3],
+            "  wrote over code at 20A-20B, last run at cycle 4: 20A  1208  JP 208"
+        );
+        assert_eq!(lines[4], "  after:");
+        assert_eq!(lines[9], "           4  20A  1208  JP 208");
+        assert_eq!(lines[11], "Cycle 6: 20A  120C  JP 20C");
+        assert_eq!(
+            lines[12],
+            "  ran bytes at 20A-20B written at cycle 5 by 208  F155  LD [I], V1"
+        );
+        assert_eq!(lines[21], "Cycle 8: 20E  F055  LD [I], V0 (3 times)");
+        assert_eq!(
+            lines[22],
+            "  wrote over code at 20B, last run at cycle 6: 20A  120C  JP 20C"
+        );
+        // The 8 instructions before the first time it happened
+        assert_eq!(lines[24], "           0  200  A20A  LD I, 20A");
+        assert_eq!(lines[31], "           7  20C  A20B  LD I, 20B");
+        assert_eq!(lines.len(), 32);
+    }
+}
@@ -0,0 +1,285 @@
+use crossterm::style::Color;
+
+// Colors for each pixel value: 0 is off, 1 is on, and 2-3 or 2-15 are used
+// by multi-plane variants where pixels can be in several planes at once
+#[derive(Clone)]
+pub struct Palette {
+    colors: Vec<Color>,
+}
+
+pub const BUILTIN_PALETTES: [(&str, &[&str]); 7] = [
+    ("classic", &["dark_yellow", "yellow", "dark_red", "red"]),
+    ("mono", &["#000000", "#ffffff", "#aaaaaa", "#555555"]),
+    ("green", &["#0a1a0a", "#33ff33", "#1f991f", "#66ff66"]),
+    ("amber", &["#1a0f00", "#ffb000", "#996a00", "#ffd060"]),
+    ("gameboy", &["#9bbc0f", "#0f380f", "#8bac0f", "#306230"]),
+    ("octo", &["#996600", "#ffcc00", "#ff6600", "#662200"]),
+    (
+        "cga",
+        &[
+            "#000000", "#ffffff", "#aa0000", "#00aa00", "#0000aa", "#aa5500", "#00aaaa", "#aa00aa",
+            "#555555", "#aaaaaa", "#ff5555", "#55ff55", "#5555ff", "#ffff55", "#55ffff", "#ff55ff",
+        ],
+    ),
+];
+
+impl Palette {
+    pub fn new(colors: Vec<Color>) -> Result<Self, String> {
+        if ![2, 4, 16].contains(&colors.len()) {
+            return Err(format!(
+                "A palette needs 2, 4 or 16 colors, not {}",
+                colors.len()
+            ));
+        }
+
+        Ok(Self { colors })
+    }
+
+    pub fn from_names(names: &[impl AsRef<str>]) -> Result<Self, String> {
+        let colors = names
+            .iter()
+            .map(|name| {
+                let name = name.as_ref();
+                parse_color(name).ok_or_else(|| format!("Unknown color '{}'", name))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Self::new(colors)
+    }
+
+    pub fn builtin(name: &str) -> Option<Self> {
+        let (_, names) = BUILTIN_PALETTES.iter().find(|(n, _)| *n == name)?;
+        Self::from_names(names).ok()
+    }
+
+    pub fn off(&self) -> Color {
+        self.colors[0]
+    }
+
+    pub fn on(&self) -> Color {
+        self.colors[1]
+    }
+
+    pub fn set_off(&mut self, color: Color) {
+        self.colors[0] = color;
+    }
+
+    pub fn set_on(&mut self, color: Color) {
+        self.colors[1] = color;
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self::builtin("classic").unwrap()
+    }
+}
+
+// Accepts a color name ("dark_yellow"), an ANSI-256 index ("208"),
+// or 24-bit RGB as "#rrggbb", "#rgb" or "rgb(r, g, b)"
+pub fn parse_color(s: &str) -> Option<Color> {
+    let s = s.trim().to_lowercase();
+
+    if let Some(hex) = s.strip_prefix('#') {
+        let value = u32::from_str_radix(hex, 16).ok()?;
+        let (r, g, b) = match hex.len() {
+            6 => (value >> 16, value >> 8, value),
+            // Each digit is doubled, so #fa0 is #ffaa00
+            3 => (
+                (value >> 8) * 0x11,
+                ((value >> 4) & 0xF) * 0x11,
+                (value & 0xF) * 0x11,
+            ),
+            _ => return None,
+        };
+
+        return Some(Color::Rgb {
+            r: r as u8,
+            g: g as u8,
+            b: b as u8,
+        });
+    }
+
+    if let Some(args) = s.strip_prefix("rgb(").and_then(|s| s.strip_suffix(')')) {
+        let parts = args
+            .split(',')
+            .map(|part| part.trim().parse::<u8>().ok())
+            .collect::<Option<Vec<u8>>>()?;
+
+        return match parts.as_slice() {
+            [r, g, b] => Some(Color::Rgb {
+                r: *r,
+                g: *g,
+                b: *b,
+            }),
+            _ => None,
+        };
+    }
+
+    if let Ok(index) = s.parse::<u8>() {
+        return Some(Color::AnsiValue(index));
+    }
+
+    Color::try_from(s.replace('-', "_").as_str()).ok()
+}
+
+// Approximate RGB for any color, using xterm's values for named and ANSI colors
+pub fn to_rgb(color: Color) -> (u8, u8, u8) {
+    const ANSI_16: [(u8, u8, u8); 16] = [
+        (0, 0, 0),
+        (128, 0, 0),
+        (0, 128, 0),
+        (128, 128, 0),
+        (0, 0, 128),
+        (128, 0, 128),
+        (0, 128, 128),
+        (192, 192, 192),
+        (128, 128, 128),
+        (255, 0, 0),
+        (0, 255, 0),
+        (255, 255, 0),
+        (0, 0, 255),
+        (255, 0, 255),
+        (0, 255, 255),
+        (255, 255, 255),
+    ];
+
+    let index = match color {
+        Color::Rgb { r, g, b } => return (r, g, b),
+        Color::Reset | Color::Black => 0,
+        Color::DarkRed => 1,
+        Color::DarkGreen => 2,
+        Color::DarkYellow => 3,
+        Color::DarkBlue => 4,
+        Color::DarkMagenta => 5,
+        Color::DarkCyan => 6,
+        Color::Grey => 7,
+        Color::DarkGrey => 8,
+        Color::Red => 9,
+        Color::Green => 10,
+        Color::Yellow => 11,
+        Color::Blue => 12,
+        Color::Magenta => 13,
+        Color::Cyan => 14,
+        Color::White => 15,
+        Color::AnsiValue(value) => value,
+    };
+
+    match index {
+        0..=15 => ANSI_16[index as usize],
+        // 6x6x6 color cube
+        16..=231 => {
+            let level = |v: u8| if v == 0 { 0 } else { 55 + v * 40 };
+            let i = index - 16;
+            (level(i / 36), level((i / 6) % 6), level(i % 6))
+        }
+        // Grayscale ramp
+        _ => {
+            let v = 8 + (index - 232) * 10;
+            (v, v, v)
+        }
+    }
+}
+
+// Mixes `from` and `to`, where `amount` 0.0 is all `from` and 1.0 is all `to`
+pub fn blend(from: Color, to: Color, amount: f32) -> Color {
+    if amount <= 0.0 {
+        return from;
+    }
+    if amount >= 1.0 {
+        return to;
+    }
+
+    let (r1, g1, b1) = to_rgb(from);
+    let (r2, g2, b2) = to_rgb(to);
+    let mix = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * amount).round() as u8;
+
+    Color::Rgb {
+        r: mix(r1, r2),
+        g: mix(g1, g2),
+        b: mix(b1, b2),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const fn rgb(r: u8, g: u8, b: u8) -> Color {
+        Color::Rgb { r, g, b }
+    }
+
+    #[test]
+    fn parses_hex_colors() {
+        assert_eq!(parse_color("#ffaa00"), Some(rgb(0xff, 0xaa, 0x00)));
+        assert_eq!(parse_color("#FA0"), Some(rgb(0xff, 0xaa, 0x00)));
+        assert_eq!(parse_color(" #102030 "), Some(rgb(0x10, 0x20, 0x30)));
+        assert_eq!(parse_color("rgb(1, 2, 3)"), Some(rgb(1, 2, 3)));
+    }
+
+    #[test]
+    fn parses_named_and_indexed_colors() {
+        assert_eq!(parse_color("dark_yellow"), Some(Color::DarkYellow));
+        assert_eq!(parse_color("Dark-Yellow"), Some(Color::DarkYellow));
+        assert_eq!(parse_color("208"), Some(Color::AnsiValue(208)));
+    }
+
+    #[test]
+    fn rejects_bad_colors() {
+        for name in [
+            "",
+            "#",
+            "#ff",
+            "#ffff",
+            "#12345g",
+            "rgb(1, 2)",
+            "rgb(1, 2, 300)",
+            "256",
+            "beige",
+        ] {
+            assert_eq!(parse_color(name), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn blend_mixes_in_rgb() {
+        let black = rgb(0, 0, 0);
+        let white = Color::White;
+        assert_eq!(blend(black, white, 0.0), black);
+        assert_eq!(blend(black, white, -1.0), black);
+        assert_eq!(blend(black, white, 1.0), white);
+        assert_eq!(blend(black, white, 0.5), rgb(128, 128, 128));
+        assert_eq!(
+            blend(rgb(0, 100, 200), rgb(100, 0, 200), 0.25),
+            rgb(25, 75, 200)
+        );
+        // Named and ANSI colors go through xterm's values
+        assert_eq!(blend(Color::AnsiValue(16), Color::Red, 0.5), rgb(128, 0, 0));
+    }
+
+    #[test]
+    fn palettes_have_2_4_or_16_colors() {
+        for size in [2, 4, 16] {
+            let names = vec!["black"; size];
+            assert!(Palette::from_names(&names).is_ok(), "{}", size);
+        }
+        for size in [0, 1, 3, 5, 17] {
+            let err = Palette::from_names(&vec!["black"; size]).err().unwrap();
+            assert_eq!(
+                err,
+                format!("A palette needs 2, 4 or 16 colors, not {}", size)
+            );
+        }
+        let err = Palette::from_names(&["black", "nope"]).err().unwrap();
+        assert_eq!(err, "Unknown color 'nope'");
+    }
+
+    #[test]
+    fn builtins_parse() {
+        for (name, colors) in BUILTIN_PALETTES {
+            let palette = Palette::builtin(name).unwrap();
+            assert_eq!(palette.colors.len(), colors.len(), "{}", name);
+        }
+        assert!(Palette::builtin("nope").is_none());
+    }
+}
@@ -0,0 +1,83 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::chip8::MEMORY_SIZE;
+use crate::disasm::Extension;
+
+// Platforms as named in the CHIP-8 community database
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum, Serialize, Deserialize)]
+pub enum Platform {
+    #[value(name = "vip")]
+    #[serde(rename = "vip")]
+    OriginalChip8,
+    #[value(name = "hybrid-vip")]
+    #[serde(rename = "hybrid-vip")]
+    HybridVip,
+    #[value(name = "modern")]
+    #[serde(rename = "modern")]
+    ModernChip8,
+    #[value(name = "chip48")]
+    #[serde(rename = "chip48")]
+    Chip48,
+    #[value(name = "schip1.0")]
+    #[serde(rename = "schip1.0")]
+    Superchip1,
+    #[value(name = "schip")]
+    #[serde(rename = "schip")]
+    Superchip,
+    #[value(name = "xo-chip")]
+    #[serde(rename = "xo-chip")]
+    XoChip,
+}
+
+impl Platform {
+    pub fn id(&self) -> &'static str {
+        match self {
+            Platform::OriginalChip8 => "originalChip8",
+            Platform::HybridVip => "hybridVIP",
+            Platform::ModernChip8 => "modernChip8",
+            Platform::Chip48 => "chip48",
+            Platform::Superchip1 => "superchip1",
+            Platform::Superchip => "superchip",
+            Platform::XoChip => "xochip",
+        }
+    }
+
+    // Return addresses the platform's stack holds
+    pub fn stack_depth(&self) -> u32 {
+        match self {
+            Platform::OriginalChip8 | Platform::HybridVip => 12,
+            _ => 16,
+        }
+    }
+
+    // End of the memory programs can use. The VIP's interpreter keeps its
+    // stack, variables and display buffer from 0xEA0 up.
+    pub fn memory_end(&self) -> usize {
+        match self {
+            Platform::OriginalChip8 | Platform::HybridVip => 0xEA0,
+            _ => MEMORY_SIZE,
+        }
+    }
+
+    // Whether programs for this platform can use `extension`'s instructions
+    pub fn has(&self, extension: Extension) -> bool {
+        match extension {
+            Extension::MachineCode => {
+                matches!(self, Platform::OriginalChip8 | Platform::HybridVip)
+            }
+            Extension::SuperChip => matches!(
+                self,
+                Platform::Superchip1 | Platform::Superchip | Platform::XoChip
+            ),
+            Extension::XoChip => *self == Platform::XoChip,
+        }
+    }
+
+    pub fn from_id(id: &str) -> Option<Self> {
+        Self::value_variants()
+            .iter()
+            .copied()
+            .find(|p| p.id() == id)
+    }
+}
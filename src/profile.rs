@@ -0,0 +1,287 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    path::Path,
+};
+
+use crate::chip8::{MEMORY_SIZE, PROGRAM_START};
+use crate::disasm;
+use crate::symbols::Symbols;
+
+// How many entries each part of the report lists
+const TOP: usize = 10;
+
+// Counts what the program spends its instructions on. Routines are tracked
+// with a call stack of their own, pushed by 2NNN and popped by 00EE.
+pub struct Profiler {
+    bxnn: bool,
+    instructions: u64,
+    per_address: Vec<u64>,
+    // The last opcode run at each address
+    opcodes: Vec<u16>,
+    per_opcode: BTreeMap<&'static str, u64>,
+    // Entry points of the routines being run, the program itself first
+    calls: Vec<u16>,
+    // Times each routine was called
+    call_counts: HashMap<u16, u64>,
+    // Instructions run with each call stack
+    stacks: HashMap<Vec<u16>, u64>,
+    // Backward jumps, from the jump to its target, and how often they were taken
+    loops: HashMap<(u16, u16), u64>,
+}
+
+impl Profiler {
+    pub fn new(bxnn: bool) -> Self {
+        Self {
+            bxnn,
+            instructions: 0,
+            per_address: vec![0; MEMORY_SIZE],
+            opcodes: vec![0; MEMORY_SIZE],
+            per_opcode: BTreeMap::new(),
+            calls: vec![PROGRAM_START as u16],
+            call_counts: HashMap::new(),
+            stacks: HashMap::new(),
+            loops: HashMap::new(),
+        }
+    }
+
+    // Called after each instruction with where it was, what it was, where
+    // the program went next and how deep the stack is now
+    pub fn record(&mut self, pc: u16, opcode: u16, next_pc: u16, depth: usize) {
+        self.instructions += 1;
+        self.per_address[pc as usize % MEMORY_SIZE] += 1;
+        self.opcodes[pc as usize % MEMORY_SIZE] = opcode;
+        let pattern = disasm::pattern(opcode).unwrap_or("????");
+        *self.per_opcode.entry(pattern).or_default() += 1;
+
+        match self.stacks.get_mut(&self.calls) {
+            Some(count) => *count += 1,
+            None => {
+                self.stacks.insert(self.calls.clone(), 1);
+            }
+        }
+
+        if pattern == "2NNN" && depth == self.calls.len() {
+            self.calls.push(next_pc);
+            *self.call_counts.entry(next_pc).or_default() += 1;
+        } else if next_pc <= pc && pattern != "00EE" {
+            *self.loops.entry((pc, next_pc)).or_default() += 1;
+        }
+        // Keeps in step with returns, and with the stack being emptied some
+        // other way
+        self.calls.truncate(depth + 1);
+    }
+
+    pub fn report(&self, symbols: &Symbols) -> String {
+        let percent = |count: u64| count as f64 * 100.0 / self.instructions.max(1) as f64;
+        let mut text = format!("Profile of {} instructions\n", self.instructions);
+
+        // Instructions in each routine, and in it or anything it called
+        let mut own: HashMap<u16, u64> = HashMap::new();
+        let mut total: HashMap<u16, u64> = HashMap::new();
+        for (stack, count) in &self.stacks {
+            *own.entry(*stack.last().unwrap()).or_default() += count;
+            // Recursive routines are only counted once per stack
+            let routines: HashSet<&u16> = stack.iter().collect();
+            for routine in routines {
+                *total.entry(*routine).or_default() += count;
+            }
+        }
+        let mut routines: Vec<(u16, u64)> = own.into_iter().collect();
+        routines.sort_by_key(|&(address, count)| (std::cmp::Reverse(count), address));
+
+        text += "\nHottest routines:\n";
+        text += &format!(
+            "  {:<16} {:>12} {:>7} {:>11} {:>9}\n",
+            "", "instructions", "own", "with calls", "calls"
+        );
+        for (address, count) in routines.iter().take(TOP) {
+            text += &format!(
+                "  {:<16} {:>12} {:>6.1}% {:>10.1}% {:>9}\n",
+                symbols.routine_name(*address),
+                count,
+                percent(*count),
+                percent(total[address]),
+                self.call_counts.get(address).copied().unwrap_or(0),
+            );
+        }
+
+        let mut addresses: Vec<(usize, u64)> = self
+            .per_address
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|&(_, count)| count > 0)
+            .collect();
+        addresses.sort_by_key(|&(address, count)| (std::cmp::Reverse(count), address));
+
+        text += "\nHottest instructions:\n";
+        for (address, count) in addresses.iter().take(TOP) {
+            text += &format!(
+                "  {:03X}  {:<18} {:>9} {:>6.1}%\n",
+                address,
+                symbols
+                    .mnemonic(self.opcodes[*address], self.bxnn)
+                    .unwrap_or_else(|| format!("{:04X}", self.opcodes[*address])),
+                count,
+                percent(*count)
+            );
+        }
+
+        // A loop's instructions are everything between its target and the
+        // jump back, which is exact for simple loops
+        let mut loops: Vec<(u16, u16, u64, u64)> = self
+            .loops
+            .iter()
+            .map(|(&(from, to), &iterations)| {
+                let inside = self.per_address[to as usize..=from as usize].iter().sum();
+                (to, from, iterations, inside)
+            })
+            .collect();
+        loops.sort_by_key(|&(to, from, _, inside)| (std::cmp::Reverse(inside), to, from));
+
+        text += "\nHottest loops:\n";
+        text += &format!(
+            "  {:<7}  {:<16} {:>10} {:>12}\n",
+            "", "routine", "iterations", "instructions"
+        );
+        for (to, from, iterations, inside) in loops.iter().take(TOP) {
+            text += &format!(
+                "  {:03X}-{:03X}  {:<16} {:>10} {:>12} {:>6.1}%\n",
+                to,
+                from,
+                symbols.routine_name(self.routine_containing(*to)),
+                iterations,
+                inside,
+                percent(*inside)
+            );
+        }
+
+        let mut opcodes: Vec<(&str, u64)> =
+            self.per_opcode.iter().map(|(&op, &n)| (op, n)).collect();
+        opcodes.sort_by_key(|&(op, count)| (std::cmp::Reverse(count), op));
+
+        text += "\nOpcodes:\n";
+        for (op, count) in opcodes {
+            text += &format!("  {}  {:>9} {:>6.1}%\n", op, count, percent(count));
+        }
+        text
+    }
+
+    // One line per call stack, like "main;sub_2A0 1234", for flamegraph tools
+    pub fn save_folded(&self, path: &Path, symbols: &Symbols) -> Result<(), String> {
+        let mut lines: Vec<String> = self
+            .stacks
+            .iter()
+            .map(|(stack, count)| {
+                let names: Vec<String> = stack.iter().map(|a| symbols.routine_name(*a)).collect();
+                format!("{} {}", names.join(";"), count)
+            })
+            .collect();
+        lines.sort();
+
+        let mut text = lines.join("\n");
+        text.push('\n');
+        fs::write(path, text).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    }
+
+    // The closest routine entry at or before `address`
+    fn routine_containing(&self, address: u16) -> u16 {
+        self.call_counts
+            .keys()
+            .copied()
+            .filter(|&entry| entry <= address)
+            .max()
+            .unwrap_or(PROGRAM_START as u16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::Chip8;
+    use crate::random;
+    use crate::rom::Rom;
+    use crate::settings::Settings;
+
+    // Calls 20C three times in a loop back to 202, then spins at 20A
+    const LOOP: &[u8] = &[
+        0x60, 0x03, // 200: LD V0, 3
+        0x22, 0x0C, // 202: CALL 20C
+        0x70, 0xFF, // 204: ADD V0, FF
+        0x30, 0x00, // 206: SE V0, 0
+        0x12, 0x02, // 208: JP 202
+        0x12, 0x0A, // 20A: JP 20A
+        0x00, 0xEE, // 20C: RET
+    ];
+
+    // Runs `instructions` instructions, or up to the first error
+    fn profile(rom: &[u8], stack_depth: usize, instructions: usize) -> (Profiler, Option<String>) {
+        let rom = Rom::from_bytes(rom.to_vec(), rom.len()).unwrap();
+        let mut config = Settings::defaults().to_config().unwrap();
+        config.mute = true;
+        config.stack_depth = Some(stack_depth);
+        let mut cpu = Chip8::new(&rom, config, random::new(0));
+        cpu.set_profiler(Profiler::new(false));
+
+        let err = (0..instructions).find_map(|_| cpu.run_instruction().err());
+        (cpu.take_profiler().unwrap(), err)
+    }
+
+    #[test]
+    fn counts_instructions_and_opcodes() {
+        let (profiler, err) = profile(LOOP, 16, 20);
+        assert!(err.is_none());
+        assert_eq!(profiler.instructions, 20);
+
+        let counts = [1, 3, 3, 3, 2, 5, 3];
+        for (index, count) in counts.into_iter().enumerate() {
+            let address = PROGRAM_START + index * 2;
+            assert_eq!(profiler.per_address[address], count, "{:03X}", address);
+        }
+        assert_eq!(profiler.per_opcode["1NNN"], 7);
+        assert_eq!(profiler.per_opcode["2NNN"], 3);
+        assert_eq!(profiler.per_opcode["00EE"], 3);
+    }
+
+    #[test]
+    fn backward_jumps_are_loops() {
+        let (profiler, _) = profile(LOOP, 16, 20);
+        // Returns go back too, but aren't loops
+        let loops: HashMap<(u16, u16), u64> = [((0x208, 0x202), 2), ((0x20A, 0x20A), 5)].into();
+        assert_eq!(profiler.loops, loops);
+
+        let report = profiler.report(&Symbols::default());
+        let hottest = report.split("Hottest loops:\n").nth(1).unwrap();
+        let lines: Vec<&str> = hottest.lines().skip(1).take(2).collect();
+        // 11 instructions ran between 202 and 208, not counting the call
+        // out to 20C
+        assert!(lines[0].starts_with("  202-208  main"), "{}", lines[0]);
+        assert!(lines[0].contains(" 2           11 "), "{}", lines[0]);
+        assert!(lines[1].starts_with("  20A-20A  main"), "{}", lines[1]);
+    }
+
+    #[test]
+    fn instructions_belong_to_the_routine_on_top() {
+        let (profiler, _) = profile(LOOP, 16, 20);
+        let stacks: HashMap<Vec<u16>, u64> = [(vec![0x200], 17), (vec![0x200, 0x20C], 3)].into();
+        assert_eq!(profiler.stacks, stacks);
+        assert_eq!(profiler.call_counts, [(0x20C, 3)].into());
+        assert_eq!(profiler.calls, [0x200]);
+    }
+
+    #[test]
+    fn failed_calls_arent_counted() {
+        // 200: CALL 204; 204: CALL 204, which overflows a 1 deep stack
+        let rom = [0x22, 0x04, 0x12, 0x02, 0x22, 0x04];
+        let (profiler, err) = profile(&rom, 1, 10);
+        assert!(err.unwrap().contains("Stack overflow"));
+
+        assert_eq!(profiler.instructions, 2);
+        assert_eq!(profiler.calls, [0x200, 0x204]);
+        assert_eq!(profiler.call_counts, [(0x204, 1)].into());
+        let stacks: HashMap<Vec<u16>, u64> = [(vec![0x200], 1), (vec![0x200, 0x204], 1)].into();
+        assert_eq!(profiler.stacks, stacks);
+        assert!(profiler.loops.is_empty());
+    }
+}
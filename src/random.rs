@@ -0,0 +1,38 @@
+// Where CXNN gets its random numbers from
+pub trait Random {
+    // A random byte for CXNN
+    fn next_byte(&mut self) -> u8;
+}
+
+// The default generator
+pub fn new(seed: u64) -> Box<dyn Random> {
+    Box::new(Xorshift::new(seed))
+}
+
+// xorshift64*, seeded through splitmix64 so nearby seeds give unrelated
+// sequences and a seed of 0 still works
+pub struct Xorshift {
+    state: u64,
+}
+
+impl Xorshift {
+    pub fn new(seed: u64) -> Self {
+        let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+
+        Self {
+            state: if z == 0 { 1 } else { z },
+        }
+    }
+}
+
+impl Random for Xorshift {
+    fn next_byte(&mut self) -> u8 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        (self.state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 56) as u8
+    }
+}
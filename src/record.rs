@@ -0,0 +1,246 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::BufWriter,
+    path::{Path, PathBuf},
+};
+
+use crossterm::style::Color;
+
+use crate::render::Image;
+use crate::screenshot::{self, rgb_bytes};
+
+// Records every presented frame, 60 per second, to an animated GIF or a
+// directory of numbered PPM files
+pub struct Recorder {
+    target: Target,
+    scale: usize,
+    // Size of recorded frames, after scaling
+    width: u16,
+    height: u16,
+    // Frames added so far
+    frames: u64,
+}
+
+enum Target {
+    Gif {
+        encoder: gif::Encoder<BufWriter<File>>,
+        path: PathBuf,
+        // The last frame and the frame number it started at. Identical frames
+        // are merged into one with a longer delay.
+        held: Option<(Vec<u8>, u64)>,
+    },
+    Frames(PathBuf),
+}
+
+impl Recorder {
+    // A path ending in .gif records a GIF, anything else is a directory for frames
+    pub fn create(path: &Path, scale: usize) -> Result<Self, String> {
+        let scale = scale.max(1);
+        let (width, height) = ((64 * scale) as u16, (32 * scale) as u16);
+        let is_gif = path
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("gif"));
+
+        let target = if is_gif {
+            let file = File::create(path)
+                .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+            let mut encoder = gif::Encoder::new(BufWriter::new(file), width, height, &[])
+                .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+            encoder
+                .set_repeat(gif::Repeat::Infinite)
+                .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+
+            Target::Gif {
+                encoder,
+                path: path.to_path_buf(),
+                held: None,
+            }
+        } else {
+            fs::create_dir_all(path)
+                .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+            Target::Frames(path.to_path_buf())
+        };
+
+        Ok(Self {
+            target,
+            scale,
+            width,
+            height,
+            frames: 0,
+        })
+    }
+
+    pub fn add(&mut self, image: &Image) -> Result<(), String> {
+        let frame = self.frames;
+        self.frames += 1;
+
+        match &mut self.target {
+            Target::Gif {
+                encoder,
+                path,
+                held,
+            } => {
+                let rgb = rgb_bytes(&image.scaled(self.scale));
+                if held.as_ref().is_some_and(|(last, _)| *last == rgb) {
+                    return Ok(());
+                }
+
+                match held.take() {
+                    // Viewers slow down frames shorter than 2/100 s, so a frame
+                    // that short is replaced by the next one instead
+                    Some((_, start)) if centiseconds(frame) - centiseconds(start) < 2 => {
+                        *held = Some((rgb, start));
+                    }
+                    Some((last, start)) => {
+                        write_gif_frame(encoder, (self.width, self.height), &last, start, frame)
+                            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+                        *held = Some((rgb, frame));
+                    }
+                    None => *held = Some((rgb, frame)),
+                }
+                Ok(())
+            }
+            Target::Frames(dir) => {
+                let path = dir.join(format!("frame-{:06}.ppm", frame + 1));
+                // The background only matters for PBM
+                screenshot::save(&path, image, Color::Reset, self.scale)
+            }
+        }
+    }
+
+    // Writes out the last frame, which is held until the next one differs
+    pub fn finish(mut self) -> Result<(), String> {
+        let end = self.frames;
+        if let Target::Gif {
+            encoder,
+            path,
+            held: Some((last, start)),
+        } = &mut self.target
+        {
+            let size = (self.width, self.height);
+            write_gif_frame(encoder, size, last, *start, end.max(*start + 2))
+                .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+        }
+        Ok(())
+    }
+}
+
+// GIF delays are in hundredths of a second, so frame boundaries are rounded
+// from the 60 fps frame numbers to keep the total time right
+fn centiseconds(frame: u64) -> u64 {
+    (frame * 100 + 30) / 60
+}
+
+fn write_gif_frame(
+    encoder: &mut gif::Encoder<BufWriter<File>>,
+    (width, height): (u16, u16),
+    rgb: &[u8],
+    start: u64,
+    end: u64,
+) -> Result<(), gif::EncodingError> {
+    // Indexed with the exact colors when there are few enough of them
+    let mut colors: HashMap<&[u8], u8> = HashMap::new();
+    let mut palette = Vec::new();
+    let mut pixels = Vec::with_capacity(rgb.len() / 3);
+    for color in rgb.chunks(3) {
+        let next = colors.len();
+        if next == 256 && !colors.contains_key(color) {
+            break;
+        }
+        let index = *colors.entry(color).or_insert_with(|| {
+            palette.extend_from_slice(color);
+            next as u8
+        });
+        pixels.push(index);
+    }
+
+    let mut frame = if pixels.len() * 3 == rgb.len() {
+        gif::Frame::from_palette_pixels(width, height, pixels, palette, None)
+    } else {
+        gif::Frame::from_rgb_speed(width, height, rgb, 10)
+    };
+    frame.delay = (centiseconds(end) - centiseconds(start)).min(u16::MAX as u64) as u16;
+
+    encoder.write_frame(&frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("chip8-record-{}-{}", std::process::id(), name))
+    }
+
+    fn filled(r: u8) -> Image {
+        Image::new(64, 32, Color::Rgb { r, g: 0, b: 0 })
+    }
+
+    // Each frame's delay and the red level of its first pixel
+    fn read_gif(path: &Path) -> Vec<(u16, u8)> {
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::RGBA);
+        let mut decoder = options.read_info(File::open(path).unwrap()).unwrap();
+        assert_eq!((decoder.width(), decoder.height()), (64, 32));
+
+        let mut frames = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            frames.push((frame.delay, frame.buffer[0]));
+        }
+        frames
+    }
+
+    #[test]
+    fn gif_merges_repeated_frames() {
+        let path = temp_path("same.gif");
+        let mut recorder = Recorder::create(&path, 1).unwrap();
+        for r in [10, 10, 200, 200, 200] {
+            recorder.add(&filled(r)).unwrap();
+        }
+        recorder.finish().unwrap();
+
+        let frames = read_gif(&path);
+        fs::remove_file(&path).unwrap();
+        // 2 frames at 60 fps round to 3/100 s, and the next 3 to 5/100 s
+        assert_eq!(frames, [(3, 10), (5, 200)]);
+    }
+
+    #[test]
+    fn gif_replaces_frames_too_short_to_show() {
+        let path = temp_path("short.gif");
+        let mut recorder = Recorder::create(&path, 1).unwrap();
+        for r in [10, 100, 200] {
+            recorder.add(&filled(r)).unwrap();
+        }
+        recorder.finish().unwrap();
+
+        let frames = read_gif(&path);
+        fs::remove_file(&path).unwrap();
+        // The frame at 100 would only last 1/100 s, so 200 takes its place
+        assert_eq!(frames, [(2, 10), (3, 200)]);
+    }
+
+    #[test]
+    fn directories_get_numbered_ppm_frames() {
+        let dir = temp_path("frames");
+        let mut recorder = Recorder::create(&dir, 2).unwrap();
+        recorder.add(&filled(10)).unwrap();
+        recorder.add(&filled(10)).unwrap();
+        recorder.finish().unwrap();
+
+        let mut names: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        let frame = fs::read(dir.join("frame-000002.ppm")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(names, ["frame-000001.ppm", "frame-000002.ppm"]);
+        let header = b"P6\n128 64\n255\n";
+        assert_eq!(&frame[..header.len()], header);
+        assert_eq!(frame.len(), header.len() + 128 * 64 * 3);
+        assert_eq!(frame[header.len()..][..3], [10, 0, 0]);
+    }
+}
@@ -0,0 +1,541 @@
+use std::io::{self, Write};
+
+use clap::ValueEnum;
+use crossterm::{
+    QueueableCommand, cursor,
+    style::{Color, Print, ResetColor, SetBackgroundColor, SetForegroundColor},
+    terminal,
+};
+use serde::{Deserialize, Serialize};
+
+// How CHIP-8 pixels are packed into terminal cells
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RenderMode {
+    // Pick the best mode that fits the terminal
+    Auto,
+    // One pixel per cell
+    Block,
+    // Two pixels per cell, stacked with upper half blocks
+    Half,
+    // 2x4 pixels per cell as braille dots, one color per cell
+    Braille,
+}
+
+// The picture to present, as one color per pixel
+pub struct Image {
+    width: usize,
+    height: usize,
+    pixels: Vec<Color>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Cell {
+    pub symbol: char,
+    pub fg: Color,
+    pub bg: Color,
+}
+
+pub struct CellGrid {
+    width: usize,
+    height: usize,
+    cells: Vec<Cell>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize, fill: Color) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![fill; width * height],
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    // Pixels outside the image read as `outside`
+    pub fn get_or(&self, x: usize, y: usize, outside: Color) -> Color {
+        if x < self.width && y < self.height {
+            self.pixels[y * self.width + x]
+        } else {
+            outside
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> Color {
+        self.pixels[y * self.width + x]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, color: Color) {
+        self.pixels[y * self.width + x] = color;
+    }
+
+    // Each pixel becomes a `scale` x `scale` square
+    pub fn scaled(&self, scale: usize) -> Image {
+        let mut image = Image::new(self.width * scale, self.height * scale, Color::Reset);
+        for y in 0..image.height {
+            for x in 0..image.width {
+                image.set(x, y, self.get(x / scale, y / scale));
+            }
+        }
+        image
+    }
+}
+
+impl CellGrid {
+    // A grid of blank cells in the terminal's colors
+    pub fn new(width: usize, height: usize) -> Self {
+        let blank = Cell {
+            symbol: ' ',
+            fg: Color::Reset,
+            bg: Color::Reset,
+        };
+        Self {
+            width,
+            height,
+            cells: vec![blank; width * height],
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> Cell {
+        self.cells[y * self.width + x]
+    }
+
+    // Cells outside the grid are ignored
+    pub fn set(&mut self, x: usize, y: usize, cell: Cell) {
+        if x < self.width && y < self.height {
+            self.cells[y * self.width + x] = cell;
+        }
+    }
+
+    // Writes `text` from (x, y), cut off at the right edge
+    pub fn print(&mut self, x: usize, y: usize, text: &str, fg: Color, bg: Color) {
+        for (i, symbol) in text.chars().enumerate() {
+            self.set(x + i, y, Cell { symbol, fg, bg });
+        }
+    }
+
+    // Copies `grid` in with its top left corner at (x, y)
+    pub fn paste(&mut self, x: usize, y: usize, grid: &CellGrid) {
+        for row in 0..grid.height {
+            for col in 0..grid.width {
+                self.set(x + col, y + row, grid.get(col, row));
+            }
+        }
+    }
+}
+
+impl RenderMode {
+    // Pixels per cell horizontally and vertically
+    pub fn cell_size(&self) -> (usize, usize) {
+        match self {
+            RenderMode::Auto | RenderMode::Block => (1, 1),
+            RenderMode::Half => (1, 2),
+            RenderMode::Braille => (2, 4),
+        }
+    }
+
+    // Cells needed to show a width x height image
+    pub fn grid_size(&self, width: usize, height: usize) -> (usize, usize) {
+        let (cw, ch) = self.cell_size();
+        (width.div_ceil(cw), height.div_ceil(ch))
+    }
+
+    // Half blocks give square pixels, so prefer them and fall back to braille
+    // when the terminal is too small
+    pub fn resolve(&self, terminal: (u16, u16), width: usize, height: usize) -> RenderMode {
+        if *self != RenderMode::Auto {
+            return *self;
+        }
+
+        let (cols, rows) = RenderMode::Half.grid_size(width, height);
+        if cols <= terminal.0 as usize && rows <= terminal.1 as usize {
+            RenderMode::Half
+        } else {
+            RenderMode::Braille
+        }
+    }
+}
+
+// The resolved mode and the largest integer scale that fit a width x height
+// image in the terminal, or Err with the cells needed if it doesn't fit at all
+pub fn fit(
+    mode: RenderMode,
+    terminal: (u16, u16),
+    width: usize,
+    height: usize,
+) -> Result<(RenderMode, usize), (usize, usize)> {
+    let mode = mode.resolve(terminal, width, height);
+    let fits = |scale: usize| {
+        let (cols, rows) = mode.grid_size(width * scale, height * scale);
+        cols <= terminal.0 as usize && rows <= terminal.1 as usize
+    };
+
+    if !fits(1) {
+        return Err(mode.grid_size(width, height));
+    }
+    let mut scale = 1;
+    while fits(scale + 1) {
+        scale += 1;
+    }
+
+    Ok((mode, scale))
+}
+
+// `mode` must already be resolved
+pub fn render(image: &Image, mode: RenderMode, background: Color) -> CellGrid {
+    let (width, height) = mode.grid_size(image.width(), image.height());
+    let mut cells = Vec::with_capacity(width * height);
+
+    for row in 0..height {
+        for col in 0..width {
+            let cell = match mode {
+                RenderMode::Auto | RenderMode::Block => Cell {
+                    symbol: '█',
+                    fg: image.get(col, row),
+                    bg: Color::Reset,
+                },
+                RenderMode::Half => Cell {
+                    symbol: '▀',
+                    fg: image.get_or(col, row * 2, background),
+                    bg: image.get_or(col, row * 2 + 1, background),
+                },
+                RenderMode::Braille => braille_cell(image, col * 2, row * 4, background),
+            };
+            cells.push(cell);
+        }
+    }
+
+    CellGrid {
+        width,
+        height,
+        cells,
+    }
+}
+
+// Dot bits of a braille character, indexed by [y][x] within the cell
+const BRAILLE_DOTS: [[u32; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+
+fn braille_cell(image: &Image, x: usize, y: usize, background: Color) -> Cell {
+    let mut bits = 0;
+    let mut fg = background;
+
+    for (dy, row) in BRAILLE_DOTS.iter().enumerate() {
+        for (dx, bit) in row.iter().enumerate() {
+            let color = image.get_or(x + dx, y + dy, background);
+            if color != background {
+                // A cell can only have one color, so the first lit pixel picks it
+                if bits == 0 {
+                    fg = color;
+                }
+                bits |= bit;
+            }
+        }
+    }
+
+    Cell {
+        symbol: char::from_u32(0x2800 + bits).unwrap(),
+        fg,
+        bg: background,
+    }
+}
+
+// Writes cell grids centred in the terminal, only sending the cells that
+// changed since the last grid it presented
+pub struct Presenter {
+    previous: Option<CellGrid>,
+    // Terminal size the previous grid was centred in
+    terminal: (u16, u16),
+}
+
+impl Presenter {
+    pub fn new() -> Self {
+        Self {
+            previous: None,
+            terminal: (0, 0),
+        }
+    }
+
+    pub fn present(
+        &mut self,
+        out: &mut impl Write,
+        grid: CellGrid,
+        terminal: (u16, u16),
+    ) -> io::Result<()> {
+        // Terminals without synchronized updates ignore these
+        out.queue(terminal::BeginSynchronizedUpdate)?;
+
+        let previous = self.previous.as_ref().filter(|p| {
+            p.width == grid.width && p.height == grid.height && self.terminal == terminal
+        });
+        if previous.is_none() {
+            out.queue(terminal::Clear(terminal::ClearType::All))?;
+        }
+        let left = (terminal.0 as usize).saturating_sub(grid.width) / 2;
+        let top = (terminal.1 as usize).saturating_sub(grid.height) / 2;
+        let changed = |x: usize, y: usize| match previous {
+            Some(p) => p.get(x, y) != grid.get(x, y),
+            None => true,
+        };
+
+        let mut fg = None;
+        let mut bg = None;
+        for y in 0..grid.height {
+            let mut x = 0;
+            while x < grid.width {
+                if !changed(x, y) {
+                    x += 1;
+                    continue;
+                }
+
+                // One cursor move for each run of changed cells
+                out.queue(cursor::MoveTo((left + x) as u16, (top + y) as u16))?;
+                while x < grid.width && changed(x, y) {
+                    let cell = grid.get(x, y);
+                    if fg != Some(cell.fg) {
+                        out.queue(SetForegroundColor(cell.fg))?;
+                        fg = Some(cell.fg);
+                    }
+                    if bg != Some(cell.bg) {
+                        out.queue(SetBackgroundColor(cell.bg))?;
+                        bg = Some(cell.bg);
+                    }
+                    out.queue(Print(cell.symbol))?;
+                    x += 1;
+                }
+            }
+        }
+
+        out.queue(ResetColor)?;
+        out.queue(terminal::EndSynchronizedUpdate)?;
+        out.flush()?;
+
+        self.previous = Some(grid);
+        self.terminal = terminal;
+        Ok(())
+    }
+
+    // Replaces the picture with a centred message, wrapped at spaces when
+    // the terminal is too narrow for it
+    pub fn notice(
+        &mut self,
+        out: &mut impl Write,
+        message: &str,
+        terminal: (u16, u16),
+    ) -> io::Result<()> {
+        let width = terminal.0 as usize;
+        let mut lines: Vec<String> = Vec::new();
+        for word in message.split(' ') {
+            match lines.last_mut() {
+                Some(line) if line.chars().count() + 1 + word.chars().count() <= width => {
+                    line.push(' ');
+                    line.push_str(word);
+                }
+                _ => lines.push(word.chars().take(width).collect()),
+            }
+        }
+
+        out.queue(terminal::Clear(terminal::ClearType::All))?;
+        let top = (terminal.1 as usize).saturating_sub(lines.len()) / 2;
+        for (i, line) in lines.iter().enumerate() {
+            let left = width.saturating_sub(line.chars().count()) / 2;
+            out.queue(cursor::MoveTo(left as u16, (top + i) as u16))?;
+            out.queue(Print(line))?;
+        }
+        out.flush()?;
+
+        // The next picture has to be drawn in full
+        self.previous = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ON: Color = Color::White;
+    const OFF: Color = Color::Black;
+
+    #[test]
+    fn auto_prefers_half_blocks_when_they_fit() {
+        assert_eq!(
+            fit(RenderMode::Auto, (64, 16), 64, 32),
+            Ok((RenderMode::Half, 1))
+        );
+        assert_eq!(
+            fit(RenderMode::Auto, (80, 24), 64, 32),
+            Ok((RenderMode::Half, 1))
+        );
+        assert_eq!(
+            fit(RenderMode::Auto, (200, 60), 64, 32),
+            Ok((RenderMode::Half, 3))
+        );
+        assert_eq!(
+            fit(RenderMode::Auto, (63, 16), 64, 32),
+            Ok((RenderMode::Braille, 1))
+        );
+        assert_eq!(
+            fit(RenderMode::Auto, (40, 10), 64, 32),
+            Ok((RenderMode::Braille, 1))
+        );
+    }
+
+    #[test]
+    fn too_small_reports_the_cells_needed() {
+        assert_eq!(fit(RenderMode::Auto, (31, 8), 64, 32), Err((32, 8)));
+        assert_eq!(fit(RenderMode::Block, (64, 31), 64, 32), Err((64, 32)));
+        assert_eq!(fit(RenderMode::Half, (63, 16), 64, 32), Err((64, 16)));
+        assert_eq!(
+            fit(RenderMode::Braille, (32, 8), 64, 32),
+            Ok((RenderMode::Braille, 1))
+        );
+    }
+
+    #[test]
+    fn block_uses_a_cell_per_pixel() {
+        let mut image = Image::new(2, 1, OFF);
+        image.set(1, 0, ON);
+        let grid = render(&image, RenderMode::Block, OFF);
+
+        assert_eq!((grid.width(), grid.height()), (2, 1));
+        assert!(
+            grid.get(0, 0)
+                == Cell {
+                    symbol: '█',
+                    fg: OFF,
+                    bg: Color::Reset
+                }
+        );
+        assert!(
+            grid.get(1, 0)
+                == Cell {
+                    symbol: '█',
+                    fg: ON,
+                    bg: Color::Reset
+                }
+        );
+    }
+
+    #[test]
+    fn half_stacks_two_rows_and_pads_with_the_background() {
+        let mut image = Image::new(1, 3, OFF);
+        image.set(0, 1, ON);
+        image.set(0, 2, ON);
+        let grid = render(&image, RenderMode::Half, Color::Blue);
+
+        assert_eq!((grid.width(), grid.height()), (1, 2));
+        assert!(
+            grid.get(0, 0)
+                == Cell {
+                    symbol: '▀',
+                    fg: OFF,
+                    bg: ON
+                }
+        );
+        assert!(
+            grid.get(0, 1)
+                == Cell {
+                    symbol: '▀',
+                    fg: ON,
+                    bg: Color::Blue
+                }
+        );
+    }
+
+    #[test]
+    fn braille_packs_2x4_dots_in_the_first_lit_color() {
+        let mut image = Image::new(4, 4, OFF);
+        image.set(0, 0, ON);
+        image.set(1, 3, Color::Red);
+        let grid = render(&image, RenderMode::Braille, OFF);
+
+        assert_eq!((grid.width(), grid.height()), (2, 1));
+        assert!(
+            grid.get(0, 0)
+                == Cell {
+                    symbol: '\u{2881}',
+                    fg: ON,
+                    bg: OFF
+                }
+        );
+        assert!(
+            grid.get(1, 0)
+                == Cell {
+                    symbol: '\u{2800}',
+                    fg: OFF,
+                    bg: OFF
+                }
+        );
+    }
+
+    // Cursor moves in `out` as (column, row)
+    fn moves(out: &[u8]) -> Vec<(u16, u16)> {
+        let text = String::from_utf8_lossy(out);
+        text.split("\x1b[")
+            .filter_map(|seq| {
+                let (row, rest) = seq.split_once(';')?;
+                let (col, _) = rest.split_once('H')?;
+                Some((col.parse::<u16>().ok()? - 1, row.parse::<u16>().ok()? - 1))
+            })
+            .collect()
+    }
+
+    fn grid(symbols: [&str; 2]) -> CellGrid {
+        let mut grid = CellGrid::new(4, 2);
+        for (y, row) in symbols.iter().enumerate() {
+            grid.print(0, y, row, ON, OFF);
+        }
+        grid
+    }
+
+    #[test]
+    fn presenter_only_redraws_changed_runs() {
+        let clear = "\x1b[2J";
+        let mut presenter = Presenter::new();
+
+        let mut out = Vec::new();
+        presenter
+            .present(&mut out, grid(["abcd", "efgh"]), (10, 4))
+            .unwrap();
+        assert!(String::from_utf8_lossy(&out).contains(clear));
+        assert_eq!(moves(&out), [(3, 1), (3, 2)]);
+
+        let mut out = Vec::new();
+        presenter
+            .present(&mut out, grid(["aXYd", "Zfgh"]), (10, 4))
+            .unwrap();
+        let text = String::from_utf8_lossy(&out);
+        assert!(!text.contains(clear));
+        assert_eq!(moves(&out), [(4, 1), (3, 2)]);
+        assert!(text.contains("XY") && text.contains('Z') && !text.contains('a'));
+
+        let mut out = Vec::new();
+        presenter
+            .present(&mut out, grid(["aXYd", "Zfgh"]), (10, 4))
+            .unwrap();
+        assert!(moves(&out).is_empty());
+
+        // A resized terminal moves the picture, so it's drawn in full
+        let mut out = Vec::new();
+        presenter
+            .present(&mut out, grid(["aXYd", "Zfgh"]), (12, 4))
+            .unwrap();
+        assert!(String::from_utf8_lossy(&out).contains(clear));
+        assert_eq!(moves(&out), [(4, 1), (4, 2)]);
+    }
+}
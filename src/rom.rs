@@ -0,0 +1,80 @@
+use std::{fs, path::Path};
+
+pub struct Rom {
+    data: Vec<u8>,
+}
+
+impl Rom {
+    // Reads the whole file and checks that it fits in `capacity` bytes
+    pub fn load(path: &Path, capacity: usize) -> Result<Self, String> {
+        let data =
+            fs::read(path).map_err(|e| format!("Failed to read file {}: {}", path.display(), e))?;
+
+        Self::from_bytes(data, capacity)
+            .map_err(|err| format!("Bad ROM {}: {}", path.display(), err))
+    }
+
+    pub fn from_bytes(data: Vec<u8>, capacity: usize) -> Result<Self, String> {
+        if data.is_empty() {
+            return Err("file is empty".to_string());
+        }
+        let rom = Self { data };
+        rom.check_fits(capacity)?;
+
+        Ok(rom)
+    }
+
+    // Platforms and in-memory stacks can leave less room than the file was
+    // loaded with, so the resolved config's capacity is checked again
+    pub fn check_fits(&self, capacity: usize) -> Result<(), String> {
+        if self.data.len() > capacity {
+            return Err(format!(
+                "ROM is {} bytes but only {} bytes fit in memory",
+                self.data.len(),
+                capacity
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_empty_roms() {
+        let err = Rom::from_bytes(Vec::new(), 16).err().unwrap();
+        assert_eq!(err, "file is empty");
+    }
+
+    #[test]
+    fn rejects_roms_over_capacity() {
+        let err = Rom::from_bytes(vec![0; 17], 16).err().unwrap();
+        assert_eq!(err, "ROM is 17 bytes but only 16 bytes fit in memory");
+    }
+
+    #[test]
+    fn accepts_exactly_full_roms() {
+        let rom = Rom::from_bytes(vec![0xAB; 16], 16).unwrap();
+        assert_eq!(rom.bytes().len(), 16);
+        assert!(rom.check_fits(16).is_ok());
+        assert_eq!(
+            rom.check_fits(15),
+            Err("ROM is 16 bytes but only 15 bytes fit in memory".to_string())
+        );
+    }
+
+    #[test]
+    fn load_names_the_file() {
+        let path = std::env::temp_dir().join(format!("chip8-rom-{}.ch8", std::process::id()));
+        fs::write(&path, []).unwrap();
+        let err = Rom::load(&path, 16).err().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(err, format!("Bad ROM {}: file is empty", path.display()));
+    }
+}
@@ -0,0 +1,167 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use crossterm::style::Color;
+
+use crate::palette::to_rgb;
+use crate::render::Image;
+
+// Writes `image` with each pixel scaled up to `scale` x `scale`. The format
+// comes from the extension: .png, .ppm (color) or .pbm (black and white,
+// where any pixel that isn't `background` is black).
+pub fn save(path: &Path, image: &Image, background: Color, scale: usize) -> Result<(), String> {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase());
+    if !matches!(extension.as_deref(), Some("png" | "ppm" | "pbm")) {
+        return Err(format!(
+            "{} is not a .png, .ppm or .pbm file",
+            path.display()
+        ));
+    }
+    let image = image.scaled(scale.max(1));
+
+    let file =
+        File::create(path).map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+    let mut out = BufWriter::new(file);
+
+    let result = match extension.as_deref() {
+        Some("png") => write_png(&mut out, &image),
+        Some("ppm") => write_ppm(&mut out, &image),
+        _ => write_pbm(&mut out, &image, background),
+    };
+
+    result
+        .and_then(|()| out.flush().map_err(|e| e.to_string()))
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+pub fn rgb_bytes(image: &Image) -> Vec<u8> {
+    let mut data = Vec::with_capacity(image.width() * image.height() * 3);
+    for y in 0..image.height() {
+        for x in 0..image.width() {
+            let (r, g, b) = to_rgb(image.get(x, y));
+            data.extend([r, g, b]);
+        }
+    }
+    data
+}
+
+fn write_png(out: &mut impl Write, image: &Image) -> Result<(), String> {
+    let mut encoder = png::Encoder::new(out, image.width() as u32, image.height() as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+    writer
+        .write_image_data(&rgb_bytes(image))
+        .map_err(|e| e.to_string())
+}
+
+// Binary netpbm formats, which any image viewer or script can read
+fn write_ppm(out: &mut impl Write, image: &Image) -> Result<(), String> {
+    write!(out, "P6\n{} {}\n255\n", image.width(), image.height()).map_err(|e| e.to_string())?;
+    out.write_all(&rgb_bytes(image)).map_err(|e| e.to_string())
+}
+
+fn write_pbm(out: &mut impl Write, image: &Image, background: Color) -> Result<(), String> {
+    write!(out, "P4\n{} {}\n", image.width(), image.height()).map_err(|e| e.to_string())?;
+
+    // Rows are packed 8 pixels to a byte, most significant bit first, with 1 as black
+    for y in 0..image.height() {
+        let mut row = vec![0u8; image.width().div_ceil(8)];
+        for x in 0..image.width() {
+            if image.get(x, y) != background {
+                row[x / 8] |= 0x80 >> (x % 8);
+            }
+        }
+        out.write_all(&row).map_err(|e| e.to_string())?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    const OFF: Color = Color::Rgb { r: 0, g: 0, b: 0 };
+    const ON: Color = Color::Rgb {
+        r: 255,
+        g: 170,
+        b: 0,
+    };
+
+    // 9x2 so PBM rows take two bytes
+    fn image() -> Image {
+        let mut image = Image::new(9, 2, OFF);
+        image.set(0, 0, ON);
+        image.set(8, 0, ON);
+        image.set(1, 1, ON);
+        image
+    }
+
+    fn save_to(name: &str, scale: usize) -> Result<Vec<u8>, String> {
+        let path = std::env::temp_dir().join(format!("chip8-{}-{}", std::process::id(), name));
+        let result = save(&path, &image(), OFF, scale).map(|()| fs::read(&path).unwrap());
+        let _ = fs::remove_file(&path);
+        result
+    }
+
+    #[test]
+    fn ppm_has_a_header_and_rgb_pixels() {
+        let data = save_to("shot.ppm", 1).unwrap();
+        let header = b"P6\n9 2\n255\n";
+        assert_eq!(&data[..header.len()], header);
+        let pixels = &data[header.len()..];
+        assert_eq!(pixels.len(), 9 * 2 * 3);
+        assert_eq!(pixels[..6], [255, 170, 0, 0, 0, 0]);
+        assert_eq!(pixels[8 * 3..9 * 3], [255, 170, 0]);
+        assert_eq!(pixels[10 * 3..11 * 3], [255, 170, 0]);
+    }
+
+    #[test]
+    fn pbm_packs_rows_into_bits() {
+        let data = save_to("shot.pbm", 1).unwrap();
+        assert_eq!(data, b"P4\n9 2\n\x80\x80\x40\x00");
+    }
+
+    #[test]
+    fn scale_repeats_pixels() {
+        let data = save_to("big.pbm", 2).unwrap();
+        let rows = [
+            [0xC0, 0x00, 0xC0],
+            [0xC0, 0x00, 0xC0],
+            [0x30, 0, 0],
+            [0x30, 0, 0],
+        ];
+        assert_eq!(
+            data,
+            [b"P4\n18 4\n".as_slice(), rows.as_flattened()].concat()
+        );
+    }
+
+    #[test]
+    fn png_has_the_scaled_size() {
+        let data = save_to("shot.png", 3).unwrap();
+        assert_eq!(data[..8], *b"\x89PNG\r\n\x1a\n");
+        assert_eq!(data[12..16], *b"IHDR");
+        assert_eq!(data[16..24], [0, 0, 0, 27, 0, 0, 0, 6]);
+    }
+
+    #[test]
+    fn other_extensions_are_refused() {
+        let err = save_to("shot.jpg", 1).err().unwrap();
+        assert!(
+            err.ends_with("shot.jpg is not a .png, .ppm or .pbm file"),
+            "{}",
+            err
+        );
+    }
+}
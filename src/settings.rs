@@ -0,0 +1,583 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::chip8::{MEMORY_SIZE, PROGRAM_START};
+use crate::config::Config;
+use crate::database::{Database, Match, PlatformInfo};
+use crate::filter::FilterMode;
+use crate::keymap::{Hotkey, Hotkeys, Keymap, SCREENSHOT_KEY, parse_key_name};
+use crate::palette::{Palette, parse_color};
+use crate::platform::Platform;
+use crate::render::RenderMode;
+use crate::stack::VIP_STACK_END;
+
+// One layer of settings from a config file, the ROM database or the command line.
+// Values left unset fall through to the layer below.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub platform: Option<Platform>,
+    pub cpu_hz: Option<u32>,
+    pub quirks: QuirkSettings,
+    pub colors: ColorSettings,
+    pub audio: AudioSettings,
+    pub display: DisplaySettings,
+    // Keyboard key name -> CHIP-8 key, added on top of the hex keypad
+    pub keys: BTreeMap<String, u8>,
+    pub hotkeys: HotkeySettings,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuirkSettings {
+    pub bxnn: Option<bool>,
+    pub shift_in_place_8xy: Option<bool>,
+    pub increment_i_on_mem: Option<bool>,
+    pub fx1e_overflow: Option<bool>,
+    pub skip_stack_underflow: Option<bool>,
+    pub skip_bad_opcodes: Option<bool>,
+    // Most return addresses the stack holds, 0 for no limit
+    pub stack_depth: Option<u32>,
+    // Keep the stack in memory where the COSMAC VIP has it
+    pub stack_in_memory: Option<bool>,
+}
+
+// `pixels` replaces the palette's colors, then `on` and `off` replace the first two
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ColorSettings {
+    pub palette: Option<String>,
+    pub pixels: Option<Vec<String>>,
+    pub on: Option<String>,
+    pub off: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioSettings {
+    pub mute: Option<bool>,
+    pub volume: Option<f32>,
+    pub tone_hz: Option<f32>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct DisplaySettings {
+    pub render: Option<RenderMode>,
+    pub filter: Option<FilterMode>,
+    // How many frames the filter blends, holds or fades over
+    pub filter_frames: Option<u32>,
+    // Each pixel is saved as a square this many pixels wide
+    pub screenshot_scale: Option<u32>,
+}
+
+// Keyboard key names for the hotkeys, or "none" to leave one unbound
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct HotkeySettings {
+    pub pause: Option<String>,
+    pub reset: Option<String>,
+    pub faster: Option<String>,
+    pub slower: Option<String>,
+    pub mute: Option<String>,
+    pub fast_forward: Option<String>,
+    pub quit: Option<String>,
+}
+
+pub fn global_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("chip8-interpreter").join("config.toml"))
+}
+
+// The ROM's file name with `.toml` added, so ROMs that only differ in
+// extension get their own sidecars
+pub fn sidecar_path(rom: &Path) -> PathBuf {
+    let mut name = rom.as_os_str().to_owned();
+    name.push(".toml");
+    PathBuf::from(name)
+}
+
+// CLI > sidecar > ROM database > global > defaults
+pub fn resolve_settings(
+    db: &Database,
+    found: Option<&Match>,
+    global: Option<Settings>,
+    sidecar: Option<Settings>,
+    cli: Settings,
+) -> Settings {
+    let layers = [
+        global,
+        found.map(Settings::from_database),
+        sidecar,
+        Some(cli),
+    ];
+    Settings::resolve(db, found, layers.into_iter().flatten().collect())
+}
+
+// Reads the global config, or `config` in its place, and the ROM's sidecar,
+// so every command layers the same files under `cli`
+pub fn load_and_resolve(
+    db: &Database,
+    found: Option<&Match>,
+    config: Option<&Path>,
+    rom: Option<&Path>,
+    cli: Settings,
+) -> Result<Settings, String> {
+    let load = |path: Option<PathBuf>| match path {
+        Some(path) => Settings::load(&path),
+        None => Ok(None),
+    };
+    let global = load(config.map(Path::to_path_buf).or_else(global_path))?;
+    let sidecar = load(rom.map(sidecar_path))?;
+    Ok(resolve_settings(db, found, global, sidecar, cli))
+}
+
+impl Settings {
+    pub fn defaults() -> Self {
+        Self {
+            platform: None,
+            cpu_hz: Some(500),
+            quirks: QuirkSettings {
+                bxnn: Some(false),
+                shift_in_place_8xy: Some(false),
+                increment_i_on_mem: Some(false),
+                fx1e_overflow: Some(false),
+                skip_stack_underflow: Some(false),
+                skip_bad_opcodes: Some(false),
+                stack_depth: Some(16),
+                stack_in_memory: Some(false),
+            },
+            colors: ColorSettings {
+                palette: Some("classic".to_string()),
+                ..Default::default()
+            },
+            audio: AudioSettings {
+                mute: Some(false),
+                volume: Some(0.2),
+                tone_hz: Some(440.0),
+            },
+            display: DisplaySettings {
+                render: Some(RenderMode::Auto),
+                filter: Some(FilterMode::None),
+                filter_frames: Some(3),
+                screenshot_scale: Some(1),
+            },
+            keys: BTreeMap::new(),
+            hotkeys: HotkeySettings {
+                pause: Some("f5".to_string()),
+                reset: Some("f8".to_string()),
+                faster: Some("f3".to_string()),
+                slower: Some("f2".to_string()),
+                mute: Some("f4".to_string()),
+                fast_forward: Some("f6".to_string()),
+                quit: Some("esc".to_string()),
+            },
+        }
+    }
+
+    // Returns None if there is no file at `path`
+    pub fn load(path: &Path) -> Result<Option<Self>, String> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("Failed to read config {}: {}", path.display(), e)),
+        };
+
+        toml::from_str(&text)
+            .map(Some)
+            .map_err(|e| format!("Bad config {}: {}", path.display(), e))
+    }
+
+    pub fn from_database(found: &Match) -> Self {
+        let mut settings = Self {
+            platform: found.platform.and_then(|p| Platform::from_id(&p.id)),
+            cpu_hz: found.rom.tickrate.map(|t| t * 60),
+            keys: found.keys(),
+            ..Default::default()
+        };
+
+        if let Some(pixels) = found.pixel_colors()
+            && Palette::from_names(pixels).is_ok()
+        {
+            settings.colors.pixels = Some(pixels.to_vec());
+        }
+
+        settings
+    }
+
+    fn from_platform(platform: &PlatformInfo, found: Option<&Match>) -> Self {
+        let quirks = match found {
+            Some(f) => f.quirks_on(platform),
+            None => platform.quirks,
+        };
+        let tickrate = found
+            .and_then(|f| f.rom.tickrate)
+            .unwrap_or(platform.default_tickrate);
+
+        Self {
+            cpu_hz: Some(tickrate * 60),
+            quirks: QuirkSettings {
+                bxnn: Some(quirks.jump),
+                shift_in_place_8xy: Some(quirks.shift),
+                increment_i_on_mem: Some(
+                    !quirks.memory_leave_i_unchanged && !quirks.memory_increment_by_x,
+                ),
+                stack_depth: Platform::from_id(&platform.id).map(|p| p.stack_depth()),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    // Values set in `over` win
+    pub fn merge(self, over: Self) -> Self {
+        let mut keys = self.keys;
+        keys.extend(over.keys);
+
+        Self {
+            platform: over.platform.or(self.platform),
+            cpu_hz: over.cpu_hz.or(self.cpu_hz),
+            quirks: QuirkSettings {
+                bxnn: over.quirks.bxnn.or(self.quirks.bxnn),
+                shift_in_place_8xy: over
+                    .quirks
+                    .shift_in_place_8xy
+                    .or(self.quirks.shift_in_place_8xy),
+                increment_i_on_mem: over
+                    .quirks
+                    .increment_i_on_mem
+                    .or(self.quirks.increment_i_on_mem),
+                fx1e_overflow: over.quirks.fx1e_overflow.or(self.quirks.fx1e_overflow),
+                skip_stack_underflow: over
+                    .quirks
+                    .skip_stack_underflow
+                    .or(self.quirks.skip_stack_underflow),
+                skip_bad_opcodes: over
+                    .quirks
+                    .skip_bad_opcodes
+                    .or(self.quirks.skip_bad_opcodes),
+                stack_depth: over.quirks.stack_depth.or(self.quirks.stack_depth),
+                stack_in_memory: over.quirks.stack_in_memory.or(self.quirks.stack_in_memory),
+            },
+            // Picking a palette starts the colors over
+            colors: if over.colors.palette.is_some() || over.colors.pixels.is_some() {
+                over.colors
+            } else {
+                ColorSettings {
+                    palette: self.colors.palette,
+                    pixels: self.colors.pixels,
+                    on: over.colors.on.or(self.colors.on),
+                    off: over.colors.off.or(self.colors.off),
+                }
+            },
+            audio: AudioSettings {
+                mute: over.audio.mute.or(self.audio.mute),
+                volume: over.audio.volume.or(self.audio.volume),
+                tone_hz: over.audio.tone_hz.or(self.audio.tone_hz),
+            },
+            display: DisplaySettings {
+                render: over.display.render.or(self.display.render),
+                filter: over.display.filter.or(self.display.filter),
+                filter_frames: over.display.filter_frames.or(self.display.filter_frames),
+                screenshot_scale: over
+                    .display
+                    .screenshot_scale
+                    .or(self.display.screenshot_scale),
+            },
+            keys,
+            hotkeys: HotkeySettings {
+                pause: over.hotkeys.pause.or(self.hotkeys.pause),
+                reset: over.hotkeys.reset.or(self.hotkeys.reset),
+                faster: over.hotkeys.faster.or(self.hotkeys.faster),
+                slower: over.hotkeys.slower.or(self.hotkeys.slower),
+                mute: over.hotkeys.mute.or(self.hotkeys.mute),
+                fast_forward: over.hotkeys.fast_forward.or(self.hotkeys.fast_forward),
+                quit: over.hotkeys.quit.or(self.hotkeys.quit),
+            },
+        }
+    }
+
+    // Stacks `layers` (lowest precedence first) on top of the defaults.
+    // Choosing a platform in a layer brings in that platform's quirks and speed
+    // at the same precedence.
+    pub fn resolve(db: &Database, found: Option<&Match>, layers: Vec<Settings>) -> Self {
+        let mut settings = Self::defaults();
+
+        for layer in layers {
+            if let Some(platform) = layer.platform.and_then(|p| db.platform(p)) {
+                settings = settings.merge(Self::from_platform(platform, found));
+            }
+            settings = settings.merge(layer);
+        }
+
+        settings
+    }
+
+    // Only call on resolved settings, which have every value set
+    pub fn to_config(&self) -> Result<Config, String> {
+        let color =
+            |name: &str| parse_color(name).ok_or_else(|| format!("Unknown color '{}'", name));
+
+        let mut palette = match (&self.colors.pixels, &self.colors.palette) {
+            (Some(pixels), _) => Palette::from_names(pixels)?,
+            (None, Some(name)) => {
+                Palette::builtin(name).ok_or_else(|| format!("Unknown palette '{}'", name))?
+            }
+            (None, None) => Palette::default(),
+        };
+        if let Some(on) = &self.colors.on {
+            palette.set_on(color(on)?);
+        }
+        if let Some(off) = &self.colors.off {
+            palette.set_off(color(off)?);
+        }
+
+        let mut keymap = Keymap::default();
+        for (name, key) in &self.keys {
+            let code = parse_key_name(name).ok_or_else(|| format!("Unknown key '{}'", name))?;
+            if code == SCREENSHOT_KEY {
+                return Err(format!(
+                    "Key '{}' saves screenshots, so it can't be on the keypad",
+                    name
+                ));
+            }
+            if *key > 0xF {
+                return Err(format!("Key '{}' is bound to {}, not 0-15", name, key));
+            }
+            keymap.bind(code, *key);
+        }
+
+        let mut hotkeys = Hotkeys::default();
+        let bindings = [
+            ("pause", &self.hotkeys.pause, Hotkey::Pause),
+            ("reset", &self.hotkeys.reset, Hotkey::Reset),
+            ("faster", &self.hotkeys.faster, Hotkey::Faster),
+            ("slower", &self.hotkeys.slower, Hotkey::Slower),
+            ("mute", &self.hotkeys.mute, Hotkey::Mute),
+            (
+                "fast_forward",
+                &self.hotkeys.fast_forward,
+                Hotkey::FastForward,
+            ),
+            ("quit", &self.hotkeys.quit, Hotkey::Quit),
+        ];
+        for (action, name, hotkey) in bindings {
+            let Some(name) = name.as_deref().filter(|&name| name != "none") else {
+                continue;
+            };
+            let code = parse_key_name(name).ok_or_else(|| format!("Unknown key '{}'", name))?;
+            if code == SCREENSHOT_KEY {
+                return Err(format!(
+                    "Key '{}' saves screenshots, so it can't be the {} hotkey",
+                    name, action
+                ));
+            }
+            if keymap.key_for(code).is_some() {
+                return Err(format!(
+                    "Key '{}' is on the keypad, so it can't be the {} hotkey",
+                    name, action
+                ));
+            }
+            if hotkeys.hotkey_for(code).is_some() {
+                return Err(format!("Key '{}' is bound to more than one hotkey", name));
+            }
+            hotkeys.bind(code, hotkey);
+        }
+
+        let volume = self.audio.volume.unwrap_or_default();
+        if !(0.0..=1.0).contains(&volume) {
+            return Err(format!("Volume {} is not between 0 and 1", volume));
+        }
+
+        let cpu_hz = self.cpu_hz.unwrap_or_default();
+        if cpu_hz == 0 {
+            return Err("cpu_hz must be above 0".to_string());
+        }
+
+        let filter_frames = self.display.filter_frames.unwrap_or_default();
+        if !(1..=60).contains(&filter_frames) {
+            return Err(format!(
+                "filter_frames {} is not between 1 and 60",
+                filter_frames
+            ));
+        }
+
+        let screenshot_scale = self.display.screenshot_scale.unwrap_or_default();
+        if !(1..=64).contains(&screenshot_scale) {
+            return Err(format!(
+                "screenshot_scale {} is not between 1 and 64",
+                screenshot_scale
+            ));
+        }
+
+        let stack_depth = self.quirks.stack_depth.unwrap_or(16) as usize;
+        let stack_in_memory = self.quirks.stack_in_memory.unwrap_or_default();
+        if stack_in_memory && stack_depth == 0 {
+            return Err("stack_in_memory needs a stack_depth above 0".to_string());
+        }
+        if stack_in_memory && stack_depth * 2 > VIP_STACK_END - PROGRAM_START {
+            return Err(format!(
+                "stack_depth {} is too deep to keep in memory",
+                stack_depth
+            ));
+        }
+
+        let mut program_end = self.platform.map_or(MEMORY_SIZE, |p| p.memory_end());
+        if stack_in_memory {
+            program_end = program_end.min(VIP_STACK_END - stack_depth * 2);
+        }
+
+        Ok(Config {
+            skip_stack_underflow: self.quirks.skip_stack_underflow.unwrap_or_default(),
+            stack_depth: (stack_depth > 0).then_some(stack_depth),
+            stack_in_memory,
+            rom_capacity: program_end - PROGRAM_START,
+            bxnn: self.quirks.bxnn.unwrap_or_default(),
+            fx1e_overflow: self.quirks.fx1e_overflow.unwrap_or_default(),
+            shift_in_place_8xy: self.quirks.shift_in_place_8xy.unwrap_or_default(),
+            increment_i_on_mem: self.quirks.increment_i_on_mem.unwrap_or_default(),
+            skip_bad_opcodes: self.quirks.skip_bad_opcodes.unwrap_or_default(),
+            cpu_hz,
+            mute: self.audio.mute.unwrap_or_default(),
+            volume,
+            tone_hz: self.audio.tone_hz.unwrap_or_default(),
+            palette,
+            keymap,
+            hotkeys,
+            render_mode: self.display.render.unwrap_or(RenderMode::Auto),
+            filter: self.display.filter.unwrap_or(FilterMode::None),
+            filter_frames: filter_frames as usize,
+            screenshot_scale: screenshot_scale as usize,
+        })
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).expect("Settings always serialize")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_pause(key: &str) -> Settings {
+        let mut settings = Settings::defaults();
+        settings.hotkeys.pause = Some(key.to_string());
+        settings
+    }
+
+    #[test]
+    fn hotkeys_cant_take_reserved_keys() {
+        assert!(with_pause("f9").to_config().is_ok());
+        assert!(with_pause("none").to_config().is_ok());
+        for key in ["f12", "c", "f8"] {
+            assert!(with_pause(key).to_config().is_err(), "{}", key);
+        }
+    }
+
+    #[test]
+    fn keypad_cant_take_the_screenshot_key() {
+        let mut settings = Settings::defaults();
+        settings.keys.insert("f12".to_string(), 1);
+        assert!(settings.to_config().is_err());
+    }
+
+    #[test]
+    fn rom_capacity_follows_platform_and_stack() {
+        let mut settings = Settings::defaults();
+        assert_eq!(settings.to_config().unwrap().rom_capacity, 0xE00);
+        settings.platform = Some(Platform::OriginalChip8);
+        assert_eq!(settings.to_config().unwrap().rom_capacity, 0xEA0 - 0x200);
+        settings.platform = Some(Platform::ModernChip8);
+        settings.quirks.stack_in_memory = Some(true);
+        assert_eq!(
+            settings.to_config().unwrap().rom_capacity,
+            VIP_STACK_END - 32 - 0x200
+        );
+    }
+
+    fn cpu_hz(cpu_hz: u32) -> Settings {
+        Settings {
+            cpu_hz: Some(cpu_hz),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn later_layers_win() {
+        let db = Database::embedded();
+        let resolve = |global, sidecar, cli| resolve_settings(&db, None, global, sidecar, cli);
+
+        let all = resolve(Some(cpu_hz(100)), Some(cpu_hz(200)), cpu_hz(300));
+        assert_eq!(all.cpu_hz, Some(300));
+        let no_cli = resolve(Some(cpu_hz(100)), Some(cpu_hz(200)), Settings::default());
+        assert_eq!(no_cli.cpu_hz, Some(200));
+        let global_only = resolve(Some(cpu_hz(100)), None, Settings::default());
+        assert_eq!(global_only.cpu_hz, Some(100));
+        let none = resolve(None, None, Settings::default());
+        assert_eq!(none.cpu_hz, Settings::defaults().cpu_hz);
+    }
+
+    #[test]
+    fn platform_applies_at_its_layer() {
+        let db = Database::embedded();
+        let platform = |platform| Settings {
+            platform: Some(platform),
+            ..Default::default()
+        };
+        let no_jump = || Settings {
+            quirks: QuirkSettings {
+                bxnn: Some(false),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        // A sidecar quirk wins over the global config's platform
+        let settings = resolve_settings(
+            &db,
+            None,
+            Some(platform(Platform::Chip48)),
+            Some(no_jump()),
+            Settings::default(),
+        );
+        assert_eq!(settings.quirks.bxnn, Some(false));
+
+        // and a platform on the command line brings its quirks back
+        let settings =
+            resolve_settings(&db, None, None, Some(no_jump()), platform(Platform::Chip48));
+        assert_eq!(settings.quirks.bxnn, Some(true));
+    }
+
+    #[test]
+    fn files_are_layered_under_the_command_line() {
+        let dir = std::env::temp_dir().join(format!("chip8-settings-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let rom = dir.join("game.ch8");
+        let config = dir.join("config.toml");
+        fs::write(&config, "cpu_hz = 100\n[audio]\nvolume = 0.5\n").unwrap();
+        fs::write(sidecar_path(&rom), "cpu_hz = 200\n").unwrap();
+
+        let db = Database::embedded();
+        let resolve =
+            |rom: Option<&Path>, cli| load_and_resolve(&db, None, Some(&config), rom, cli).unwrap();
+        let with_sidecar = resolve(Some(&rom), Settings::default());
+        let no_sidecar = resolve(None, Settings::default());
+        let with_cli = resolve(Some(&rom), cpu_hz(300));
+        let missing = load_and_resolve(&db, None, Some(&dir.join("none.toml")), None, cpu_hz(1));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(with_sidecar.cpu_hz, Some(200));
+        assert_eq!(with_sidecar.audio.volume, Some(0.5));
+        assert_eq!(no_sidecar.cpu_hz, Some(100));
+        assert_eq!(with_cli.cpu_hz, Some(300));
+        assert_eq!(
+            missing.unwrap().audio.volume,
+            Settings::defaults().audio.volume
+        );
+    }
+}
@@ -1,36 +1,125 @@
+// The COSMAC VIP's stack grows down from here, each entry high byte first
+pub const VIP_STACK_END: usize = 0xED0;
+
 pub struct Stack {
-    sp: u8,
-    stack: [u16; 16],
+    // Most return addresses it holds, or None for no limit
+    depth: Option<usize>,
+    // Keeps the entries in emulated memory below VIP_STACK_END, where
+    // programs can see them, instead of in `entries`
+    in_memory: bool,
+    sp: usize,
+    entries: Vec<u16>,
 }
 
 impl Stack {
-    pub fn new() -> Self {
+    pub fn new(depth: Option<usize>, in_memory: bool) -> Self {
         Self {
+            depth,
+            in_memory,
             sp: 0,
-            stack: [0; 16],
+            entries: Vec::new(),
         }
     }
 
-    pub fn subroutine(&mut self, pc: u16) -> Result<(), &'static str> {
-        self.stack[self.sp as usize] = pc;
-        self.sp += 1;
+    // Where entry `index` is kept in memory
+    fn slot(index: usize) -> usize {
+        VIP_STACK_END - 2 * (index + 1)
+    }
 
-        if self.sp > 15 {
+    pub fn subroutine(&mut self, pc: u16, memory: &mut [u8]) -> Result<(), &'static str> {
+        if self.depth.is_some_and(|depth| self.sp >= depth) {
             return Err("Stack overflow");
         }
 
+        if self.in_memory {
+            let slot = Self::slot(self.sp);
+            memory[slot..slot + 2].copy_from_slice(&pc.to_be_bytes());
+        } else {
+            self.entries.push(pc);
+        }
+        self.sp += 1;
+
         Ok(())
     }
 
-    pub fn return_subroutine(&mut self) -> Result<u16, &'static str> {
+    pub fn return_subroutine(&mut self, memory: &[u8]) -> Result<u16, &'static str> {
         if self.sp == 0 {
             return Err("Stack underflow");
         }
 
         self.sp -= 1;
-        let last_addr = self.stack[self.sp as usize];
-        self.stack[self.sp as usize] = 0;
+        if self.in_memory {
+            let slot = Self::slot(self.sp);
+            Ok(u16::from_be_bytes([memory[slot], memory[slot + 1]]))
+        } else {
+            Ok(self.entries.pop().unwrap_or_default())
+        }
+    }
+
+    // How many return addresses are on the stack
+    pub fn sp(&self) -> usize {
+        self.sp
+    }
+
+    // Return addresses, oldest first
+    pub fn entries(&self, memory: &[u8]) -> Vec<u16> {
+        if self.in_memory {
+            (0..self.sp)
+                .map(|index| {
+                    let slot = Self::slot(index);
+                    u16::from_be_bytes([memory[slot], memory[slot + 1]])
+                })
+                .collect()
+        } else {
+            self.entries.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn depth_limits_calls() {
+        for in_memory in [false, true] {
+            let mut memory = [0; 4096];
+            let mut stack = Stack::new(Some(12), in_memory);
+            for call in 0..12 {
+                stack.subroutine(0x200 + call, &mut memory).unwrap();
+            }
+            assert_eq!(stack.subroutine(0x300, &mut memory), Err("Stack overflow"));
+            assert_eq!(stack.sp(), 12);
+        }
+    }
+
+    #[test]
+    fn unlimited_depth_never_overflows() {
+        let mut memory = [0; 4096];
+        let mut stack = Stack::new(None, false);
+        for call in 0..10_000 {
+            stack.subroutine(call as u16, &mut memory).unwrap();
+        }
+        assert_eq!(stack.return_subroutine(&memory), Ok(9_999));
+        assert_eq!(stack.sp(), 9_999);
+    }
+
+    #[test]
+    fn in_memory_entries_are_high_byte_first_below_the_end() {
+        let mut memory = [0; 4096];
+        let mut stack = Stack::new(Some(16), true);
+        stack.subroutine(0x234, &mut memory).unwrap();
+        stack.subroutine(0x5A6, &mut memory).unwrap();
+
+        assert_eq!(memory[VIP_STACK_END - 2..VIP_STACK_END], [0x02, 0x34]);
+        assert_eq!(memory[VIP_STACK_END - 4..VIP_STACK_END - 2], [0x05, 0xA6]);
+        assert_eq!(memory[VIP_STACK_END], 0);
+        assert_eq!(stack.entries(&memory), [0x234, 0x5A6]);
 
-        Ok(last_addr)
+        // Programs can rewrite return addresses
+        memory[VIP_STACK_END - 3] = 0xB8;
+        assert_eq!(stack.return_subroutine(&memory), Ok(0x5B8));
+        assert_eq!(stack.return_subroutine(&memory), Ok(0x234));
+        assert_eq!(stack.return_subroutine(&memory), Err("Stack underflow"));
     }
 }
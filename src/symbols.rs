@@ -0,0 +1,178 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::Path,
+};
+
+use crate::chip8::{MEMORY_SIZE, PROGRAM_START};
+use crate::disasm;
+
+// Labels for addresses, from a symbol file
+#[derive(Clone, Default)]
+pub struct Symbols {
+    names: BTreeMap<u16, String>,
+    addresses: HashMap<String, u16>,
+}
+
+impl Symbols {
+    // JSON files hold an object of names to addresses. Other files have an
+    // address and a name on each line, with # or ; comments. The name can
+    // come first when the address has a 0x or $ prefix.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let bad = |e: String| format!("Bad symbol file {}: {}", path.display(), e);
+
+        if text.trim_start().starts_with('{') {
+            Self::from_json(&text).map_err(bad)
+        } else {
+            Self::from_text(&text).map_err(bad)
+        }
+    }
+
+    fn from_json(text: &str) -> Result<Self, String> {
+        let labels: BTreeMap<String, serde_json::Value> =
+            serde_json::from_str(text).map_err(|e| e.to_string())?;
+
+        let mut symbols = Self::default();
+        for (name, value) in labels {
+            let address = match &value {
+                serde_json::Value::Number(n) => n.as_u64().and_then(|n| u16::try_from(n).ok()),
+                serde_json::Value::String(s) => parse_hex(s),
+                _ => None,
+            };
+            match address {
+                Some(address) => symbols.add(name, address)?,
+                None => return Err(format!("'{}' has a bad address {}", name, value)),
+            }
+        }
+        Ok(symbols)
+    }
+
+    pub fn from_text(text: &str) -> Result<Self, String> {
+        let mut symbols = Self::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split(['#', ';']).next().unwrap_or_default();
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let label = match fields[..] {
+                [] => continue,
+                // Names like "add" read as hex too, so a name only comes
+                // first before a prefixed address
+                [a, b] => match (parse_prefixed_hex(a), parse_prefixed_hex(b)) {
+                    (None, Some(address)) => Some((a, address)),
+                    _ => parse_hex(a).map(|address| (b, address)),
+                },
+                _ => None,
+            };
+            let Some((name, address)) = label else {
+                return Err(format!(
+                    "line {} isn't an address and a name: '{}'",
+                    number + 1,
+                    line.trim()
+                ));
+            };
+            symbols.add(name.to_string(), address)?;
+        }
+        Ok(symbols)
+    }
+
+    fn add(&mut self, name: String, address: u16) -> Result<(), String> {
+        if address as usize >= MEMORY_SIZE {
+            return Err(format!("'{}' is past the end of memory", name));
+        }
+        // The first name for an address is the one shown
+        self.names.entry(address).or_insert_with(|| name.clone());
+        self.addresses.insert(name, address);
+        Ok(())
+    }
+
+    pub fn name(&self, address: u16) -> Option<&str> {
+        self.names.get(&address).map(String::as_str)
+    }
+
+    // "draw" or "draw+4", from the closest label at or before `address`
+    pub fn label(&self, address: u16) -> Option<String> {
+        let (&start, name) = self.names.range(..=address).next_back()?;
+        Some(match address - start {
+            0 => name.clone(),
+            offset => format!("{}+{}", name, offset),
+        })
+    }
+
+    // A label, or "main" for the start of the program and "sub_2A0" for
+    // other routines
+    pub fn routine_name(&self, address: u16) -> String {
+        match self.name(address) {
+            Some(name) => name.to_string(),
+            None if address == PROGRAM_START as u16 => "main".to_string(),
+            None => format!("sub_{:03X}", address),
+        }
+    }
+
+    // disasm::mnemonic, with addresses that have labels shown as the label
+    pub fn mnemonic(&self, opcode: u16, bxnn: bool) -> Option<String> {
+        let mnemonic = disasm::mnemonic(opcode, bxnn)?;
+        // As BXNN, the low bits are an offset from VX rather than an address
+        let addressed = match disasm::pattern(opcode) {
+            Some("1NNN" | "2NNN" | "ANNN") => true,
+            Some("BNNN") => !bxnn,
+            _ => false,
+        };
+        let nnn = opcode & 0x0FFF;
+        let operands = mnemonic.strip_suffix(&format!("{:03X}", nnn));
+        match (self.name(nnn), operands) {
+            (Some(name), Some(operands)) if addressed => Some(format!("{}{}", operands, name)),
+            _ => Some(mnemonic),
+        }
+    }
+
+    // A hex address like 2A0, or a label as @name
+    pub fn parse_address(&self, text: &str) -> Option<u16> {
+        match text.strip_prefix('@') {
+            Some(name) => self.addresses.get(name).copied(),
+            None => parse_hex(text).filter(|&a| (a as usize) < MEMORY_SIZE),
+        }
+    }
+}
+
+fn parse_hex(text: &str) -> Option<u16> {
+    parse_prefixed_hex(text).or_else(|| u16::from_str_radix(text, 16).ok())
+}
+
+// Only hex with a 0x or $ in front
+fn parse_prefixed_hex(text: &str) -> Option<u16> {
+    let digits = text.strip_prefix("0x").or_else(|| text.strip_prefix('$'))?;
+    u16::from_str_radix(digits, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_lines_take_either_order() {
+        let symbols =
+            Symbols::from_text("200 main\n0x24A draw ; player\nadd 0x2A0\nface $2B0\n").unwrap();
+        assert_eq!(symbols.name(0x200), Some("main"));
+        assert_eq!(symbols.name(0x24A), Some("draw"));
+        assert_eq!(symbols.name(0x2A0), Some("add"));
+        assert_eq!(symbols.name(0x2B0), Some("face"));
+    }
+
+    #[test]
+    fn unprefixed_address_has_to_come_first() {
+        assert!(Symbols::from_text("main 200\n").is_err());
+        // 0xFACE is past the end of memory
+        assert!(Symbols::from_text("face 2A0\n").is_err());
+    }
+
+    #[test]
+    fn mnemonics_show_labels_for_addresses() {
+        let symbols = Symbols::from_text("2A0 draw\n").unwrap();
+        assert_eq!(symbols.mnemonic(0x22A0, false).unwrap(), "CALL draw");
+        assert_eq!(symbols.mnemonic(0xA2A0, false).unwrap(), "LD I, draw");
+        assert_eq!(symbols.mnemonic(0xB2A0, false).unwrap(), "JP V0, draw");
+        assert_eq!(symbols.mnemonic(0xB2A0, true).unwrap(), "JP V2, 2A0");
+        assert_eq!(symbols.mnemonic(0x62A0, false).unwrap(), "LD V2, A0");
+    }
+}
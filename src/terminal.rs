@@ -0,0 +1,104 @@
+use std::{
+    io,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crossterm::{
+    cursor,
+    event::{KeyboardEnhancementFlags, PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags},
+    execute,
+    style::ResetColor,
+    terminal::{self, EnterAlternateScreen, LeaveAlternateScreen},
+};
+
+// Whether the terminal is currently set up for the interpreter
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+// Set by signals and Ctrl-C to ask the main loop to stop
+static EXIT_REQUESTED: AtomicBool = AtomicBool::new(false);
+// Whether the terminal was asked to report key releases
+static RELEASES: AtomicBool = AtomicBool::new(false);
+
+// Puts the terminal in raw mode on the alternate screen with the cursor
+// hidden, asking for key releases where the terminal supports it, and puts
+// everything back when dropped. A panic restores the
+// terminal before the message is printed, so it isn't lost on the
+// alternate screen.
+pub struct TerminalGuard {
+    _private: (),
+}
+
+impl TerminalGuard {
+    pub fn enter() -> Result<Self, &'static str> {
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            restore();
+            default_hook(info);
+        }));
+
+        ACTIVE.store(true, Ordering::SeqCst);
+
+        // Created first, so returning an error below undoes what was done
+        let guard = Self { _private: () };
+        if terminal::enable_raw_mode().is_err() {
+            return Err("Failed to enable raw mode");
+        }
+        if execute!(
+            io::stdout(),
+            EnterAlternateScreen,
+            cursor::Hide,
+            terminal::SetTitle("CHIP-8 Interpreter")
+        )
+        .is_err()
+        {
+            return Err("Failed to set up terminal");
+        }
+
+        let flags = KeyboardEnhancementFlags::REPORT_EVENT_TYPES;
+        if terminal::supports_keyboard_enhancement().unwrap_or(false)
+            && execute!(io::stdout(), PushKeyboardEnhancementFlags(flags)).is_ok()
+        {
+            RELEASES.store(true, Ordering::SeqCst);
+        }
+
+        Ok(guard)
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        restore();
+    }
+}
+
+// Safe to call more than once; only the first call after `enter` does anything
+fn restore() {
+    if !ACTIVE.swap(false, Ordering::SeqCst) {
+        return;
+    }
+
+    let mut stdout = io::stdout();
+    // Errors are ignored so as much as possible gets restored
+    if RELEASES.swap(false, Ordering::SeqCst) {
+        let _ = execute!(stdout, PopKeyboardEnhancementFlags);
+    }
+    let _ = execute!(stdout, ResetColor, cursor::Show, LeaveAlternateScreen);
+    let _ = terminal::disable_raw_mode();
+}
+
+// Ctrl-C doesn't send SIGINT in raw mode, so it's also checked for as a key
+pub fn handle_signals() {
+    ctrlc::set_handler(request_exit).expect("Error setting signal handler");
+}
+
+pub fn request_exit() {
+    EXIT_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+pub fn exit_requested() -> bool {
+    EXIT_REQUESTED.load(Ordering::Relaxed)
+}
+
+// Whether key releases are reported, so held keys don't have to be guessed
+pub fn reports_releases() -> bool {
+    RELEASES.load(Ordering::Relaxed)
+}
@@ -1,5 +1,3 @@
-use std::sync::{Arc, Mutex};
-use std::thread;
 use std::time::Duration;
 
 use rodio::source::{SineWave, Source};
@@ -7,92 +5,97 @@ use rodio::{OutputStream, Sink};
 
 struct Sounds {
     stream: OutputStream,
+    volume: f32,
+    tone_hz: f32,
 }
 
 impl Sounds {
-    pub fn new() -> Self {
+    pub fn open(volume: f32, tone_hz: f32) -> Result<Self, &'static str> {
         let stream =
-            rodio::OutputStreamBuilder::open_default_stream().expect("open default stream");
-        Self { stream }
+            rodio::OutputStreamBuilder::open_default_stream().map_err(|_| "No sound device")?;
+        Ok(Self {
+            stream,
+            volume,
+            tone_hz,
+        })
     }
 
     pub fn play_sound(&self) {
         let sink = Sink::connect_new(&self.stream.mixer());
-        let source = SineWave::new(440.0)
+        let source = SineWave::new(self.tone_hz)
             .take_duration(Duration::from_millis(50))
-            .amplify(0.20);
+            .amplify(self.volume);
         sink.append(source);
         sink.detach();
     }
 }
 
+// Counted down by the main loop once per frame, so runs are repeatable
 pub struct Timers {
-    delay_timer: Arc<Mutex<u8>>,
-    sound_timer: Arc<Mutex<u8>>,
+    delay_timer: u8,
+    sound_timer: u8,
+    // Only opened once sound is wanted
+    sounds: Option<Sounds>,
+    muted: bool,
+    volume: f32,
+    tone_hz: f32,
 }
 
 impl Timers {
-    pub fn new(mute: bool) -> Self {
-        let delay_timer = Arc::new(Mutex::new(0));
-        let sound_timer = Arc::new(Mutex::new(0));
+    pub fn new(mute: bool, volume: f32, tone_hz: f32) -> Self {
+        let sounds = if !mute {
+            Some(Sounds::open(volume, tone_hz).expect("open default stream"))
+        } else {
+            None
+        };
 
-        let delay_clone = Arc::clone(&delay_timer);
-        let sound_clone = Arc::clone(&sound_timer);
-
-        let sounds = if !mute { Some(Sounds::new()) } else { None };
+        Self {
+            delay_timer: 0,
+            sound_timer: 0,
+            sounds,
+            muted: mute,
+            volume,
+            tone_hz,
+        }
+    }
 
-        thread::spawn(move || {
-            loop {
-                thread::sleep(Duration::from_millis(16)); // 60Hz
+    pub fn set_mute(&mut self, mute: bool) -> Result<(), &'static str> {
+        if !mute && self.sounds.is_none() {
+            self.sounds = Some(Sounds::open(self.volume, self.tone_hz)?);
+        }
+        self.muted = mute;
+        Ok(())
+    }
 
-                if let Ok(mut delay) = delay_clone.lock() {
-                    if *delay > 0 {
-                        *delay -= 1;
-                    }
-                }
+    // Called at 60Hz
+    pub fn tick(&mut self) {
+        if self.delay_timer > 0 {
+            self.delay_timer -= 1;
+        }
 
-                if let Ok(mut sound) = sound_clone.lock() {
-                    if *sound > 0 {
-                        *sound -= 1;
-                        match sounds {
-                            Some(ref s) => s.play_sound(),
-                            None => {}
-                        };
-                    }
-                }
+        if self.sound_timer > 0 {
+            self.sound_timer -= 1;
+            if let Some(sounds) = self.sounds.as_ref().filter(|_| !self.muted) {
+                sounds.play_sound();
             }
-        });
-
-        Self {
-            delay_timer,
-            sound_timer,
         }
     }
 
     pub fn get_delay(&self) -> Result<u8, &'static str> {
-        match self.delay_timer.lock() {
-            Ok(value) => Ok(*value),
-            Err(_) => Err("Failed to lock delay timer"),
-        }
+        Ok(self.delay_timer)
     }
 
-    pub fn set_delay(&self, value: u8) -> Result<(), &'static str> {
-        match self.delay_timer.lock() {
-            Ok(mut timer) => {
-                *timer = value;
-                Ok(())
-            }
-            Err(_) => Err("Failed to lock delay timer"),
-        }
+    pub fn get_sound(&self) -> u8 {
+        self.sound_timer
     }
 
-    pub fn set_sound(&self, value: u8) -> Result<(), &'static str> {
-        match self.sound_timer.lock() {
-            Ok(mut timer) => {
-                *timer = value;
-                Ok(())
-            }
-            Err(_) => Err("Failed to lock sound timer"),
-        }
+    pub fn set_delay(&mut self, value: u8) -> Result<(), &'static str> {
+        self.delay_timer = value;
+        Ok(())
+    }
+
+    pub fn set_sound(&mut self, value: u8) -> Result<(), &'static str> {
+        self.sound_timer = value;
+        Ok(())
     }
 }
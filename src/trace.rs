@@ -0,0 +1,467 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::disasm;
+use crate::symbols::Symbols;
+
+// Binary traces start with this, then a version byte and a flags byte
+const MAGIC: &[u8; 4] = b"C8TR";
+const VERSION: u8 = 2;
+// Flags byte: BNNN was run as BXNN
+const FLAG_BXNN: u8 = 1;
+
+// The parts of the machine a trace reports changes to
+#[derive(Clone, Copy, PartialEq)]
+pub struct State {
+    pub v: [u8; 16],
+    pub i: u16,
+    pub sp: usize,
+    pub delay: u8,
+    pub sound: u8,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Change {
+    V(u8, u8),
+    I(u16),
+    Sp(usize),
+    Delay(u8),
+    Sound(u8),
+}
+
+// One instruction that was run, and what it changed
+#[derive(Clone, PartialEq)]
+pub struct Entry {
+    // Instructions run before this one
+    pub cycle: u64,
+    pub pc: u16,
+    pub opcode: u16,
+    pub changes: Vec<Change>,
+}
+
+pub fn changes(before: &State, after: &State) -> Vec<Change> {
+    let mut changes = Vec::new();
+    for register in 0..16 {
+        if before.v[register] != after.v[register] {
+            changes.push(Change::V(register as u8, after.v[register]));
+        }
+    }
+    if before.i != after.i {
+        changes.push(Change::I(after.i));
+    }
+    if before.sp != after.sp {
+        changes.push(Change::Sp(after.sp));
+    }
+    if before.delay != after.delay {
+        changes.push(Change::Delay(after.delay));
+    }
+    if before.sound != after.sound {
+        changes.push(Change::Sound(after.sound));
+    }
+    changes
+}
+
+impl Entry {
+    // "      1234  204  6A05  LD VA, 05          VA=05"
+    pub fn to_text(&self, bxnn: bool, symbols: &Symbols) -> String {
+        let mnemonic = symbols
+            .mnemonic(self.opcode, bxnn)
+            .unwrap_or_else(|| "???".to_string());
+        let changes: Vec<String> = self
+            .changes
+            .iter()
+            .map(|change| match change {
+                Change::V(register, value) => format!("V{:X}={:02X}", register, value),
+                Change::I(value) => format!("I={:03X}", value),
+                Change::Sp(value) => format!("SP={}", value),
+                Change::Delay(value) => format!("DT={:02X}", value),
+                Change::Sound(value) => format!("ST={:02X}", value),
+            })
+            .collect();
+
+        format!(
+            "{:>10}  {:03X}  {:04X}  {:<18}  {}",
+            self.cycle,
+            self.pc,
+            self.opcode,
+            mnemonic,
+            changes.join(" ")
+        )
+        .trim_end()
+        .to_string()
+    }
+
+    // to_text, after a "label:" line if the address has one
+    pub fn to_listing(&self, bxnn: bool, symbols: &Symbols) -> String {
+        match symbols.name(self.pc) {
+            Some(name) => format!("{}:\n{}", name, self.to_text(bxnn, symbols)),
+            None => self.to_text(bxnn, symbols),
+        }
+    }
+}
+
+// Which instructions get logged. Every set filter has to match.
+pub struct Filter {
+    // Inclusive address range
+    addresses: Option<(u16, u16)>,
+    // Opcode patterns like "8XY4", or single hex digits for a whole group
+    ops: Vec<String>,
+    // Inclusive cycle range
+    cycles: Option<(u64, u64)>,
+}
+
+impl Filter {
+    // `addresses` is hex, like "200-2FF", and `cycles` is decimal, like
+    // "1000-2000"; either end of a range can be left out. `ops` is a comma
+    // separated list like "D,8XY4,FX0A".
+    pub fn parse(
+        addresses: Option<&str>,
+        ops: Option<&str>,
+        cycles: Option<&str>,
+        symbols: &Symbols,
+    ) -> Result<Self, String> {
+        let addresses = addresses
+            .map(|range| {
+                parse_range(range, 0, 0xFFF, |n| symbols.parse_address(n)).ok_or_else(|| {
+                    format!(
+                        "'{}' is not an address range like 200-2FF or @start-@end",
+                        range
+                    )
+                })
+            })
+            .transpose()?;
+        let cycles = cycles
+            .map(|range| {
+                parse_range(range, 0, u64::MAX, |n| n.parse().ok())
+                    .ok_or_else(|| format!("'{}' is not a cycle range like 1000-2000", range))
+            })
+            .transpose()?;
+
+        let mut patterns = Vec::new();
+        for op in ops.into_iter().flat_map(|ops| ops.split(',')) {
+            let op = op.trim().to_uppercase();
+            let is_group = op.len() == 1 && op.chars().all(|c| c.is_ascii_hexdigit());
+            let is_pattern = disasm::PATTERNS.contains(&op.as_str());
+            if !is_group && !is_pattern {
+                return Err(format!(
+                    "'{}' is not an opcode like 8XY4 or an opcode group like 8",
+                    op
+                ));
+            }
+            patterns.push(op);
+        }
+
+        Ok(Self {
+            addresses,
+            ops: patterns,
+            cycles,
+        })
+    }
+
+    pub fn matches(&self, cycle: u64, pc: u16, opcode: u16) -> bool {
+        if self
+            .addresses
+            .is_some_and(|(start, end)| pc < start || pc > end)
+        {
+            return false;
+        }
+        if self
+            .cycles
+            .is_some_and(|(start, end)| cycle < start || cycle > end)
+        {
+            return false;
+        }
+        if self.ops.is_empty() {
+            return true;
+        }
+
+        let group = format!("{:X}", opcode >> 12);
+        let pattern = disasm::pattern(opcode);
+        self.ops
+            .iter()
+            .any(|op| *op == group || Some(op.as_str()) == pattern)
+    }
+}
+
+// "start-end", "start-", "-end" or a single value
+fn parse_range<T: Copy>(
+    range: &str,
+    min: T,
+    max: T,
+    parse: impl Fn(&str) -> Option<T>,
+) -> Option<(T, T)> {
+    let (start, end) = match range.split_once('-') {
+        Some((start, end)) => (start.trim(), end.trim()),
+        None => (range.trim(), range.trim()),
+    };
+    let start = if start.is_empty() { min } else { parse(start)? };
+    let end = if end.is_empty() { max } else { parse(end)? };
+    Some((start, end))
+}
+
+// Writes trace entries as text, or in the binary format if the path ends in .bin
+pub struct Tracer {
+    out: BufWriter<File>,
+    path: PathBuf,
+    binary: bool,
+    bxnn: bool,
+    // Labels for text traces
+    symbols: Symbols,
+    filter: Filter,
+    last_cycle: u64,
+}
+
+impl Tracer {
+    pub fn create(
+        path: &Path,
+        filter: Filter,
+        bxnn: bool,
+        symbols: Symbols,
+    ) -> Result<Self, String> {
+        let file = File::create(path)
+            .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+        let binary = path
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("bin"));
+
+        let mut tracer = Self {
+            out: BufWriter::new(file),
+            path: path.to_path_buf(),
+            binary,
+            bxnn,
+            symbols,
+            filter,
+            last_cycle: 0,
+        };
+        if binary {
+            let flags = if bxnn { FLAG_BXNN } else { 0 };
+            let mut header = MAGIC.to_vec();
+            header.extend([VERSION, flags]);
+            tracer.write(&header)?;
+        }
+        Ok(tracer)
+    }
+
+    pub fn wants(&self, cycle: u64, pc: u16, opcode: u16) -> bool {
+        self.filter.matches(cycle, pc, opcode)
+    }
+
+    pub fn log(&mut self, entry: &Entry) -> Result<(), String> {
+        if !self.binary {
+            let line = entry.to_listing(self.bxnn, &self.symbols) + "\n";
+            return self.write(line.as_bytes());
+        }
+
+        // Cycles are stored as the gap from the last entry, which is usually 1
+        let mut bytes = Vec::with_capacity(16);
+        write_varint(&mut bytes, entry.cycle - self.last_cycle);
+        self.last_cycle = entry.cycle;
+        bytes.extend(entry.pc.to_be_bytes());
+        bytes.extend(entry.opcode.to_be_bytes());
+        bytes.push(entry.changes.len() as u8);
+        for change in &entry.changes {
+            match *change {
+                Change::V(register, value) => bytes.extend([register, value]),
+                Change::I(value) => {
+                    bytes.push(0x10);
+                    bytes.extend(value.to_be_bytes());
+                }
+                // The stack can be deeper than a byte when it has no limit
+                Change::Sp(value) => {
+                    bytes.push(0x11);
+                    write_varint(&mut bytes, value as u64);
+                }
+                Change::Delay(value) => bytes.extend([0x12, value]),
+                Change::Sound(value) => bytes.extend([0x13, value]),
+            }
+        }
+        self.write(&bytes)
+    }
+
+    pub fn finish(mut self) -> Result<(), String> {
+        let result = self.out.flush();
+        result.map_err(|e| format!("Failed to write {}: {}", self.path.display(), e))
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), String> {
+        self.out
+            .write_all(bytes)
+            .map_err(|e| format!("Failed to write {}: {}", self.path.display(), e))
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+// Reads a binary trace back, one entry at a time
+pub struct Reader {
+    input: BufReader<File>,
+    path: PathBuf,
+    pub bxnn: bool,
+    last_cycle: u64,
+}
+
+impl Reader {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let file =
+            File::open(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let mut reader = Self {
+            input: BufReader::new(file),
+            path: path.to_path_buf(),
+            bxnn: false,
+            last_cycle: 0,
+        };
+
+        let mut header = [0; 6];
+        let read = reader.input.read_exact(&mut header);
+        if read.is_err() || &header[..4] != MAGIC {
+            return Err(format!("{} is not a binary trace", path.display()));
+        }
+        if header[4] != VERSION {
+            return Err(format!(
+                "{} is a version {} trace, only version {} is supported",
+                path.display(),
+                header[4],
+                VERSION
+            ));
+        }
+        reader.bxnn = header[5] & FLAG_BXNN != 0;
+        Ok(reader)
+    }
+
+    // None at the end of the trace
+    pub fn next_entry(&mut self) -> Result<Option<Entry>, String> {
+        let gap = match self.read_varint() {
+            Ok(Some(gap)) => gap,
+            Ok(None) => return Ok(None),
+            Err(()) => return Err(self.truncated()),
+        };
+        self.entry(gap).map(Some).map_err(|()| self.truncated())
+    }
+
+    fn entry(&mut self, gap: u64) -> Result<Entry, ()> {
+        let mut fixed = [0; 5];
+        self.input.read_exact(&mut fixed).map_err(|_| ())?;
+
+        let mut changes = Vec::with_capacity(fixed[4] as usize);
+        for _ in 0..fixed[4] {
+            let tag = self.byte()?;
+            changes.push(match tag {
+                0x00..=0x0F => Change::V(tag, self.byte()?),
+                0x10 => Change::I(u16::from_be_bytes([self.byte()?, self.byte()?])),
+                0x11 => Change::Sp(self.read_varint()?.ok_or(())? as usize),
+                0x12 => Change::Delay(self.byte()?),
+                0x13 => Change::Sound(self.byte()?),
+                _ => return Err(()),
+            });
+        }
+
+        self.last_cycle += gap;
+        Ok(Entry {
+            cycle: self.last_cycle,
+            pc: u16::from_be_bytes([fixed[0], fixed[1]]),
+            opcode: u16::from_be_bytes([fixed[2], fixed[3]]),
+            changes,
+        })
+    }
+
+    fn byte(&mut self) -> Result<u8, ()> {
+        let mut byte = [0];
+        self.input.read_exact(&mut byte).map_err(|_| ())?;
+        Ok(byte[0])
+    }
+
+    // None if the trace ends cleanly before the value
+    fn read_varint(&mut self) -> Result<Option<u64>, ()> {
+        let mut value = 0u64;
+        let mut shift = 0;
+        loop {
+            let mut byte = [0];
+            match self.input.read_exact(&mut byte) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof && shift == 0 => return Ok(None),
+                Err(_) => return Err(()),
+            }
+            if shift >= 64 {
+                return Err(());
+            }
+            value |= ((byte[0] & 0x7F) as u64) << shift;
+            if byte[0] & 0x80 == 0 {
+                return Ok(Some(value));
+            }
+            shift += 7;
+        }
+    }
+
+    fn truncated(&self) -> String {
+        format!("{} is truncated or corrupt", self.path.display())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn binary_trace_round_trips() {
+        let entries = vec![
+            Entry {
+                cycle: 0,
+                pc: 0x200,
+                opcode: 0x6A05,
+                changes: vec![Change::V(0xA, 0x05)],
+            },
+            Entry {
+                cycle: 1,
+                pc: 0x202,
+                opcode: 0xA2F0,
+                changes: vec![Change::I(0x2F0)],
+            },
+            // A gap that takes more than one varint byte
+            Entry {
+                cycle: 100_000,
+                pc: 0xFFE,
+                opcode: 0xF315,
+                changes: vec![
+                    Change::Delay(0xFF),
+                    Change::Sound(3),
+                    Change::Sp(2),
+                    Change::Sp(300),
+                    Change::V(0xF, 1),
+                ],
+            },
+            Entry {
+                cycle: 100_001,
+                pc: 0x204,
+                opcode: 0x1204,
+                changes: Vec::new(),
+            },
+        ];
+
+        let path = std::env::temp_dir().join(format!("chip8-trace-{}.bin", std::process::id()));
+        let filter = Filter::parse(None, None, None, &Symbols::default()).unwrap();
+        let mut tracer = Tracer::create(&path, filter, true, Symbols::default()).unwrap();
+        for entry in &entries {
+            tracer.log(entry).unwrap();
+        }
+        tracer.finish().unwrap();
+
+        let mut reader = Reader::open(&path).unwrap();
+        let mut read = Vec::new();
+        while let Some(entry) = reader.next_entry().unwrap() {
+            read.push(entry);
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(reader.bxnn);
+        assert!(read == entries);
+    }
+}